@@ -0,0 +1,64 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/* Generates the kernel symbol table used by `symbols::resolve`.
+
+A kernel can't easily look up its own symbol addresses while it is being linked, so the table is produced from the
+symbols of a previous build of the same kernel:
+
+    cargo build
+    nm -nC --defined-only target/target_triple_config/debug/rust_os > kernel.sym
+    KERNEL_SYMBOL_MAP=kernel.sym cargo build
+
+The addresses are only right if embedding the table leaves the code exactly as it was. Two things make sure of that:
+the table lives in its own `.ksymtab` section, which linker.ld places after `.text`, and the kernel only reaches it
+through the `SYMBOLS` slice, which symbols.rs reads with a volatile load. Otherwise the compiler would specialize the
+lookup on the table, and an empty table would fold it down to nothing while a full one would need a real binary search,
+so the code would change size and every function after it would move. With both, one round is enough. Without
+`KERNEL_SYMBOL_MAP`, an empty table is generated and `resolve` always returns `None`.
+
+The build script also links every kernel binary, including the test kernels, with linker.ld. */
+fn main() {
+    println!("cargo:rerun-if-env-changed=KERNEL_SYMBOL_MAP");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=linker.ld");
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg=-T{}", Path::new(&manifest_dir).join("linker.ld").display());
+
+    let mut symbols = Vec::new();
+    if let Ok(path) = env::var("KERNEL_SYMBOL_MAP") {
+        println!("cargo:rerun-if-changed={}", path);
+        let map = fs::read_to_string(&path).expect("failed to read KERNEL_SYMBOL_MAP");
+        for line in map.lines() {
+            // Each line of `nm` output looks like `0000000000201120 T rust_os::init`.
+            let mut parts = line.splitn(3, ' ');
+            let (address, kind, name) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(address), Some(kind), Some(name)) => (address, kind, name),
+                _ => continue,
+            };
+            // Only code symbols are useful for symbolizing instruction pointers.
+            if kind != "T" && kind != "t" {
+                continue;
+            }
+            if let Ok(address) = u64::from_str_radix(address, 16) {
+                symbols.push((address, name.to_string()));
+            }
+        }
+    }
+    symbols.sort();
+    symbols.dedup_by_key(|(address, _)| *address);
+
+    let mut table = String::new();
+    table.push_str("#[link_section = \".ksymtab\"]\n");
+    table.push_str(&format!("static ENTRIES: [(u64, &str); {}] = [\n", symbols.len()));
+    for (address, name) in &symbols {
+        table.push_str(&format!("    ({:#x}, {:?}),\n", address, name));
+    }
+    table.push_str("];\n");
+    table.push_str("#[link_section = \".ksymtab\"]\n#[used]\n");
+    table.push_str("pub static SYMBOLS: &[(u64, &str)] = &ENTRIES;\n");
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("symbols.rs"), table).unwrap();
+}
@@ -29,17 +29,220 @@ global heap allocator. The attribute is only applicable to a static that impleme
 use linked_list_allocator::LockedHeap;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelAllocator = KernelAllocator { heap: LockedHeap::empty() };
+
+/* The kernel allocator wraps the linked list heap to decide what happens when the heap runs out of memory. Returning a
+null pointer would make the alloc crate call its allocation error handler, which just panics with the requested size and
+tells us nothing about the state of the heap. Instead, a failed allocation goes through the out of memory policy, which
+can try to make room and retry before giving up with a panic that includes the heap statistics. */
+struct KernelAllocator {
+    heap: LockedHeap,
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if cfg!(feature = "debug-alloc") && crate::interrupts::in_interrupt() {
+            panic!(
+                "allocation of {} bytes in interrupt context, which deadlocks if the interrupted code holds the heap lock",
+                layout.size()
+            );
+        }
+        loop {
+            if let Ok(ptr) = self.heap.lock().allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            // The heap lock is released here, so the policy may free memory or extend the heap before we retry.
+            if !out_of_memory(layout) {
+                let frames = crate::memory::free_memory_report();
+                let frames: &dyn fmt::Display = match frames.as_ref() {
+                    Some(report) => report,
+                    None => &"frame allocator unavailable",
+                };
+                panic!(
+                    "out of memory: {} bytes with alignment {} requested\n{}\n{}",
+                    layout.size(),
+                    layout.align(),
+                    stats(),
+                    frames
+                );
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.lock().deallocate(core::ptr::NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+/// What the allocator does when the heap has no room for an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OomPolicy {
+    /// Panic right away.
+    Panic = 0,
+    /// Map more pages at the end of the heap, up to `HEAP_MAX_SIZE`, then panic.
+    Grow = 1,
+    /// Ask the registered shrinkers to free cached memory first (see shrinker.rs), then grow, then panic.
+    Reclaim = 2,
+}
+
+static OOM_POLICY: AtomicU8 = AtomicU8::new(crate::config::OOM_POLICY as u8);
+static OOM_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_oom_policy(policy: OomPolicy) {
+    OOM_POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+pub fn oom_policy() -> OomPolicy {
+    match OOM_POLICY.load(Ordering::SeqCst) {
+        0 => OomPolicy::Panic,
+        1 => OomPolicy::Grow,
+        _ => OomPolicy::Reclaim,
+    }
+}
+
+/* Applies the policy once. Returns whether it made progress, in which case the allocation is retried. Every step that
+made progress returns right away, so the cheaper steps are tried again before the heap grows any further. */
+fn out_of_memory(layout: Layout) -> bool {
+    OOM_EVENTS.fetch_add(1, Ordering::Relaxed);
+    let policy = oom_policy();
+    if policy == OomPolicy::Reclaim && crate::shrinker::shrink(layout.size()) > 0 {
+        return true;
+    }
+    policy != OomPolicy::Panic && grow(layout)
+}
+
+/* Growing the heap needs the page mapper and the frame allocator, which only exist after memory::init. The kernel hands
+them over with enable_growth once it is done with its own mappings. */
+struct Growth {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+}
+
+static GROWTH: Mutex<Option<Growth>> = Mutex::new(None);
+
+/// The heap grows by at least this many bytes at a time.
+const GROWTH_STEP: usize = 16 * 1024;
+/// The heap never grows beyond this size.
+pub const HEAP_MAX_SIZE: usize = crate::config::HEAP_MAX_SIZE;
+
+/// Allows the heap to grow up to `HEAP_MAX_SIZE` when it runs out of memory, using the given mapper and frame allocator
+/// to map the new pages.
+pub fn enable_growth(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *GROWTH.lock() = Some(Growth { mapper, frame_allocator });
+}
+
+/// Runs `f` with the frame allocator that was handed over by `enable_growth`, if there is one and it isn't in use.
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut BootInfoFrameAllocator) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut growth = GROWTH.try_lock()?;
+        growth.as_mut().map(|growth| f(&mut growth.frame_allocator))
+    })
+}
+
+/// Zeroes up to `budget` freed frames, see `BootInfoFrameAllocator::scrub`. Returns how many it zeroed.
+pub fn scrub_frames(budget: usize) -> usize {
+    with_frame_allocator(|frames| frames.scrub(budget)).unwrap_or(0)
+}
+
+/* Maps enough pages after the current end of the heap to fit `layout`, and hands them to the heap. */
+fn grow(layout: Layout) -> bool {
+    let mut growth = GROWTH.lock();
+    let growth = match growth.as_mut() {
+        Some(growth) => growth,
+        None => return false,
+    };
+    let (top, size) = {
+        let heap = ALLOCATOR.heap.lock();
+        (heap.top(), heap.size())
+    };
+    // The new memory may have to be aligned, and the heap needs some of it for its own bookkeeping.
+    let needed = layout.size() + layout.align() + GROWTH_STEP;
+    let by = (needed - needed % GROWTH_STEP).min(HEAP_MAX_SIZE - size);
+    if by == 0 || by < layout.size() {
+        return false;
+    }
+
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(top as u64));
+    let last_page = Page::containing_address(VirtAddr::new((top + by - 1) as u64));
+    for page in Page::range_inclusive(first_page, last_page) {
+        let frame = match growth.frame_allocator.allocate_frame_for(Owner::KernelHeap) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { growth.mapper.map_to(page, frame, flags, &mut growth.frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => return false,
+        }
+    }
+    // Safety: the pages directly after the heap's top have just been mapped and nothing else uses them.
+    unsafe { ALLOCATOR.heap.lock().extend(by) };
+    HEAP_TOP.store(top + by, Ordering::SeqCst);
+    true
+}
+
+/// A snapshot of the heap's state.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub bottom: usize,
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+    pub oom_events: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "heap {:#x}..{:#x}: {} of {} bytes used, {} free (max {}), {} out of memory events, policy {:?}",
+            self.bottom,
+            self.bottom + self.size,
+            self.used,
+            self.size,
+            self.free,
+            HEAP_MAX_SIZE,
+            self.oom_events,
+            oom_policy()
+        )
+    }
+}
+
+/// Releases the heap lock if a panic abandoned it while it was held, see `test_panic_handler`.
+///
+/// This function is unsafe because the heap may be halfway through an allocation, and because whoever holds the lock
+/// must never run again.
+pub(crate) unsafe fn force_unlock() {
+    ALLOCATOR.heap.force_unlock();
+}
+
+pub fn stats() -> HeapStats {
+    let heap = ALLOCATOR.heap.lock();
+    HeapStats {
+        bottom: heap.bottom(),
+        size: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+        oom_events: OOM_EVENTS.load(Ordering::Relaxed),
+    }
+}
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+use crate::memory::{BootInfoFrameAllocator, Owner};
 
 /* To create a kernel heap, we need to define a heap memory region from which the allocator can allocate memory.
 To do this, we need to define a virtual memory range for the heap region and then map this region to physical frames. */
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_SIZE: usize = crate::config::HEAP_SIZE; // 100 KiB by default, see config.rs
 
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::{MapToError, UnmapError}, Mapper, Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
@@ -48,7 +251,7 @@ use x86_64::{
 both limited to 4 KiB pages by using Size4KiB as the generic parameter. */
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator,
 ) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
@@ -60,7 +263,7 @@ pub fn init_heap(
 
     for page in page_range {
         let frame = frame_allocator
-            .allocate_frame()
+            .allocate_frame_for(Owner::KernelHeap)
             .ok_or(MapToError::FrameAllocationFailed)?;
         /* With these flags, both read and write accesses are allowed, which makes sense for heap memory. */
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
@@ -69,10 +272,82 @@ pub fn init_heap(
         };
     }
 
+    /* Whatever the bootloader mapped next to the heap would be silently overwritten by an allocator bug. */
+    unmap_guard(mapper, HEAP_START - GUARD_SIZE);
+    unmap_guard(mapper, HEAP_START + HEAP_MAX_SIZE);
+
     /* Initialize the allocator after allocating the heap frames because the init() method writes to the heap. */
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.heap.lock().init(HEAP_START, HEAP_SIZE);
     }
+    HEAP_TOP.store(HEAP_START + HEAP_SIZE, Ordering::SeqCst);
 
     Ok(())
+}
+
+/* Guard pages. The page right below the heap and the page right after its largest possible extent are never mapped,
+and neither is the space between the heap's current top and that extent. An allocator bug or a buffer overrun that
+runs off either end of the heap therefore page faults instead of corrupting whatever is mapped next to it, and the page
+fault handler can tell from the faulting address that it was the heap. */
+const GUARD_SIZE: usize = 4096;
+
+// The current end of the heap. Kept outside the heap lock so that the page fault handler can read it even if the fault
+// happened while the lock was held.
+static HEAP_TOP: AtomicUsize = AtomicUsize::new(HEAP_START);
+
+/* Nothing is supposed to be mapped next to the heap, so this only makes sure of it. A frame found there belongs to
+whoever mapped it, and handing it to the frame allocator could give out memory that is still in use, while dropping it
+would leak it, so a mapped guard page is a bug. */
+fn unmap_guard(mapper: &mut impl Mapper<Size4KiB>, address: usize) {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
+    match mapper.unmap(page) {
+        Err(UnmapError::PageNotMapped) => {}
+        Ok((frame, flush)) => {
+            flush.flush();
+            crate::bug!("heap guard page {:?} was mapped to {:?}", page, frame);
+        }
+        Err(error) => crate::bug!("heap guard page {:?} can't be unmapped: {:?}", page, error),
+    }
+}
+
+/// Which end of the heap a faulting access ran off, and by how many bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapFault {
+    /// The access was the given number of bytes below the heap.
+    Underflow(usize),
+    /// The access was the given number of bytes past the heap's current end.
+    Overflow(usize),
+}
+
+impl fmt::Display for HeapFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeapFault::Underflow(bytes) => write!(f, "heap underflow, {} bytes below the heap", bytes),
+            HeapFault::Overflow(bytes) => write!(f, "heap overflow, {} bytes past the end of the heap", bytes),
+        }
+    }
+}
+
+/// Tells whether a page fault at `address` hit one of the heap's guard areas.
+pub fn guard_fault(address: u64) -> Option<HeapFault> {
+    classify_fault(address as usize, HEAP_TOP.load(Ordering::Relaxed))
+}
+
+fn classify_fault(address: usize, top: usize) -> Option<HeapFault> {
+    if (HEAP_START - GUARD_SIZE..HEAP_START).contains(&address) {
+        Some(HeapFault::Underflow(HEAP_START - address))
+    } else if (top..HEAP_START + HEAP_MAX_SIZE + GUARD_SIZE).contains(&address) {
+        Some(HeapFault::Overflow(address - top))
+    } else {
+        None
+    }
+}
+
+#[test_case]
+fn test_classify_fault() {
+    let top = HEAP_START + HEAP_SIZE;
+    assert_eq!(classify_fault(HEAP_START - 8, top), Some(HeapFault::Underflow(8)));
+    assert_eq!(classify_fault(HEAP_START, top), None);
+    assert_eq!(classify_fault(top + 3, top), Some(HeapFault::Overflow(3)));
+    assert_eq!(classify_fault(HEAP_START + HEAP_MAX_SIZE + GUARD_SIZE, top), None);
 }
\ No newline at end of file
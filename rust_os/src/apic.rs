@@ -0,0 +1,274 @@
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+/* Every CPU has a local APIC, which receives interrupts for that CPU and has a timer of its own. The PIT, in contrast,
+exists once per machine, so it can't give every CPU its own scheduler tick. This module switches the local APIC to
+x2APIC mode, where its registers are model specific registers instead of a memory mapped page, and uses its timer as
+the tick instead of the PIT.
+
+The timer runs in one of two modes:
+
+    TSC-deadline  the timer fires when the time stamp counter reaches the value written to IA32_TSC_DEADLINE, and the
+                  interrupt handler arms the next deadline one tick after the last one. Deadlines are absolute, so
+                  ticks don't drift.
+    periodic      the timer counts down from an initial count at the bus frequency and reloads itself.
+
+Neither the TSC frequency nor the APIC bus frequency is known up front, so both are measured against the PIT, which is
+still running at config::TIMER_HZ when init_timer is called. Only after that is the PIT's interrupt masked.
+
+Without x2APIC support the kernel simply keeps using the PIT. The legacy PICs stay in charge of all other interrupts. */
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+// x2APIC registers, at 0x800 plus the xAPIC register offset divided by 16.
+const X2APIC_ID: u32 = 0x802;
+const X2APIC_EOI: u32 = 0x80b;
+const X2APIC_SPURIOUS: u32 = 0x80f;
+const X2APIC_LVT_TIMER: u32 = 0x832;
+const X2APIC_INITIAL_COUNT: u32 = 0x838;
+const X2APIC_CURRENT_COUNT: u32 = 0x839;
+const X2APIC_DIVIDE: u32 = 0x83e;
+
+const SPURIOUS_ENABLE: u64 = 1 << 8;
+const LVT_MASKED: u64 = 1 << 16;
+const LVT_PERIODIC: u64 = 1 << 17;
+const LVT_TSC_DEADLINE: u64 = 2 << 17;
+// Divide the bus clock by 16.
+const DIVIDE_BY_16: u64 = 0b0011;
+
+/// The vector of the local APIC timer interrupt.
+pub const TIMER_VECTOR: u8 = crate::interrupts::PIC_2_OFFSET + 8;
+/// The vector the local APIC uses for spurious interrupts. Its low four bits must be set on older CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// How the scheduler tick is generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TimerMode {
+    /// The PIT on IRQ 0, shared by all CPUs.
+    Pit = 0,
+    /// The local APIC timer in periodic mode.
+    Periodic = 1,
+    /// The local APIC timer in TSC-deadline mode.
+    TscDeadline = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// The CPU doesn't support x2APIC mode.
+    NoX2Apic,
+    /// The PIT didn't tick while measuring the timer frequency, e.g. because interrupts are disabled.
+    CalibrationFailed,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(TimerMode::Pit as u8);
+// TSC cycles per tick in TSC-deadline mode.
+static TSC_PER_TICK: AtomicU64 = AtomicU64::new(0);
+// The TSC value the timer was last armed for in TSC-deadline mode.
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// The number of PIT ticks the timer frequencies are measured over.
+const CALIBRATION_TICKS: u64 = 10;
+
+fn cpuid_features() -> u32 {
+    // Safety: CPUID leaf 1 exists on every x86_64 CPU. Newer compilers consider __cpuid safe to call.
+    #[allow(unused_unsafe)]
+    unsafe { core::arch::x86_64::__cpuid(1).ecx }
+}
+
+pub fn x2apic_supported() -> bool {
+    cpuid_features() & (1 << 21) != 0
+}
+
+pub fn tsc_deadline_supported() -> bool {
+    cpuid_features() & (1 << 24) != 0
+}
+
+pub fn timer_mode() -> TimerMode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => TimerMode::Periodic,
+        2 => TimerMode::TscDeadline,
+        _ => TimerMode::Pit,
+    }
+}
+
+unsafe fn read(register: u32) -> u64 {
+    Msr::new(register).read()
+}
+
+unsafe fn write(register: u32, value: u64) {
+    Msr::new(register).write(value)
+}
+
+/// The x2APIC ID of the current CPU. Only meaningful once `init_timer` has switched to x2APIC mode.
+pub fn id() -> u32 {
+    unsafe { read(X2APIC_ID) as u32 }
+}
+
+/// Enables x2APIC mode on the current CPU and replaces the PIT with the local APIC timer as the source of timer ticks.
+/// Must be called with interrupts enabled, while the PIT is still ticking at `config::TIMER_HZ`. On failure, the local
+/// APIC is taken out of x2APIC mode again and the PIT stays the tick.
+pub fn init_timer() -> Result<TimerMode, ApicError> {
+    if !x2apic_supported() {
+        return Err(ApicError::NoX2Apic);
+    }
+    let (base, spurious) = unsafe {
+        let base = read(IA32_APIC_BASE);
+        write(IA32_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        let spurious = read(X2APIC_SPURIOUS);
+        write(X2APIC_SPURIOUS, SPURIOUS_ENABLE | u64::from(SPURIOUS_VECTOR));
+        (base, spurious)
+    };
+
+    let mode = match start_timer() {
+        Ok(mode) => mode,
+        Err(error) => {
+            unsafe { restore(base, spurious) };
+            return Err(error);
+        }
+    };
+
+    // The PIT is no longer needed; mask IRQ 0 so that time doesn't advance twice per tick.
+    crate::interrupts::mask(crate::interrupts::IRQ_TIMER);
+    MODE.store(mode as u8, Ordering::SeqCst);
+    Ok(mode)
+}
+
+fn start_timer() -> Result<TimerMode, ApicError> {
+    if tsc_deadline_supported() {
+        let tsc_per_tick = measure(|| unsafe { core::arch::x86_64::_rdtsc() })?;
+        TSC_PER_TICK.store(tsc_per_tick, Ordering::SeqCst);
+        let deadline = unsafe { core::arch::x86_64::_rdtsc() } + tsc_per_tick;
+        DEADLINE.store(deadline, Ordering::SeqCst);
+        unsafe {
+            write(X2APIC_LVT_TIMER, LVT_TSC_DEADLINE | u64::from(TIMER_VECTOR));
+            write(IA32_TSC_DEADLINE, deadline);
+        }
+        Ok(TimerMode::TscDeadline)
+    } else {
+        // Let the timer count down from its maximum while the PIT ticks, without raising interrupts.
+        unsafe {
+            write(X2APIC_DIVIDE, DIVIDE_BY_16);
+            write(X2APIC_LVT_TIMER, LVT_MASKED | u64::from(TIMER_VECTOR));
+            write(X2APIC_INITIAL_COUNT, u64::from(u32::MAX));
+        }
+        let counts_per_tick = measure(|| u64::from(u32::MAX) - unsafe { read(X2APIC_CURRENT_COUNT) })?;
+        unsafe {
+            write(X2APIC_LVT_TIMER, LVT_PERIODIC | u64::from(TIMER_VECTOR));
+            write(X2APIC_INITIAL_COUNT, counts_per_tick.max(1));
+        }
+        Ok(TimerMode::Periodic)
+    }
+}
+
+/* Undoes the mode change of init_timer, given the APIC base and spurious interrupt registers from before it. The CPU
+only allows leaving x2APIC mode by disabling the local APIC, and enabling it again in xAPIC mode would reset its local
+interrupt entries to masked, cutting off LINT0, through which the PICs deliver their interrupts. So the local APIC stays
+disabled, and the CPU takes the PICs' interrupts directly, as if it had no local APIC at all.
+
+This function is unsafe because it must only be called by init_timer, with the registers it read. */
+unsafe fn restore(base: u64, spurious: u64) {
+    write(X2APIC_INITIAL_COUNT, 0);
+    write(X2APIC_LVT_TIMER, LVT_MASKED | u64::from(TIMER_VECTOR));
+    if base & APIC_BASE_X2APIC != 0 {
+        // The firmware had already switched to x2APIC mode.
+        write(X2APIC_SPURIOUS, spurious);
+    } else {
+        write(IA32_APIC_BASE, base & !(APIC_BASE_ENABLE | APIC_BASE_X2APIC));
+    }
+}
+
+/* Returns how much `counter` advances during one PIT tick, averaged over CALIBRATION_TICKS ticks. The measurement
+starts right after a tick, so that it covers whole ticks. */
+fn measure(counter: impl Fn() -> u64) -> Result<u64, ApicError> {
+    let wait_for_tick = |after: u64| -> Result<u64, ApicError> {
+        // Without a single tick in this many iterations, the PIT interrupt isn't arriving at all.
+        for _ in 0..100_000_000u64 {
+            let now = crate::time::ticks();
+            if now > after {
+                return Ok(now);
+            }
+            core::hint::spin_loop();
+        }
+        Err(ApicError::CalibrationFailed)
+    };
+    let start_tick = wait_for_tick(crate::time::ticks())?;
+    let start = counter();
+    let mut tick = start_tick;
+    while tick < start_tick + CALIBRATION_TICKS {
+        tick = wait_for_tick(tick)?;
+    }
+    let elapsed = counter() - start;
+    Ok(elapsed / CALIBRATION_TICKS)
+}
+
+/// Called by the local APIC timer interrupt handler, after the tick has been accounted.
+pub fn timer_interrupt() {
+    unsafe {
+        if timer_mode() == TimerMode::TscDeadline {
+            let next = next_deadline(
+                DEADLINE.load(Ordering::Relaxed),
+                TSC_PER_TICK.load(Ordering::Relaxed),
+                core::arch::x86_64::_rdtsc(),
+            );
+            DEADLINE.store(next, Ordering::Relaxed);
+            write(IA32_TSC_DEADLINE, next);
+        }
+        end_of_interrupt();
+    }
+}
+
+/* The deadline one tick after `last`, so that the time it takes to handle an interrupt doesn't add up over the ticks.
+If that is already in the past, e.g. because interrupts were disabled for longer than a tick, the missed ticks are
+skipped rather than fired back to back, keeping the deadlines on the same grid. */
+fn next_deadline(last: u64, per_tick: u64, now: u64) -> u64 {
+    let next = last + per_tick;
+    if next > now {
+        return next;
+    }
+    let missed = (now - next) / per_tick + 1;
+    next + missed * per_tick
+}
+
+/// Signals the end of an interrupt delivered by the local APIC.
+///
+/// This function is unsafe because it must only be called at the end of an interrupt handler for an interrupt that
+/// was delivered by the local APIC, or the next interrupt of a lower priority is acknowledged too early.
+pub unsafe fn end_of_interrupt() {
+    write(X2APIC_EOI, 0);
+}
+
+#[test_case]
+fn test_tick_source() {
+    use crate::{config, pit};
+
+    if timer_mode() != TimerMode::Pit {
+        assert!(x2apic_supported());
+    }
+    // Whatever the source, the tick must advance at config::TIMER_HZ. Like the calibration, this is checked against
+    // the PIT, whose counter keeps running when its interrupt is masked.
+    let cycles_per_tick = u64::from(pit::divisor_for(config::TIMER_HZ));
+    let start = crate::time::ticks();
+    pit::spin_cycles(cycles_per_tick * CALIBRATION_TICKS);
+    let ticks = crate::time::ticks() - start;
+    assert!(
+        (CALIBRATION_TICKS - 2..=CALIBRATION_TICKS + 2).contains(&ticks),
+        "{} ticks in the time of {} PIT ticks ({:?})",
+        ticks,
+        CALIBRATION_TICKS,
+        timer_mode()
+    );
+}
+
+#[test_case]
+fn test_next_deadline() {
+    assert_eq!(next_deadline(1000, 100, 1050), 1100);
+    // Late, but still before the next deadline.
+    assert_eq!(next_deadline(1000, 100, 1099), 1100);
+    // Ticks were missed; the deadline stays on the grid.
+    assert_eq!(next_deadline(1000, 100, 1100), 1200);
+    assert_eq!(next_deadline(1000, 100, 1350), 1400);
+}
@@ -0,0 +1,55 @@
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::vga_buffer::Color;
+
+/* Reports the progress of kernel initialization. Every subsystem that init() brings up runs as a named stage, which
+prints a line with a colored status column to the VGA buffer:
+
+    gdt                                                                   [  OK  ]
+    ps2 keyboard                                                          [ FAIL ]
+
+and a line of key=value pairs to the serial port, which is easy to grep for in CI logs:
+
+    boot: stage=ps2 keyboard status=fail error=Timeout
+
+A failing stage doesn't stop the boot. Its error is logged at the error level, so it can be looked up again once the
+kernel is up, and the stage's result is handed back to the caller, which decides how to carry on without it. */
+
+static STAGES: AtomicUsize = AtomicUsize::new(0);
+static FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+const NAME_WIDTH: usize = 70;
+
+/// Runs a fallible boot stage and reports its outcome.
+pub fn stage<T, E: fmt::Debug>(name: &str, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let result = f();
+    STAGES.fetch_add(1, Ordering::Relaxed);
+    match &result {
+        Ok(_) => {
+            crate::print!("{:<width$}", name, width = NAME_WIDTH);
+            crate::vga_buffer::_print_colored(Color::LightGreen, format_args!("[  OK  ]\n"));
+            crate::serial_println!("boot: stage={} status=ok", name);
+        }
+        Err(error) => {
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+            crate::print!("{:<width$}", name, width = NAME_WIDTH);
+            crate::vga_buffer::_print_colored(Color::LightRed, format_args!("[ FAIL ]\n"));
+            crate::serial_println!("boot: stage={} status=fail error={:?}", name, error);
+            crate::error!("boot stage {} failed: {:?}", name, error);
+        }
+    }
+    result
+}
+
+/// Runs a boot stage that can't fail.
+pub fn step(name: &str, f: impl FnOnce()) {
+    let _ = stage(name, || -> Result<(), core::convert::Infallible> {
+        f();
+        Ok(())
+    });
+}
+
+/// The number of stages that ran and the number of those that failed.
+pub fn summary() -> (usize, usize) {
+    (STAGES.load(Ordering::Relaxed), FAILURES.load(Ordering::Relaxed))
+}
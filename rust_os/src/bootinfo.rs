@@ -0,0 +1,279 @@
+/* The kernel is booted by the `bootloader` crate under QEMU, which passes its own BootInfo struct. GRUB and other
+bootloaders on real hardware speak Multiboot2 instead and pass a different structure. The BootInformation trait hides
+the difference, so that the memory setup code only needs to know about usable memory areas, the physical memory
+offset, and the kernel command line.
+
+Only the parsing side of Multiboot2 lives here. Booting through GRUB additionally needs a Multiboot2 header and a
+32-bit entry stub that switches to long mode before jumping into Rust, which the `bootloader` crate does for us today.
+The `bootloader` crate can't pass a command line, so on that path it is read from QEMU's fw_cfg device (see fwcfg.rs). */
+
+use bootloader::bootinfo::{BootInfo, MemoryRegionType};
+
+/// The kind of a physical memory area, independent of the boot protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    BadMemory,
+    /// Memory used by the kernel image, boot structures, or page tables set up by the bootloader.
+    InUse,
+}
+
+impl MemoryKind {
+    pub const ALL: [MemoryKind; 6] = [
+        MemoryKind::Usable,
+        MemoryKind::Reserved,
+        MemoryKind::AcpiReclaimable,
+        MemoryKind::AcpiNvs,
+        MemoryKind::BadMemory,
+        MemoryKind::InUse,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MemoryKind::Usable => "usable",
+            MemoryKind::Reserved => "reserved",
+            MemoryKind::AcpiReclaimable => "acpi reclaimable",
+            MemoryKind::AcpiNvs => "acpi nvs",
+            MemoryKind::BadMemory => "bad memory",
+            MemoryKind::InUse => "in use",
+        }
+    }
+}
+
+/// A physical memory area `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryArea {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryKind,
+}
+
+pub trait BootInformation {
+    /// The virtual address at which the complete physical memory is mapped, or `None` if physical memory is identity
+    /// mapped.
+    fn physical_memory_offset(&self) -> Option<u64>;
+
+    /// Calls `f` for every memory area reported by the bootloader.
+    fn for_each_memory_area(&self, f: &mut dyn FnMut(MemoryArea));
+
+    /// The kernel command line, if the bootloader passes one.
+    fn command_line(&self) -> Option<&str>;
+}
+
+impl BootInformation for BootInfo {
+    fn physical_memory_offset(&self) -> Option<u64> {
+        Some(self.physical_memory_offset)
+    }
+
+    fn for_each_memory_area(&self, f: &mut dyn FnMut(MemoryArea)) {
+        for region in self.memory_map.iter() {
+            let kind = match region.region_type {
+                MemoryRegionType::Usable => MemoryKind::Usable,
+                MemoryRegionType::Reserved => MemoryKind::Reserved,
+                MemoryRegionType::AcpiReclaimable => MemoryKind::AcpiReclaimable,
+                MemoryRegionType::AcpiNvs => MemoryKind::AcpiNvs,
+                MemoryRegionType::BadMemory => MemoryKind::BadMemory,
+                _ => MemoryKind::InUse,
+            };
+            f(MemoryArea { start: region.range.start_addr(), end: region.range.end_addr(), kind });
+        }
+    }
+
+    /* Version 0.9 of the bootloader crate has no way to pass a command line, so it comes from QEMU instead. */
+    fn command_line(&self) -> Option<&str> {
+        crate::fwcfg::command_line()
+    }
+}
+
+/* The Multiboot2 boot information is a list of tags following an 8-byte header (total size, reserved). Every tag starts
+with a u32 type and a u32 size and is padded to an 8-byte boundary. The list ends with a tag of type 0. */
+const TAG_END: u32 = 0;
+const TAG_COMMAND_LINE: u32 = 1;
+const TAG_MEMORY_MAP: u32 = 6;
+
+/// The Multiboot2 boot information structure passed by GRUB in `ebx`.
+pub struct Multiboot2Info {
+    bytes: &'static [u8],
+}
+
+impl Multiboot2Info {
+    /// Wraps the boot information at `addr`.
+    ///
+    /// This function is unsafe because the caller must guarantee that `addr` points to a valid Multiboot2 boot
+    /// information structure that stays mapped and unmodified for the rest of the kernel's lifetime.
+    pub unsafe fn load(addr: usize) -> Multiboot2Info {
+        let total_size = core::ptr::read(addr as *const u32) as usize;
+        Multiboot2Info::from_bytes(core::slice::from_raw_parts(addr as *const u8, total_size))
+    }
+
+    pub fn from_bytes(bytes: &'static [u8]) -> Multiboot2Info {
+        Multiboot2Info { bytes }
+    }
+
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.bytes.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_u64(&self, offset: usize) -> Option<u64> {
+        Some(u64::from(self.read_u32(offset)?) | u64::from(self.read_u32(offset + 4)?) << 32)
+    }
+
+    /// Calls `f` with the type and payload of every tag until it returns `false` or the end tag is reached.
+    fn for_each_tag(&self, mut f: impl FnMut(u32, &'static [u8]) -> bool) {
+        let mut offset = 8;
+        while let (Some(kind), Some(size)) = (self.read_u32(offset), self.read_u32(offset + 4)) {
+            let size = size as usize;
+            if kind == TAG_END || size < 8 {
+                return;
+            }
+            let payload = match self.bytes.get(offset + 8..offset + size) {
+                Some(payload) => payload,
+                None => return,
+            };
+            if !f(kind, payload) {
+                return;
+            }
+            // Tags are padded to the next multiple of 8 bytes.
+            offset += (size + 7) & !7;
+        }
+    }
+}
+
+impl BootInformation for Multiboot2Info {
+    fn physical_memory_offset(&self) -> Option<u64> {
+        None
+    }
+
+    fn for_each_memory_area(&self, f: &mut dyn FnMut(MemoryArea)) {
+        let base = self.bytes.as_ptr() as usize;
+        self.for_each_tag(|kind, payload| {
+            if kind != TAG_MEMORY_MAP {
+                return true;
+            }
+            // The memory map payload is entry_size: u32, entry_version: u32, then entries of
+            // base_addr: u64, length: u64, type: u32, reserved: u32.
+            let payload_offset = payload.as_ptr() as usize - base;
+            let entry_size = match self.read_u32(payload_offset) {
+                Some(size) if size >= 24 => size as usize,
+                _ => return false,
+            };
+            let mut entry = payload_offset + 8;
+            while entry + entry_size <= payload_offset + payload.len() {
+                let (start, length, kind) =
+                    match (self.read_u64(entry), self.read_u64(entry + 8), self.read_u32(entry + 16)) {
+                        (Some(start), Some(length), Some(kind)) => (start, length, kind),
+                        _ => break,
+                    };
+                let kind = match kind {
+                    1 => MemoryKind::Usable,
+                    3 => MemoryKind::AcpiReclaimable,
+                    4 => MemoryKind::AcpiNvs,
+                    5 => MemoryKind::BadMemory,
+                    _ => MemoryKind::Reserved,
+                };
+                // An entry that wraps around the end of the address space is bogus.
+                if let Some(end) = start.checked_add(length) {
+                    f(MemoryArea { start, end, kind });
+                }
+                entry += entry_size;
+            }
+            false
+        });
+    }
+
+    fn command_line(&self) -> Option<&str> {
+        let mut command_line = None;
+        self.for_each_tag(|kind, payload| {
+            if kind == TAG_COMMAND_LINE {
+                // The command line is a zero terminated UTF-8 string.
+                let len = payload.iter().position(|&b| b == 0).unwrap_or(payload.len());
+                command_line = core::str::from_utf8(&payload[..len]).ok();
+                return false;
+            }
+            true
+        });
+        command_line
+    }
+}
+
+/// Returns the value of `key=value` in a space separated kernel command line, e.g. `param(cmdline, "log")`.
+pub fn param<'a>(command_line: &'a str, key: &str) -> Option<&'a str> {
+    command_line
+        .split_whitespace()
+        .filter_map(|arg| arg.split_once('='))
+        .find(|&(k, _)| k == key)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+#[repr(C, align(8))]
+struct TestInfo([u8; 104]);
+
+/* A Multiboot2 structure with a command line tag and a memory map tag holding two entries, given as (offset, u32)
+pairs. All other bytes are zero. */
+#[cfg(test)]
+static TEST_INFO: TestInfo = {
+    const WORDS: [(usize, u32); 14] = [
+        (0, 104),                                // total size
+        (8, TAG_COMMAND_LINE), (12, 8 + 10),     // "log=debug\0" at 16, padded to 32
+        (32, TAG_MEMORY_MAP), (36, 16 + 2 * 24), // entry size and version follow
+        (40, 24), (44, 0),
+        (56, 0x9fc00), (64, 1),                  // 0..0x9fc00, available
+        (72, 0x100000), (80, 0x7ee0000), (88, 2), // 0x100000..0x7fe0000, reserved
+        (96, TAG_END), (100, 8),
+    ];
+    let mut bytes = [0u8; 104];
+    let mut i = 0;
+    while i < WORDS.len() {
+        let (offset, value) = WORDS[i];
+        let le = value.to_le_bytes();
+        bytes[offset] = le[0];
+        bytes[offset + 1] = le[1];
+        bytes[offset + 2] = le[2];
+        bytes[offset + 3] = le[3];
+        i += 1;
+    }
+    let command_line = b"log=debug";
+    let mut j = 0;
+    while j < command_line.len() {
+        bytes[16 + j] = command_line[j];
+        j += 1;
+    }
+    TestInfo(bytes)
+};
+
+#[test_case]
+fn test_multiboot2_command_line() {
+    let info = Multiboot2Info::from_bytes(&TEST_INFO.0);
+    assert_eq!(info.command_line(), Some("log=debug"));
+    assert_eq!(param(info.command_line().unwrap(), "log"), Some("debug"));
+    assert_eq!(param("selftest=on log=info", "selftest"), Some("on"));
+}
+
+#[test_case]
+fn test_multiboot2_memory_map() {
+    let info = Multiboot2Info::from_bytes(&TEST_INFO.0);
+    let mut areas = [None; 3];
+    let mut count = 0;
+    info.for_each_memory_area(&mut |area| {
+        areas[count] = Some(area);
+        count += 1;
+    });
+    assert_eq!(count, 2);
+    assert_eq!(areas[0], Some(MemoryArea { start: 0, end: 0x9fc00, kind: MemoryKind::Usable }));
+    assert_eq!(areas[1], Some(MemoryArea { start: 0x100000, end: 0x7fe0000, kind: MemoryKind::Reserved }));
+}
+
+#[test_case]
+fn test_multiboot2_frame_allocator() {
+    let info = Multiboot2Info::from_bytes(&TEST_INFO.0);
+    // Safety: the allocator only reads the memory map here and never hands out a frame.
+    let frames = unsafe { crate::memory::BootInfoFrameAllocator::init(&info) };
+    let mut regions = frames.usable_regions();
+    assert_eq!(regions.next(), Some((0, 0x9fc00)));
+    assert_eq!(regions.next(), None);
+}
@@ -0,0 +1,193 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/* Kernel assertions, in the spirit of Linux's BUG() and WARN().
+
+    kassert!(cond)      checks an invariant in every build, not only debug builds like debug_assert!.
+    bug!("...")         reports a state the kernel can't continue from and stops the current CPU.
+    warn_once!("...")   reports something suspicious the kernel can continue from, once per call site.
+
+Every report carries the file and line it came from and goes through the log, so it also ends up in dmesg. A BUG
+additionally dumps the control registers and the stack pointer, then halts the current CPU with interrupts disabled
+instead of panicking: with more than one CPU, the others keep running and can still be inspected. During a test run,
+bug! panics instead, so that the test fails and the run continues.
+
+Warnings from a broken loop could drown the log, so all warnings share a rate limit, and the number of suppressed ones is
+reported with the next warning that gets through. */
+
+/// Allows at most `burst` events in every window of `interval_ms` milliseconds.
+pub struct RateLimit {
+    interval_ms: u64,
+    burst: usize,
+    window_start: AtomicU64,
+    count: AtomicUsize,
+    suppressed: AtomicUsize,
+}
+
+impl RateLimit {
+    pub const fn new(interval_ms: u64, burst: usize) -> RateLimit {
+        RateLimit {
+            interval_ms,
+            burst,
+            window_start: AtomicU64::new(0),
+            count: AtomicUsize::new(0),
+            suppressed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether an event at time `now_ms` is allowed. Races between CPUs can let a few events too many through, which
+    /// is fine for logging.
+    pub fn allow(&self, now_ms: u64) -> bool {
+        let start = self.window_start.load(Ordering::Relaxed);
+        if now_ms >= start + self.interval_ms || now_ms < start {
+            self.window_start.store(now_ms, Ordering::Relaxed);
+            self.count.store(0, Ordering::Relaxed);
+        }
+        if self.count.fetch_add(1, Ordering::Relaxed) < self.burst {
+            true
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    /// Returns the number of events that were not allowed since the last call, and resets it.
+    pub fn take_suppressed(&self) -> usize {
+        self.suppressed.swap(0, Ordering::Relaxed)
+    }
+}
+
+static WARN_LIMIT: RateLimit = RateLimit::new(1000, 10);
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+static BUGS: AtomicUsize = AtomicUsize::new(0);
+static TESTING: AtomicBool = AtomicBool::new(false);
+static PANICKING_FROM_BUG: AtomicBool = AtomicBool::new(false);
+
+/// The number of warnings reported since boot, including suppressed ones.
+pub fn warning_count() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+/// The number of BUGs reported since boot. Only ever more than zero in tests, or on other CPUs than the halted one.
+pub fn bug_count() -> usize {
+    BUGS.load(Ordering::Relaxed)
+}
+
+/// Called by the test runner, so that bug! fails the current test instead of halting.
+pub fn set_testing(testing: bool) {
+    TESTING.store(testing, Ordering::SeqCst);
+}
+
+/// Whether the current panic was raised by bug! or kassert! during a test run. Resets the flag, so the test panic
+/// handler calls it once per panic.
+pub fn take_panicking_from_bug() -> bool {
+    PANICKING_FROM_BUG.swap(false, Ordering::SeqCst)
+}
+
+/// Whether a test run is in progress.
+pub fn testing() -> bool {
+    TESTING.load(Ordering::Relaxed)
+}
+
+#[doc(hidden)]
+pub fn _warn(file: &'static str, line: u32, args: fmt::Arguments) {
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
+    if !WARN_LIMIT.allow(crate::time::uptime_ms()) {
+        return;
+    }
+    let suppressed = WARN_LIMIT.take_suppressed();
+    if suppressed > 0 {
+        crate::warn!("{} warnings suppressed", suppressed);
+    }
+    crate::warn!("WARNING at {}:{}: {}", file, line, args);
+}
+
+#[doc(hidden)]
+#[inline(never)]
+pub fn _bug(file: &'static str, line: u32, args: fmt::Arguments) -> ! {
+    BUGS.fetch_add(1, Ordering::Relaxed);
+    if TESTING.load(Ordering::SeqCst) {
+        PANICKING_FROM_BUG.store(true, Ordering::SeqCst);
+        panic!("BUG at {}:{}: {}", file, line, args);
+    }
+    x86_64::instructions::interrupts::disable();
+    crate::error!("BUG at {}:{}: {}", file, line, args);
+    dump_registers();
+    crate::error!("halting CPU {}", crate::cpu::current_cpu());
+    crate::hlt_loop();
+}
+
+fn dump_registers() {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+    let (rsp, rbp): (u64, u64);
+    unsafe {
+        core::arch::asm!(
+            "mov {}, rsp", "mov {}, rbp",
+            out(reg) rsp, out(reg) rbp,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    let (cr3, _) = Cr3::read();
+    crate::error!("RSP={:#018x} RBP={:#018x} RFLAGS={:#018x}", rsp, rbp, x86_64::registers::rflags::read_raw());
+    crate::error!("CR0={:#018x} CR2={:#018x} CR3={:#018x} CR4={:#018x}",
+        Cr0::read_raw(), Cr2::read().as_u64(), cr3.start_address().as_u64(), Cr4::read_raw());
+}
+
+/// Reports a state the kernel can't continue from and halts the current CPU: `bug!("freed frame {:?} twice", frame)`.
+#[macro_export]
+macro_rules! bug {
+    () => ($crate::bug::_bug(file!(), line!(), format_args!("unreachable state")));
+    ($($arg:tt)*) => ($crate::bug::_bug(file!(), line!(), format_args!($($arg)*)));
+}
+
+/// Like `assert!`, but reports a failure as a BUG: `kassert!(frame.is_aligned(), "frame {:?}", frame)`.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::bug!("assertion failed: {}", stringify!($cond));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::bug!("assertion failed: {}: {}", stringify!($cond), format_args!($($arg)+));
+        }
+    };
+}
+
+/// Logs a warning with its file and line the first time this call site is reached.
+#[macro_export]
+macro_rules! warn_once {
+    ($($arg:tt)*) => {{
+        static WARNED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+        if !WARNED.swap(true, core::sync::atomic::Ordering::Relaxed) {
+            $crate::bug::_warn(file!(), line!(), format_args!($($arg)*));
+        }
+    }};
+}
+
+#[test_case]
+fn test_warn_once() {
+    let before = warning_count();
+    for _ in 0..3 {
+        warn_once!("test warning");
+    }
+    assert_eq!(warning_count(), before + 1);
+}
+
+#[test_case]
+fn test_rate_limit() {
+    let limit = RateLimit::new(100, 2);
+    assert!(limit.allow(1000));
+    assert!(limit.allow(1050));
+    assert!(!limit.allow(1099));
+    assert_eq!(limit.take_suppressed(), 1);
+    assert!(limit.allow(1100));
+}
+
+crate::should_panic_case! {
+    fn test_failed_kassert_is_a_bug() {
+        kassert!(1 + 1 == 3, "arithmetic");
+    }
+}
@@ -0,0 +1,264 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::portio::{self, PortRange};
+
+/* The CMOS NVRAM is 128 bytes of battery backed memory next to the real time clock. It is accessed through two ports:
+a register index is written to 0x70, then the register is read or written through 0x71. Two details make this easy to
+get wrong:
+
+  - Bit 7 of the index port is not part of the index but disables NMIs. Every index write sets it, so whoever wants NMIs
+    off must say so here, and everyone else must preserve it.
+  - The two port accesses must not be separated by another access, e.g. from an interrupt handler, so the pair is done
+    under a lock with interrupts disabled.
+
+Besides the clock, the firmware keeps its configuration here: the floppy drive types, the equipment byte and the memory
+sizes, protected by a checksum over registers 0x10..=0x2d that is stored big-endian in 0x2e and 0x2f. Which register
+holds the century is not standardized; the ACPI FADT names it. There is no FADT parser yet, so nothing calls
+set_century_register so far and the century is assumed to be 20. */
+
+const INDEX: u16 = 0;
+const DATA: u16 = 1;
+const NMI_DISABLE: u8 = 1 << 7;
+
+// Clock registers.
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const BINARY_MODE: u8 = 1 << 2;
+const HOURS_24: u8 = 1 << 1;
+const PM: u8 = 1 << 7;
+
+// Configuration registers.
+const FLOPPY_TYPES: u8 = 0x10;
+const EQUIPMENT: u8 = 0x14;
+const BASE_MEMORY_LOW: u8 = 0x15;
+const EXTENDED_MEMORY_LOW: u8 = 0x17;
+const CHECKSUM_FIRST: u8 = 0x10;
+const CHECKSUM_LAST: u8 = 0x2d;
+const CHECKSUM_HIGH: u8 = 0x2e;
+
+lazy_static! {
+    static ref PORTS: Mutex<PortRange> = Mutex::new(portio::claim_or_panic(0x70..=0x71, "cmos"));
+}
+
+static NMI_DISABLED: AtomicBool = AtomicBool::new(false);
+// 0 until the FADT names the century register.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+/// Reads CMOS register `index`.
+pub fn read(index: u8) -> u8 {
+    assert!(index < 0x80, "CMOS register {:#x} does not exist", index);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let ports = PORTS.lock();
+        unsafe {
+            ports.write_only::<u8>(INDEX).write(index | nmi_bit());
+            ports.port::<u8>(DATA).read()
+        }
+    })
+}
+
+/// Writes CMOS register `index`.
+///
+/// This function is unsafe because the firmware relies on the configuration registers, and a wrong value can keep the
+/// machine from booting.
+pub unsafe fn write(index: u8, value: u8) {
+    assert!(index < 0x80, "CMOS register {:#x} does not exist", index);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let ports = PORTS.lock();
+        ports.write_only::<u8>(INDEX).write(index | nmi_bit());
+        ports.port::<u8>(DATA).write(value);
+    });
+}
+
+fn nmi_bit() -> u8 {
+    if NMI_DISABLED.load(Ordering::SeqCst) {
+        NMI_DISABLE
+    } else {
+        0
+    }
+}
+
+/// Masks or unmasks NMIs at the index port. Takes effect immediately and persists across later register accesses.
+pub fn set_nmi_disabled(disabled: bool) {
+    NMI_DISABLED.store(disabled, Ordering::SeqCst);
+    // Re-select a harmless register with the new bit.
+    read(STATUS_A);
+}
+
+pub fn nmi_disabled() -> bool {
+    NMI_DISABLED.load(Ordering::SeqCst)
+}
+
+/// Sets the register that holds the century, as named by the `century` field of the ACPI FADT. 0 means there is none.
+pub fn set_century_register(index: u8) {
+    CENTURY_REGISTER.store(index, Ordering::SeqCst);
+}
+
+/// Whether the configuration checksum matches, i.e. whether the configuration registers can be trusted.
+pub fn checksum_valid() -> bool {
+    let sum: u16 = (CHECKSUM_FIRST..=CHECKSUM_LAST).map(|index| u16::from(read(index))).sum();
+    let stored = u16::from(read(CHECKSUM_HIGH)) << 8 | u16::from(read(CHECKSUM_HIGH + 1));
+    sum == stored
+}
+
+/// The type of a floppy drive, as recorded by the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloppyType {
+    None,
+    Kb360,
+    Mb1_2,
+    Kb720,
+    Mb1_44,
+    Mb2_88,
+    Unknown(u8),
+}
+
+impl From<u8> for FloppyType {
+    fn from(value: u8) -> FloppyType {
+        match value {
+            0 => FloppyType::None,
+            1 => FloppyType::Kb360,
+            2 => FloppyType::Mb1_2,
+            3 => FloppyType::Kb720,
+            4 => FloppyType::Mb1_44,
+            5 => FloppyType::Mb2_88,
+            other => FloppyType::Unknown(other),
+        }
+    }
+}
+
+/// The types of the first and the second floppy drive.
+pub fn floppy_types() -> (FloppyType, FloppyType) {
+    let types = read(FLOPPY_TYPES);
+    (FloppyType::from(types >> 4), FloppyType::from(types & 0xf))
+}
+
+/// The firmware's equipment byte: bit 0 floppy drives present, bit 1 math coprocessor, bits 4-5 initial video mode.
+pub fn equipment() -> u8 {
+    read(EQUIPMENT)
+}
+
+/// Conventional memory in KiB, as recorded by the firmware.
+pub fn base_memory_kib() -> u16 {
+    u16::from(read(BASE_MEMORY_LOW)) | u16::from(read(BASE_MEMORY_LOW + 1)) << 8
+}
+
+/// Memory above 1 MiB in KiB, up to 64 MiB, as recorded by the firmware.
+pub fn extended_memory_kib() -> u16 {
+    u16::from(read(EXTENDED_MEMORY_LOW)) | u16::from(read(EXTENDED_MEMORY_LOW + 1)) << 8
+}
+
+/// A time read from the real time clock, which keeps local time or UTC, whichever the firmware was set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime([u8; 7]);
+
+fn read_raw_time() -> RawTime {
+    let century = match CENTURY_REGISTER.load(Ordering::Relaxed) {
+        0 => 0,
+        index => read(index),
+    };
+    RawTime([read(SECONDS), read(MINUTES), read(HOURS), read(DAY), read(MONTH), read(YEAR), century])
+}
+
+/// Reads the real time clock.
+pub fn read_time() -> DateTime {
+    /* The clock updates its registers once a second, and a read during the update can see a mix of old and new
+    values. Wait for an update to finish, then read until two reads agree. */
+    let mut time = loop {
+        if read(STATUS_A) & UPDATE_IN_PROGRESS == 0 {
+            break read_raw_time();
+        }
+        core::hint::spin_loop();
+    };
+    loop {
+        let again = read_raw_time();
+        if again == time {
+            break;
+        }
+        time = again;
+    }
+    decode_time(time.0, read(STATUS_B))
+}
+
+fn decode_time([second, minute, hour, day, month, year, century]: [u8; 7], status_b: u8) -> DateTime {
+    let decode = |value: u8| {
+        if status_b & BINARY_MODE != 0 {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0xf)
+        }
+    };
+    // In 12 hour mode, bit 7 of the hour marks PM and 12 means midnight or noon.
+    let mut hour_24 = decode(hour & !PM);
+    if status_b & HOURS_24 == 0 {
+        hour_24 %= 12;
+        if hour & PM != 0 {
+            hour_24 += 12;
+        }
+    }
+    let century = if century == 0 { 20 } else { decode(century) };
+    DateTime {
+        year: u16::from(century) * 100 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour: hour_24,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Logs the clock and the firmware configuration, for boot diagnostics.
+pub fn report() {
+    if !checksum_valid() {
+        crate::warn!("cmos: configuration checksum mismatch, the CMOS battery may be dead");
+    }
+    let (first, second) = floppy_types();
+    crate::info!(
+        "cmos: time {}, floppies {:?}/{:?}, equipment {:#04x}, memory {} KiB + {} KiB",
+        read_time(),
+        first,
+        second,
+        equipment(),
+        base_memory_kib(),
+        extended_memory_kib()
+    );
+}
+
+#[test_case]
+fn test_decode_time() {
+    // 11:05:09 PM on 2024-02-29 in BCD and 12 hour mode.
+    let time = decode_time([0x09, 0x05, 0x11 | PM, 0x29, 0x02, 0x24, 0], 0);
+    assert_eq!(time, DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 5, second: 9 });
+    // 12 AM is midnight and 12 PM noon.
+    assert_eq!(decode_time([0, 0, 0x12, 1, 1, 0, 0x19], 0).hour, 0);
+    assert_eq!(decode_time([0, 0, 0x12 | PM, 1, 1, 0, 0x19], 0).hour, 12);
+    assert_eq!(decode_time([0, 0, 12, 1, 1, 99, 19], BINARY_MODE | HOURS_24).year, 1999);
+}
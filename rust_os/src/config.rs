@@ -0,0 +1,94 @@
+use crate::allocator::OomPolicy;
+use crate::log::Level;
+use crate::memory::ZeroPolicy;
+
+/* Compile-time kernel configuration. The values below are selected by one of the cargo feature presets:
+
+    cargo build --features minimal      small heap, slow tick, keyboard off, only warnings logged
+    cargo build --features server       large heap, fast tick
+    cargo build --features debug-heavy  default sizes, debug logging, boot-time self-test
+
+Without a preset, the defaults apply: the heap size and keyboard setup the kernel has always used, and a 100 Hz timer
+instead of the roughly 18.2 Hz the PIT ticks at when it isn't programmed. The presets are mutually exclusive. */
+
+#[cfg(any(
+    all(feature = "minimal", feature = "server"),
+    all(feature = "minimal", feature = "debug-heavy"),
+    all(feature = "server", feature = "debug-heavy"),
+))]
+compile_error!("the `minimal`, `server` and `debug-heavy` features are mutually exclusive");
+
+/// The name of the selected preset.
+pub const PRESET: &str = if cfg!(feature = "minimal") {
+    "minimal"
+} else if cfg!(feature = "server") {
+    "server"
+} else if cfg!(feature = "debug-heavy") {
+    "debug-heavy"
+} else {
+    "default"
+};
+
+/// Size of the kernel heap in bytes.
+pub const HEAP_SIZE: usize = if cfg!(feature = "minimal") {
+    64 * 1024 // 64 KiB
+} else if cfg!(feature = "server") {
+    1024 * 1024 // 1 MiB
+} else {
+    100 * 1024 // 100 KiB
+};
+
+/// The size the heap may grow to when it runs out of memory, see allocator.rs.
+pub const HEAP_MAX_SIZE: usize = HEAP_SIZE * 4;
+
+/// What the allocator does when the heap is full. The debug preset panics right away to make leaks visible early.
+pub const OOM_POLICY: OomPolicy = if cfg!(feature = "debug-heavy") {
+    OomPolicy::Panic
+} else {
+    OomPolicy::Reclaim
+};
+
+/// When freed frames are zeroed, see memory.rs. The debug preset zeroes them right away, so that a stale mapping of a
+/// freed frame reads zeros instead of plausible old data.
+pub const FRAME_ZERO_POLICY: ZeroPolicy = if cfg!(feature = "debug-heavy") {
+    ZeroPolicy::OnFree
+} else {
+    ZeroPolicy::OnAllocate
+};
+
+/// Frequency of the timer interrupt, which is the scheduler tick once there is a scheduler. The PIT is programmed to
+/// it during init (see pit.rs).
+pub const TIMER_HZ: u32 = if cfg!(feature = "minimal") {
+    50
+} else if cfg!(feature = "server") {
+    250
+} else {
+    100
+};
+
+/// Whether the PS/2 keyboard interrupt is unmasked.
+pub const KEYBOARD: bool = !cfg!(feature = "minimal");
+
+/// The log level used for modules without a more specific filter.
+pub const DEFAULT_LOG_LEVEL: Level = if cfg!(feature = "minimal") {
+    Level::Warn
+} else if cfg!(feature = "debug-heavy") {
+    Level::Debug
+} else {
+    Level::Info
+};
+
+/// Prints the active configuration.
+pub fn dump() {
+    crate::info!(
+        "config: preset={} heap={} KiB (max {} KiB, oom {:?}) frame zeroing={:?} timer={} Hz keyboard={} log={}",
+        PRESET,
+        HEAP_SIZE / 1024,
+        HEAP_MAX_SIZE / 1024,
+        OOM_POLICY,
+        FRAME_ZERO_POLICY,
+        TIMER_HZ,
+        if KEYBOARD { "on" } else { "off" },
+        DEFAULT_LOG_LEVEL
+    );
+}
@@ -0,0 +1,280 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::tty::{self, Mode};
+
+/* When QEMU multiplexes the serial port (for example `-serial tcp::4444,server`), log output and interactive shell
+bytes arrive on the same byte stream. To let a host-side terminal client tell them apart, the kernel can switch to a
+framed protocol: every chunk of output is wrapped in a frame that carries the channel it belongs to.
+
+A frame looks like this on the wire:
+
+    FRAME_DELIMITER | channel | payload (escaped) | FRAME_DELIMITER
+
+The payload is byte-stuffed in the same way as HDLC/SLIP: any FRAME_DELIMITER or FRAME_ESCAPE byte inside the payload is
+sent as FRAME_ESCAPE followed by the original byte XOR ESCAPE_XOR. This way, the delimiter can never appear inside a frame
+and a client that connects in the middle of a stream can resynchronize at the next delimiter.
+
+The host uses the same framing in the other direction. Frames on the Shell channel carry keyboard input, and frames on
+the Control channel carry commands such as "enter raw mode", which switch the mode of the console's line discipline (see
+tty.rs). Framing is switched on with `console=framed` on the kernel command line. Without it, every byte received on
+the serial port is shell input. */
+
+pub const FRAME_DELIMITER: u8 = 0x7e;
+pub const FRAME_ESCAPE: u8 = 0x7d;
+const ESCAPE_XOR: u8 = 0x20;
+
+/// The logical channel a frame belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Channel {
+    Log = 0,
+    Shell = 1,
+    Control = 2,
+}
+
+impl Channel {
+    fn from_u8(byte: u8) -> Option<Channel> {
+        match byte {
+            0 => Some(Channel::Log),
+            1 => Some(Channel::Shell),
+            2 => Some(Channel::Control),
+            _ => None,
+        }
+    }
+}
+
+/// Commands sent by the host on the control channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Deliver every input byte as is, without line editing or echo.
+    EnterRawMode,
+    /// Go back to line-based (cooked) input.
+    ExitRawMode,
+}
+
+impl ControlCommand {
+    fn from_u8(byte: u8) -> Option<ControlCommand> {
+        match byte {
+            b'R' => Some(ControlCommand::EnterRawMode),
+            b'C' => Some(ControlCommand::ExitRawMode),
+            _ => None,
+        }
+    }
+}
+
+/* Framing is off by default so that the plain `-serial stdio` setup used by the test runner keeps working unchanged. */
+static FRAMING_ENABLED: AtomicBool = AtomicBool::new(false);
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+
+pub fn enable_framing() {
+    FRAMING_ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn disable_framing() {
+    FRAMING_ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn framing_enabled() -> bool {
+    FRAMING_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Enables framing if the kernel command line asks for it with `console=framed`.
+pub fn init(command_line: Option<&str>) {
+    if command_line.and_then(|cmdline| crate::bootinfo::param(cmdline, "console")) == Some("framed") {
+        enable_framing();
+    }
+}
+
+/// Handles a byte received on the serial port. Called by the COM1 interrupt handler.
+pub fn receive(byte: u8) {
+    if !framing_enabled() {
+        shell_input(byte);
+        return;
+    }
+    // The decoder lock is released before the event reaches the line discipline.
+    let event = DECODER.lock().push(byte);
+    match event {
+        Some(InputEvent::Shell(byte)) => shell_input(byte),
+        Some(InputEvent::Control(ControlCommand::EnterRawMode)) => tty::set_mode(Mode::Raw),
+        Some(InputEvent::Control(ControlCommand::ExitRawMode)) => tty::set_mode(Mode::Canonical),
+        None => {}
+    }
+}
+
+/* Passes shell input to the console's line discipline and echoes on the shell channel. Terminals send Enter as a
+carriage return. Only ASCII is accepted, since the bytes of a multibyte character would arrive one at a time. */
+fn shell_input(byte: u8) {
+    if !byte.is_ascii() {
+        return;
+    }
+    let ch = if byte == b'\r' { '\n' } else { char::from(byte) };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        tty::CONSOLE.lock().receive(ch, |echo| crate::serial::shell_print(format_args!("{}", echo)))
+    });
+}
+
+/// Encodes `payload` as a single frame on `channel`, passing every wire byte to `sink`.
+pub fn encode_frame(channel: Channel, payload: &[u8], mut sink: impl FnMut(u8)) {
+    sink(FRAME_DELIMITER);
+    sink(channel as u8);
+    encode_payload(payload, &mut sink);
+    sink(FRAME_DELIMITER);
+}
+
+/// Byte-stuffs `payload` without the surrounding delimiters. Used to stream a frame whose payload
+/// is produced in several pieces, e.g. by `write_fmt`.
+pub fn encode_payload(payload: &[u8], mut sink: impl FnMut(u8)) {
+    for &byte in payload {
+        if byte == FRAME_DELIMITER || byte == FRAME_ESCAPE {
+            sink(FRAME_ESCAPE);
+            sink(byte ^ ESCAPE_XOR);
+        } else {
+            sink(byte);
+        }
+    }
+}
+
+/// An event produced by the `Decoder` for a byte received from the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /// A byte of interactive input destined for the shell.
+    Shell(u8),
+    /// A control command.
+    Control(ControlCommand),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecoderState {
+    /// Waiting for the delimiter that starts a frame; bytes in between frames are discarded.
+    Idle,
+    /// Saw the opening delimiter, the next byte is the channel.
+    Channel,
+    /// Inside the payload of a frame on the given channel.
+    Payload(Channel),
+    /// Inside the payload, right after an escape byte.
+    Escaped(Channel),
+}
+
+/// A byte-at-a-time decoder for frames sent by the host, fed by `receive`.
+pub struct Decoder {
+    state: DecoderState,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub const fn new() -> Decoder {
+        Decoder { state: DecoderState::Idle }
+    }
+
+    /// Feeds one received byte into the decoder, returning an event once a payload byte is complete.
+    pub fn push(&mut self, byte: u8) -> Option<InputEvent> {
+        match self.state {
+            DecoderState::Idle => {
+                if byte == FRAME_DELIMITER {
+                    self.state = DecoderState::Channel;
+                }
+                None
+            }
+            DecoderState::Channel => {
+                self.state = match Channel::from_u8(byte) {
+                    Some(channel) => DecoderState::Payload(channel),
+                    // Two delimiters in a row: treat the second one as the start of the next frame.
+                    None if byte == FRAME_DELIMITER => DecoderState::Channel,
+                    None => DecoderState::Idle,
+                };
+                None
+            }
+            DecoderState::Payload(channel) => match byte {
+                FRAME_DELIMITER => {
+                    self.state = DecoderState::Idle;
+                    None
+                }
+                FRAME_ESCAPE => {
+                    self.state = DecoderState::Escaped(channel);
+                    None
+                }
+                byte => Self::payload_byte(channel, byte),
+            },
+            DecoderState::Escaped(channel) => {
+                self.state = DecoderState::Payload(channel);
+                Self::payload_byte(channel, byte ^ ESCAPE_XOR)
+            }
+        }
+    }
+
+    fn payload_byte(channel: Channel, byte: u8) -> Option<InputEvent> {
+        match channel {
+            Channel::Shell => Some(InputEvent::Shell(byte)),
+            Channel::Control => Some(InputEvent::Control(ControlCommand::from_u8(byte)?)),
+            // The host never sends log frames; ignore them.
+            Channel::Log => None,
+        }
+    }
+}
+
+#[test_case]
+fn test_frame_escapes_delimiters() {
+    let mut wire = [0u8; 16];
+    let mut len = 0;
+    encode_frame(Channel::Log, &[b'a', FRAME_DELIMITER, FRAME_ESCAPE], |byte| {
+        wire[len] = byte;
+        len += 1;
+    });
+    assert_eq!(
+        &wire[..len],
+        &[FRAME_DELIMITER, 0, b'a', FRAME_ESCAPE, 0x5e, FRAME_ESCAPE, 0x5d, FRAME_DELIMITER]
+    );
+}
+
+#[test_case]
+fn test_decoder_round_trip() {
+    let payload = [b'l', b's', FRAME_DELIMITER, b'\n'];
+    let mut decoder = Decoder::new();
+    let mut decoded = [0u8; 4];
+    let mut len = 0;
+    encode_frame(Channel::Shell, &payload, |byte| {
+        if let Some(InputEvent::Shell(byte)) = decoder.push(byte) {
+            decoded[len] = byte;
+            len += 1;
+        }
+    });
+    assert_eq!(&decoded[..len], &payload);
+}
+
+#[test_case]
+fn test_decoder_control_raw_mode() {
+    let mut decoder = Decoder::new();
+    let mut events = [None; 2];
+    let mut i = 0;
+    encode_frame(Channel::Control, b"RC", |byte| {
+        if let Some(event) = decoder.push(byte) {
+            events[i] = Some(event);
+            i += 1;
+        }
+    });
+    assert_eq!(events[0], Some(InputEvent::Control(ControlCommand::EnterRawMode)));
+    assert_eq!(events[1], Some(InputEvent::Control(ControlCommand::ExitRawMode)));
+}
+
+#[test_case]
+fn test_control_frames_switch_the_tty_mode() {
+    let mut frame = [0u8; 4];
+    let mut len = 0;
+    enable_framing();
+    encode_frame(Channel::Control, b"R", |byte| {
+        frame[len] = byte;
+        len += 1;
+    });
+    frame[..len].iter().for_each(|&byte| receive(byte));
+    let raw = x86_64::instructions::interrupts::without_interrupts(|| tty::CONSOLE.lock().mode());
+    frame[2] = b'C';
+    frame[..len].iter().for_each(|&byte| receive(byte));
+    let canonical = x86_64::instructions::interrupts::without_interrupts(|| tty::CONSOLE.lock().mode());
+    disable_framing();
+    assert_eq!((raw, canonical), (Mode::Raw, Mode::Canonical));
+}
@@ -0,0 +1,11 @@
+/* Per-CPU bookkeeping. Data that every CPU needs a copy of, like trace buffers, interrupt stacks or idle statistics, is
+kept in static arrays of MAX_CPUS entries and indexed with current_cpu(). That avoids locking, as long as every CPU
+only touches its own entry. */
+
+/// Upper bound on the number of CPUs the kernel keeps per-CPU data for.
+pub const MAX_CPUS: usize = 4;
+
+/// The index of the CPU we are running on. Only the bootstrap processor runs kernel code so far.
+pub fn current_cpu() -> usize {
+    0
+}
@@ -0,0 +1,30 @@
+use x86_64::registers::debug::{Dr6, Dr6Flags};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/* Kernel debugging facilities built on the x86 debug registers and the debug exception (#DB, vector 1).
+
+The CPU raises #DB for several reasons at once, and reports which ones in the DR6 status register. DR6 is sticky: the
+handler has to clear it, otherwise the next exception would see stale bits. */
+
+pub mod hwbreak;
+pub mod singlestep;
+
+/// Called by the #DB handler in interrupts.rs.
+pub fn handle_debug_exception(stack_frame: &mut InterruptStackFrame) {
+    let status = Dr6::read();
+
+    if status.intersects(Dr6Flags::TRAP) {
+        hwbreak::handle(status, stack_frame);
+    }
+    if status.contains(Dr6Flags::STEP) {
+        singlestep::handle(stack_frame);
+    }
+
+    clear_dr6();
+}
+
+fn clear_dr6() {
+    unsafe {
+        core::arch::asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack, preserves_flags));
+    }
+}
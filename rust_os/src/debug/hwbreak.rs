@@ -0,0 +1,207 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::registers::debug::{
+    BreakpointCondition, BreakpointSize, DebugAddressRegister, DebugAddressRegisterNumber, Dr0, Dr1, Dr2, Dr3,
+    Dr6Flags, Dr7, Dr7Flags,
+};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::println;
+use crate::symbols::Symbolized;
+
+/* Hardware breakpoints and watchpoints. The CPU has four debug address registers (DR0-DR3). Each one holds an address,
+and DR7 says for each of them whether it is enabled, which kind of access triggers it, and how many bytes it covers.
+When a matching access happens, the CPU raises a debug exception and sets the corresponding bit in DR6.
+
+Execute breakpoints fire before the instruction runs, so returning from the handler would fire the same breakpoint again.
+Setting the resume flag (RF) in the saved RFLAGS tells the CPU to skip instruction breakpoints for one instruction.
+Data watchpoints fire after the access, so they don't need this. */
+
+/// The kind of access a hardware breakpoint triggers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Executing the instruction at the address.
+    Execute,
+    /// Writing to the watched bytes.
+    Write,
+    /// Reading or writing the watched bytes.
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwBreakError {
+    /// All four debug address registers are in use.
+    NoFreeSlot,
+    /// Watchpoints cover 1, 2, 4 or 8 bytes; execute breakpoints always cover 1 byte.
+    InvalidLength,
+    /// The address must be aligned to the watched length.
+    Unaligned,
+    /// The slot number is not 0-3 or the slot is not in use.
+    InvalidSlot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u64,
+    pub kind: Kind,
+    pub len: usize,
+    pub hits: u64,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            Kind::Execute => write!(f, "execute at {}", Symbolized(self.address))?,
+            Kind::Write => write!(f, "write to {:#x} ({} bytes)", self.address, self.len)?,
+            Kind::Access => write!(f, "access to {:#x} ({} bytes)", self.address, self.len)?,
+        }
+        write!(f, ", {} hits", self.hits)
+    }
+}
+
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; 4]> = Mutex::new([None; 4]);
+
+fn register(slot: usize) -> DebugAddressRegisterNumber {
+    DebugAddressRegisterNumber::new(slot as u8).expect("invalid debug register slot")
+}
+
+fn write_address(slot: usize, address: u64) {
+    match slot {
+        0 => Dr0::write(address),
+        1 => Dr1::write(address),
+        2 => Dr2::write(address),
+        _ => Dr3::write(address),
+    }
+}
+
+/// Installs a hardware breakpoint and returns its slot (0-3).
+pub fn set(address: u64, kind: Kind, len: usize) -> Result<usize, HwBreakError> {
+    let size = match kind {
+        Kind::Execute if len != 1 => return Err(HwBreakError::InvalidLength),
+        _ => BreakpointSize::new(len).ok_or(HwBreakError::InvalidLength)?,
+    };
+    if address & (len as u64 - 1) != 0 {
+        return Err(HwBreakError::Unaligned);
+    }
+    let condition = match kind {
+        Kind::Execute => BreakpointCondition::InstructionExecution,
+        Kind::Write => BreakpointCondition::DataWrites,
+        Kind::Access => BreakpointCondition::DataReadsWrites,
+    };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut breakpoints = BREAKPOINTS.lock();
+        let slot = breakpoints.iter().position(Option::is_none).ok_or(HwBreakError::NoFreeSlot)?;
+        let number = register(slot);
+
+        write_address(slot, address);
+        let mut dr7 = Dr7::read();
+        dr7.set_condition(number, condition);
+        dr7.set_size(number, size);
+        dr7.insert_flags(Dr7Flags::global_breakpoint_enable(number));
+        Dr7::write(dr7);
+
+        breakpoints[slot] = Some(Breakpoint { address, kind, len, hits: 0 });
+        Ok(slot)
+    })
+}
+
+/// Removes the breakpoint in `slot`.
+pub fn clear(slot: usize) -> Result<Breakpoint, HwBreakError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut breakpoints = BREAKPOINTS.lock();
+        let breakpoint = breakpoints.get_mut(slot).and_then(Option::take).ok_or(HwBreakError::InvalidSlot)?;
+        let number = register(slot);
+        let mut dr7 = Dr7::read();
+        dr7.remove_flags(Dr7Flags::global_breakpoint_enable(number) | Dr7Flags::local_breakpoint_enable(number));
+        Dr7::write(dr7);
+        write_address(slot, 0);
+        Ok(breakpoint)
+    })
+}
+
+/// Returns the breakpoint in `slot`, if any.
+pub fn get(slot: usize) -> Option<Breakpoint> {
+    x86_64::instructions::interrupts::without_interrupts(|| BREAKPOINTS.lock().get(slot).copied().flatten())
+}
+
+/// Prints all installed breakpoints.
+pub fn list() {
+    // Printed from a copy, so that a watchpoint on memory the printing touches doesn't fire with the lock held.
+    let breakpoints = x86_64::instructions::interrupts::without_interrupts(|| *BREAKPOINTS.lock());
+    for (slot, breakpoint) in breakpoints.iter().enumerate() {
+        if let Some(breakpoint) = breakpoint {
+            println!("hwbreak {}: {}", slot, breakpoint);
+        }
+    }
+}
+
+/// Reports the breakpoints that caused a debug exception. Called from `debug::handle_debug_exception`.
+pub(crate) fn handle(status: Dr6Flags, stack_frame: &mut InterruptStackFrame) {
+    /* A debug exception isn't masked by disabling interrupts, so a watchpoint can fire while set or clear hold the lock
+    on this CPU, and waiting for it would deadlock. The hit is then reported without its details and not counted. */
+    let mut breakpoints = match BREAKPOINTS.try_lock() {
+        Some(breakpoints) => breakpoints,
+        None => {
+            println!("HARDWARE BREAKPOINT hit while breakpoints are changed, DR6 {:?}", status);
+            // RF only affects instruction breakpoints, so it is harmless if this was a watchpoint.
+            unsafe {
+                stack_frame.as_mut().update(|frame| frame.cpu_flags |= RFlags::RESUME_FLAG.bits());
+            }
+            return;
+        }
+    };
+    let mut hit = [None; 4];
+    for (slot, entry) in breakpoints.iter_mut().enumerate() {
+        if !status.contains(Dr6Flags::trap(register(slot))) {
+            continue;
+        }
+        // The CPU may report a match for a disabled slot; ignore it.
+        if let Some(breakpoint) = entry {
+            breakpoint.hits += 1;
+            hit[slot] = Some(*breakpoint);
+        }
+    }
+    drop(breakpoints);
+
+    for (slot, breakpoint) in hit.iter().enumerate() {
+        let breakpoint = match breakpoint {
+            Some(breakpoint) => breakpoint,
+            None => continue,
+        };
+        println!(
+            "HARDWARE BREAKPOINT {} hit: {} at {}",
+            slot,
+            breakpoint,
+            Symbolized(stack_frame.instruction_pointer.as_u64())
+        );
+        if breakpoint.kind == Kind::Execute {
+            unsafe {
+                stack_frame.as_mut().update(|frame| frame.cpu_flags |= RFlags::RESUME_FLAG.bits());
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_write_watchpoint_fires() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static WATCHED: AtomicU64 = AtomicU64::new(0);
+    let address = &WATCHED as *const AtomicU64 as u64;
+
+    let slot = set(address, Kind::Write, 8).expect("no free debug register");
+    // Reads must not trigger a write watchpoint.
+    assert_eq!(WATCHED.load(Ordering::SeqCst), 0);
+    WATCHED.store(1, Ordering::SeqCst);
+    WATCHED.store(2, Ordering::SeqCst);
+    let breakpoint = clear(slot).unwrap();
+    assert_eq!(breakpoint.hits, 2);
+}
+
+#[test_case]
+fn test_invalid_breakpoints_are_rejected() {
+    assert_eq!(set(0x1000, Kind::Execute, 4), Err(HwBreakError::InvalidLength));
+    assert_eq!(set(0x1001, Kind::Write, 2), Err(HwBreakError::Unaligned));
+    assert_eq!(set(0x1000, Kind::Access, 3), Err(HwBreakError::InvalidLength));
+}
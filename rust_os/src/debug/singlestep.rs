@@ -0,0 +1,94 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::println;
+use crate::symbols::Symbolized;
+
+/* Instruction-level tracing. When the trap flag (TF) in RFLAGS is set, the CPU raises a debug exception after every
+instruction, with the STEP bit set in DR6. The CPU clears TF when it enters the handler, so the handler itself isn't
+traced, and restores it from the saved RFLAGS on iretq, so tracing continues with the next instruction.
+
+`trace(f)` sets TF, calls f, and clears TF again. The #DB handler records the instruction pointer of every step into a
+fixed-size buffer (and into the trace ring, if event tracing is enabled). Once the buffer is full, the handler clears TF
+in the saved RFLAGS, so a long running function is traced only up to MAX_STEPS instructions. */
+
+pub const MAX_STEPS: usize = 4096;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static STEPS: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_STEP: AtomicU64 = AtomicU64::new(0);
+static RIPS: [AtomicU64; MAX_STEPS] = [NO_STEP; MAX_STEPS];
+
+/// Runs `f` with single-stepping enabled and returns the number of recorded steps.
+pub fn trace(f: fn()) -> usize {
+    STEPS.store(0, Ordering::SeqCst);
+    ACTIVE.store(true, Ordering::SeqCst);
+    unsafe {
+        core::arch::asm!("pushfq", "or qword ptr [rsp], 0x100", "popfq");
+    }
+    f();
+    unsafe {
+        core::arch::asm!("pushfq", "and qword ptr [rsp], ~0x100", "popfq");
+    }
+    ACTIVE.store(false, Ordering::SeqCst);
+    step_count()
+}
+
+/// The number of instructions recorded by the last `trace`.
+pub fn step_count() -> usize {
+    STEPS.load(Ordering::SeqCst).min(MAX_STEPS)
+}
+
+/// The instruction pointer of step `index` of the last trace.
+pub fn step(index: usize) -> Option<u64> {
+    if index < step_count() {
+        Some(RIPS[index].load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/// Prints the recorded instruction trace, one symbolized address per line.
+pub fn report() {
+    println!("single-step trace: {} instructions", step_count());
+    for (index, rip) in RIPS.iter().take(step_count()).enumerate() {
+        println!("{:>5}: {}", index, Symbolized(rip.load(Ordering::Relaxed)));
+    }
+}
+
+/// Records one step. Called from `debug::handle_debug_exception` when DR6 reports a single step.
+pub(crate) fn handle(stack_frame: &mut InterruptStackFrame) {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let keep_stepping = ACTIVE.load(Ordering::Relaxed) && {
+        let index = STEPS.fetch_add(1, Ordering::Relaxed);
+        if index < MAX_STEPS {
+            RIPS[index].store(rip, Ordering::Relaxed);
+            crate::trace!("single_step", rip);
+        }
+        index + 1 < MAX_STEPS
+    };
+    if !keep_stepping {
+        unsafe {
+            stack_frame.as_mut().update(|frame| frame.cpu_flags &= !RFlags::TRAP_FLAG.bits());
+        }
+    }
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn traced_function() {
+    let mut sum = 0u64;
+    for i in 0..3 {
+        sum = core::hint::black_box(sum + i);
+    }
+    assert_eq!(sum, 3);
+}
+
+#[test_case]
+fn test_single_step_records_instructions() {
+    let steps = trace(traced_function);
+    assert!(steps > 10, "only {} steps recorded", steps);
+    // Stepping stops with the trace call, so nothing is recorded afterwards.
+    assert_eq!(step_count(), steps);
+}
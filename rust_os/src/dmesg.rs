@@ -0,0 +1,131 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/* A kernel message buffer in the style of Unix dmesg. Every log message is also appended to a fixed-size ring buffer,
+so that messages that scrolled off the VGA screen, or were logged while nobody was listening on the serial port, can be
+printed again later. The buffer is a static array, so it works before the heap exists and never allocates.
+
+Messages are stored as text lines, prefixed with the uptime in milliseconds. When the buffer is full, the oldest bytes are
+overwritten; the partially overwritten line at the start is skipped when the buffer is read. */
+
+const CAPACITY: usize = 16 * 1024;
+
+struct Ring {
+    bytes: [u8; CAPACITY],
+    // Index of the oldest byte and number of valid bytes.
+    start: usize,
+    len: usize,
+    // Whether old bytes have been overwritten, so that the first line may be cut off.
+    wrapped: bool,
+}
+
+impl Ring {
+    fn push(&mut self, byte: u8) {
+        if self.len == CAPACITY {
+            self.start = (self.start + 1) % CAPACITY;
+            self.len -= 1;
+            self.wrapped = true;
+        }
+        self.bytes[(self.start + self.len) % CAPACITY] = byte;
+        self.len += 1;
+    }
+
+    /// The contents as two slices, oldest first.
+    fn as_slices(&self) -> (&[u8], &[u8]) {
+        let end = self.start + self.len;
+        if end <= CAPACITY {
+            (&self.bytes[self.start..end], &[])
+        } else {
+            (&self.bytes[self.start..], &self.bytes[..end - CAPACITY])
+        }
+    }
+}
+
+impl fmt::Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|byte| self.push(byte));
+        Ok(())
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring { bytes: [0; CAPACITY], start: 0, len: 0, wrapped: false });
+
+/// Appends one message to the buffer. Called by the logging macros for every message that passes the log filter.
+pub fn record(args: fmt::Arguments) {
+    use core::fmt::Write;
+    without_interrupts(|| {
+        let mut ring = RING.lock();
+        let _ = writeln!(ring, "[{:>8}] {}", crate::time::uptime_ms(), args);
+    });
+}
+
+/// Calls `f` with every complete line in the buffer, oldest first, without the trailing newline.
+pub fn for_each_line(mut f: impl FnMut(&str)) {
+    without_interrupts(|| {
+        let ring = RING.lock();
+        let (first, second) = ring.as_slices();
+        let mut line = [0u8; 256];
+        let mut len = 0;
+        // After a wrap-around, everything up to the first newline is the tail of an overwritten line.
+        let mut skipping = ring.wrapped;
+        for &byte in first.iter().chain(second.iter()) {
+            if byte == b'\n' {
+                if !skipping {
+                    f(core::str::from_utf8(&line[..len]).unwrap_or("<invalid utf-8>"));
+                }
+                skipping = false;
+                len = 0;
+            } else if len < line.len() {
+                line[len] = byte;
+                len += 1;
+            }
+        }
+    });
+}
+
+/// Prints the buffer to the VGA console and the serial port. Interrupts stay disabled while printing, so that new
+/// messages can't be recorded in the middle of the dump.
+pub fn dump() {
+    for_each_line(|line| {
+        crate::println!("{}", line);
+        crate::serial_println!("{}", line);
+    });
+}
+
+/// Discards all messages.
+pub fn clear() {
+    without_interrupts(|| {
+        let mut ring = RING.lock();
+        ring.start = 0;
+        ring.len = 0;
+        ring.wrapped = false;
+    });
+}
+
+/// Handles the `dmesg` shell command: prints the buffer, and clears it afterwards if given `-c`.
+pub fn command(args: &str) {
+    dump();
+    if args.split_whitespace().any(|arg| arg == "-c") {
+        clear();
+    }
+}
+
+#[test_case]
+fn test_log_messages_are_recorded() {
+    crate::error!("dmesg test message {}", 42);
+    let mut found = false;
+    for_each_line(|line| found |= line.ends_with("[ERROR] dmesg: dmesg test message 42"));
+    assert!(found);
+}
+
+#[test_case]
+fn test_ring_wraps_at_line_boundaries() {
+    let mut ring = Ring { bytes: [0; CAPACITY], start: 0, len: 0, wrapped: false };
+    for _ in 0..CAPACITY + 10 {
+        ring.push(b'x');
+    }
+    assert!(ring.wrapped);
+    let (first, second) = ring.as_slices();
+    assert_eq!(first.len() + second.len(), CAPACITY);
+}
@@ -0,0 +1,115 @@
+use spin::Once;
+use crate::portio::PortRange;
+
+/* QEMU's firmware configuration device, fw_cfg. The bootloader crate has no way to pass a kernel command line, and
+QEMU's -append only works when QEMU loads the kernel itself, so under QEMU the command line comes from a fw_cfg file
+instead:
+
+    qemu-system-x86_64 ... -fw_cfg name=opt/rust_os/cmdline,string="log=debug memtest=on"
+
+The device has a selector port at 0x510 and a data port at 0x511. Writing an item's key to the selector rewinds the
+item, and every read of the data port returns its next byte. Key 0 holds the signature "QEMU", and key 0x19 the file
+directory: a big-endian u32 count followed by one entry per file of size: u32, key: u16, reserved: u16 and a zero padded
+56 byte name, all big-endian. On real hardware nothing decodes the ports, the signature reads as 0xff bytes, and there is
+no command line. */
+
+const SELECTOR: u16 = 0;
+const DATA: u16 = 1;
+const KEY_SIGNATURE: u16 = 0x00;
+const KEY_FILE_DIR: u16 = 0x19;
+const FILE_NAME_SIZE: usize = 56;
+
+/// The fw_cfg file that holds the kernel command line.
+pub const COMMAND_LINE_FILE: &str = "opt/rust_os/cmdline";
+/// Longer command lines are cut off.
+pub const MAX_COMMAND_LINE: usize = 256;
+
+struct CommandLine {
+    bytes: [u8; MAX_COMMAND_LINE],
+    len: usize,
+}
+
+static COMMAND_LINE: Once<Option<CommandLine>> = Once::new();
+
+/// The kernel command line that QEMU passed in `COMMAND_LINE_FILE`, or None if there is none. The file is only read on
+/// the first call.
+pub fn command_line() -> Option<&'static str> {
+    let command_line = COMMAND_LINE.call_once(read_command_line).as_ref()?;
+    core::str::from_utf8(&command_line.bytes[..command_line.len]).ok()
+}
+
+fn read_command_line() -> Option<CommandLine> {
+    // The ports are only claimed while reading, so the claim is dropped again right after.
+    let ports = PortRange::claim(0x510..=0x511, "fw_cfg").ok()?;
+    let mut device = Device { ports: &ports };
+    let mut signature = [0; 4];
+    device.select(KEY_SIGNATURE);
+    device.read(&mut signature);
+    if &signature != b"QEMU" {
+        return None;
+    }
+
+    let (size, key) = device.find_file(COMMAND_LINE_FILE)?;
+    let mut command_line = CommandLine { bytes: [0; MAX_COMMAND_LINE], len: size.min(MAX_COMMAND_LINE) };
+    device.select(key);
+    device.read(&mut command_line.bytes[..command_line.len]);
+    // A command line from a file may end in a newline or a terminating zero.
+    while command_line.len > 0 && matches!(command_line.bytes[command_line.len - 1], 0 | b'\n') {
+        command_line.len -= 1;
+    }
+    Some(command_line)
+}
+
+struct Device<'a> {
+    ports: &'a PortRange,
+}
+
+impl Device<'_> {
+    fn select(&mut self, key: u16) {
+        unsafe { self.ports.write_only::<u16>(SELECTOR).write(key) };
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) {
+        let mut data = self.ports.read_only::<u8>(DATA);
+        for byte in buffer.iter_mut() {
+            *byte = unsafe { data.read() };
+        }
+    }
+
+    fn read_array<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        self.read(&mut bytes);
+        bytes
+    }
+
+    /* The size and key of the file called `name`. */
+    fn find_file(&mut self, name: &str) -> Option<(usize, u16)> {
+        self.select(KEY_FILE_DIR);
+        let count = u32::from_be_bytes(self.read_array());
+        for _ in 0..count {
+            let size = u32::from_be_bytes(self.read_array());
+            let key = u16::from_be_bytes(self.read_array());
+            let _reserved: [u8; 2] = self.read_array();
+            let file_name: [u8; FILE_NAME_SIZE] = self.read_array();
+            if file_name_is(&file_name, name) {
+                return Some((size as usize, key));
+            }
+        }
+        None
+    }
+}
+
+fn file_name_is(file_name: &[u8; FILE_NAME_SIZE], name: &str) -> bool {
+    let len = file_name.iter().position(|&b| b == 0).unwrap_or(FILE_NAME_SIZE);
+    &file_name[..len] == name.as_bytes()
+}
+
+#[test_case]
+fn test_command_line() {
+    // The test runner passes a command line, see the test-args in Cargo.toml.
+    assert_eq!(command_line(), Some("test=fw_cfg"));
+    let mut file_name = [0; FILE_NAME_SIZE];
+    file_name[..COMMAND_LINE_FILE.len()].copy_from_slice(COMMAND_LINE_FILE.as_bytes());
+    assert!(file_name_is(&file_name, COMMAND_LINE_FILE));
+    assert!(!file_name_is(&file_name, "opt/rust_os"));
+}
@@ -1,53 +1,155 @@
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
-use lazy_static::lazy_static;
+use spin::Once;
+use crate::cpu::MAX_CPUS;
 
-/* The Global Descriptor Table (GDT) is a data structure used by Intel x86-family processors starting with the 80286 in order to 
-define the characteristics of the various memory areas used during program execution, including the base address, the size, 
+/* The Global Descriptor Table (GDT) is a data structure used by Intel x86-family processors starting with the 80286 in order to
+define the characteristics of the various memory areas used during program execution, including the base address, the size,
 and access privileges like executability and writability. These memory areas are called segments in Intel terminology. */
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/* NMIs and machine checks can arrive at any moment, including while the kernel stack is overflowing into its guard
+page. On the interrupted stack, their handlers would fault again and turn a diagnosable error into a triple fault, so
+they get interrupt stacks of their own, just like double faults. */
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
+/* Every CPU needs a GDT and a TSS of its own. The TSS holds the stacks the CPU switches to, and two CPUs that fault at the
+same time must not share a double fault stack. The GDT can't be shared either, because loading a TSS marks its
+descriptor as busy, and a busy TSS can't be loaded by a second CPU.
+
+So there is one set of stacks, one TSS and one GDT per CPU. The stacks are static, the tables are built by init_cpu the
+first time a CPU is brought up: by init for the bootstrap processor, and later during the bring-up of each application
+processor. Besides the interrupt stacks, every TSS has a privilege stack, which the CPU switches to when an interrupt
+arrives in ring 3. */
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+const NMI_STACK_SIZE: usize = 4096 * 2;
+const MACHINE_CHECK_STACK_SIZE: usize = 4096 * 2;
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 4;
+/// The number of stacks every CPU has: three interrupt stacks and the privilege stack.
+pub const STACKS_PER_CPU: usize = 4;
+
+#[repr(C, align(16))]
+struct CpuStacks {
+    double_fault: [u8; DOUBLE_FAULT_STACK_SIZE],
+    nmi: [u8; NMI_STACK_SIZE],
+    machine_check: [u8; MACHINE_CHECK_STACK_SIZE],
+    privilege: [u8; PRIVILEGE_STACK_SIZE],
+}
+
+const NO_STACKS: CpuStacks = CpuStacks {
+    double_fault: [0; DOUBLE_FAULT_STACK_SIZE],
+    nmi: [0; NMI_STACK_SIZE],
+    machine_check: [0; MACHINE_CHECK_STACK_SIZE],
+    privilege: [0; PRIVILEGE_STACK_SIZE],
+};
+
+static mut STACKS: [CpuStacks; MAX_CPUS] = [NO_STACKS; MAX_CPUS];
+
+struct Stack {
+    name: &'static str,
+    start: VirtAddr,
+    size: usize,
+}
+
+impl Stack {
+    fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+}
+
+/* The stacks of `cpu`, in the order of their IST indices, followed by the privilege stack. */
+fn cpu_stacks(cpu: usize) -> [Stack; STACKS_PER_CPU] {
+    // Newer compilers consider taking the address of a static mut safe.
+    #[allow(unused_unsafe)]
+    let starts = unsafe {
+        [
+            VirtAddr::from_ptr(core::ptr::addr_of!(STACKS[cpu].double_fault)),
+            VirtAddr::from_ptr(core::ptr::addr_of!(STACKS[cpu].nmi)),
+            VirtAddr::from_ptr(core::ptr::addr_of!(STACKS[cpu].machine_check)),
+            VirtAddr::from_ptr(core::ptr::addr_of!(STACKS[cpu].privilege)),
+        ]
     };
+    [
+        Stack { name: "double fault", start: starts[0], size: DOUBLE_FAULT_STACK_SIZE },
+        Stack { name: "nmi", start: starts[1], size: NMI_STACK_SIZE },
+        Stack { name: "machine check", start: starts[2], size: MACHINE_CHECK_STACK_SIZE },
+        Stack { name: "privilege", start: starts[3], size: PRIVILEGE_STACK_SIZE },
+    ]
 }
 
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
 use x86_64::structures::gdt::SegmentSelector;
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        // both the code_selector and tss_selector are GDT segment selectors that we need to convey to the CPU
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
-}
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TSS: Once<TaskStateSegment> = Once::new();
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
+
+static TSS: [Once<TaskStateSegment>; MAX_CPUS] = [NO_TSS; MAX_CPUS];
+static GDT: [Once<(GlobalDescriptorTable, Selectors)>; MAX_CPUS] = [NO_GDT; MAX_CPUS];
 
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+fn build_tss(cpu: usize) -> TaskStateSegment {
+    let [double_fault, nmi, machine_check, privilege] = cpu_stacks(cpu);
+    /* Register the stacks before the TSS makes them usable, so that their high-water marks can be measured (see
+    stackwatch.rs). */
+    for stack in [&double_fault, &nmi, &machine_check, &privilege].iter() {
+        unsafe {
+            crate::stackwatch::register(stack.name, Some(cpu), stack.start, stack.size).expect("too many stacks registered");
+        }
+    }
+
+    let mut tss = TaskStateSegment::new();
+    // Stacks grow down, so the CPU switches to the end of each stack.
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault.end();
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = nmi.end();
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = machine_check.end();
+    tss.privilege_stack_table[0] = privilege.end();
+    tss
+}
+
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    // both the code_selector and tss_selector are GDT segment selectors that we need to convey to the CPU
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt, Selectors { code_selector, tss_selector })
+}
+
+/// Sets up and loads the GDT and TSS of the bootstrap processor.
 pub fn init() {
+    init_cpu(crate::cpu::current_cpu());
+}
+
+/// Sets up and loads the GDT and TSS of `cpu`, which must be the CPU this runs on. Called once per CPU during bring-up.
+pub fn init_cpu(cpu: usize) {
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::segmentation::{CS, Segment};
-    
-    GDT.0.load();
+
+    assert!(cpu < MAX_CPUS, "CPU {} exceeds MAX_CPUS", cpu);
+    let tss = TSS[cpu].call_once(|| build_tss(cpu));
+    let (gdt, selectors) = GDT[cpu].call_once(|| build_gdt(tss));
+    gdt.load();
     /* We reload the code segment register using CS::set_reg and load the TSS using load_tss.  */
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.code_selector);
+        load_tss(selectors.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+/// The address of the TSS of `cpu`, if that CPU has been brought up.
+pub fn tss_address(cpu: usize) -> Option<u64> {
+    TSS.get(cpu)?.r#try().map(|tss| tss as *const TaskStateSegment as u64)
+}
+
+#[test_case]
+fn test_cpus_have_distinct_stacks() {
+    let first = cpu_stacks(0);
+    let second = cpu_stacks(1);
+    assert!(first[0].end() <= second[0].start || second[0].end() <= first[0].start);
+    assert!(tss_address(crate::cpu::current_cpu()).is_some());
+}
@@ -0,0 +1,92 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::cpu::{current_cpu, MAX_CPUS};
+
+/* The idle loop and idle time accounting. Every CPU ends up in run once it has nothing else to do: the bootstrap
+processor after kernel_main, application processors after their bring-up. Before halting, the loop does background work
+that should only use otherwise wasted time, or that is too slow for the interrupt handler that asked for it: zeroing
+freed frames (see memory.rs) and resetting a confused keyboard (see ps2.rs). Page aging will go here as well.
+
+Idle time is sampled by the timer interrupt: a tick that arrives while its CPU is halted in the idle loop counts as an
+idle tick. That is as precise as the tick, which is good enough for a percentage and costs nothing while idle. Once
+there is a scheduler, run becomes the body of each CPU's idle task. */
+
+/* Frames zeroed per idle wakeup. Interrupts are off while scrubbing, so the batch is kept small. */
+const SCRUB_BATCH: usize = 8;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_IDLE: AtomicBool = AtomicBool::new(false);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+static IDLE: [AtomicBool; MAX_CPUS] = [NOT_IDLE; MAX_CPUS];
+static IDLE_TICKS: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+static TICKS: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+
+/// The idle loop of the current CPU.
+pub fn run() -> ! {
+    let cpu = current_cpu();
+    loop {
+        crate::ps2::reinit_if_requested();
+        if crate::allocator::scrub_frames(SCRUB_BATCH) > 0 {
+            continue;
+        }
+        IDLE[cpu].store(true, Ordering::Relaxed);
+        x86_64::instructions::hlt();
+        IDLE[cpu].store(false, Ordering::Relaxed);
+    }
+}
+
+/// Counts a timer tick on the current CPU. Called by the timer interrupt handler.
+pub fn account_tick() {
+    let cpu = current_cpu();
+    TICKS[cpu].fetch_add(1, Ordering::Relaxed);
+    if IDLE[cpu].load(Ordering::Relaxed) {
+        IDLE_TICKS[cpu].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The ticks `cpu` spent idle and the ticks it counted in total.
+pub fn idle_ticks(cpu: usize) -> (u64, u64) {
+    (IDLE_TICKS[cpu].load(Ordering::Relaxed), TICKS[cpu].load(Ordering::Relaxed))
+}
+
+/// The share of ticks `cpu` spent idle since boot, in percent.
+pub fn idle_percent(cpu: usize) -> u64 {
+    let (idle, total) = idle_ticks(cpu);
+    percent(idle, total)
+}
+
+fn percent(part: u64, total: u64) -> u64 {
+    match total {
+        0 => 0,
+        total => part * 100 / total,
+    }
+}
+
+/// Prints the idle time of every CPU that has counted ticks.
+pub fn report() {
+    for cpu in 0..MAX_CPUS {
+        let (idle, total) = idle_ticks(cpu);
+        if total > 0 {
+            crate::println!("cpu{}: idle {} of {} ticks ({}%)", cpu, idle, total, percent(idle, total));
+        }
+    }
+}
+
+#[test_case]
+fn test_idle_ticks_are_counted() {
+    let cpu = current_cpu();
+    let (idle, total) = idle_ticks(cpu);
+    // Pretend to be halted in the idle loop for one tick, then busy for one.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IDLE[cpu].store(true, Ordering::Relaxed);
+        account_tick();
+        IDLE[cpu].store(false, Ordering::Relaxed);
+        account_tick();
+    });
+    let (idle_after, total_after) = idle_ticks(cpu);
+    // Real timer interrupts may have been counted in between, but never as idle.
+    assert_eq!(idle_after, idle + 1);
+    assert!(total_after >= total + 2);
+    assert_eq!(percent(1, 4), 25);
+}
@@ -2,6 +2,11 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use crate::{println, gdt};
 use lazy_static::lazy_static;
 
+pub mod audit;
+pub mod errorcode;
+
+use errorcode::{PageFaultError, SelectorErrorCode};
+
 /* There's a lot of different types of CPU exceptions, such as those caused by accessing a write-only
 page, or dividing by 0, or accessing a privileged instruction in user mode. 
 
@@ -20,24 +25,54 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
         // Set the handler for the breakpoint function.
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // The debug exception reports hardware breakpoints and single steps, see debug.rs.
+        idt.debug.set_handler_fn(debug_handler);
         unsafe {
             // tell the IDT that the double fault handler should use the double fault stack when a double fault occurs
             // this allows us to catch all double faults, even kernel stack overflows
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            // NMIs and machine checks may interrupt a stack overflow, so they get their own stacks too
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
             // set an interrupt handler for the timer interrupt
             idt[InterruptIndex::Timer.as_usize()]
                 .set_handler_fn(timer_interrupt_handler); // new
             // set an interrupt handler for the keyboard interrupt
             idt[InterruptIndex::Keyboard.as_usize()]
                 .set_handler_fn(keyboard_interrupt_handler);
+            // the local APIC timer replaces the PIT once apic::init_timer has run
+            idt[usize::from(crate::apic::TIMER_VECTOR)]
+                .set_handler_fn(apic_timer_interrupt_handler);
+            idt[usize::from(crate::apic::SPURIOUS_VECTOR)]
+                .set_handler_fn(apic_spurious_interrupt_handler);
+            // the serial port interrupts when its transmit FIFO runs empty, see serial.rs
+            idt[InterruptIndex::Com1.as_usize()]
+                .set_handler_fn(com1_interrupt_handler);
+            // the lowest priority line of each PIC doubles as its spurious interrupt
+            idt[InterruptIndex::SpuriousPrimary.as_usize()]
+                .set_handler_fn(spurious_primary_interrupt_handler);
+            idt[InterruptIndex::SpuriousSecondary.as_usize()]
+                .set_handler_fn(spurious_secondary_interrupt_handler);
             // set a handler function for page faults
             idt.page_fault.set_handler_fn(page_fault_handler);
+            // handlers for the faults that push a segment selector error code
+            idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+            idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+            idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+            idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         }
         idt
     };
 }
 
+/// The address of the kernel IDT, for checking that it is the one loaded in the IDTR.
+pub fn idt_address() -> u64 {
+    &*IDT as *const InterruptDescriptorTable as u64
+}
+
 pub fn init_idt() {
     /* The load method expects a &'static self, that is, a reference valid for the complete runtime of the program. 
     This is because the CPU will access this table and it must outlive this init function. So we make the IDT static. 
@@ -52,6 +87,10 @@ extern "x86-interrupt" fn breakpoint_handler(
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn debug_handler(mut stack_frame: InterruptStackFrame) {
+    crate::debug::handle_debug_exception(&mut stack_frame);
+}
+
 /* The test invokes the int3 function to trigger a breakpoint exception. By checking that the execution continues afterward, 
 we verify that our breakpoint handler is working correctly. */
 #[test_case]
@@ -71,6 +110,44 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+/* An NMI is raised by hardware that has detected a failure it can't report any other way, e.g. a memory parity error
+or a watchdog, and a machine check by the CPU itself after an uncorrectable hardware error. Neither can be recovered from
+yet. Both can interrupt the kernel anywhere, even while it holds the VGA or serial lock, so their handlers print with
+the emergency printers, which don't take locks, and then halt the CPU. */
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    crate::emergency_println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+    crate::emergency_serial_println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+    halt();
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    crate::emergency_println!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+    crate::emergency_serial_println!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+    if let Some(status) = machine_check_status() {
+        crate::emergency_serial_println!("IA32_MCG_STATUS: {:#x}", status);
+    }
+    halt();
+}
+
+/* IA32_MCG_STATUS tells whether the interrupted instruction can be restarted. It only exists if the CPU supports the
+machine check architecture, CPUID.1:EDX bit 14. */
+fn machine_check_status() -> Option<u64> {
+    const IA32_MCG_STATUS: u32 = 0x17a;
+    // Safety: CPUID leaf 1 exists on every x86_64 CPU. Newer compilers consider __cpuid safe to call.
+    #[allow(unused_unsafe)]
+    let features = unsafe { core::arch::x86_64::__cpuid(1).edx };
+    if features & (1 << 14) == 0 {
+        return None;
+    }
+    Some(unsafe { x86_64::registers::model_specific::Msr::new(IA32_MCG_STATUS).read() })
+}
+
+/* Halts with interrupts disabled. An NMI can still wake the CPU, so keep halting. */
+fn halt() -> ! {
+    x86_64::instructions::interrupts::disable();
+    hlt_loop();
+}
+
 /* Note that a specific combination of exceptions can lead to a double fault. For example, a divide by 0 exception followed
 by a general protection fault causes a double fault, but other combinations may not.  */
 
@@ -98,6 +175,22 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/* ChainedPics talks to the command and data ports of both PICs itself, but the ports are claimed here all the same, so
+that no other driver can take them. */
+use crate::portio::{self, PortRange};
+
+lazy_static! {
+    static ref PIC_1_PORTS: PortRange = portio::claim_or_panic(0x20..=0x21, "pic");
+    static ref PIC_2_PORTS: PortRange = portio::claim_or_panic(0xa0..=0xa1, "pic");
+}
+
+/// Remaps and initializes both PICs.
+pub fn init_pics() {
+    lazy_static::initialize(&PIC_1_PORTS);
+    lazy_static::initialize(&PIC_2_PORTS);
+    unsafe { PICS.lock().initialize() };
+}
+
 /* Use an InterruptIndex struct to represent each interrupt code. The Timer is the first interrupt line to the PIC, so it has 
 the starting index of PIC_1_OFFSET. */
 #[derive(Debug, Clone, Copy)]
@@ -105,7 +198,12 @@ the starting index of PIC_1_OFFSET. */
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     // Use offset 33 for keyboard interrupts
-    Keyboard
+    Keyboard,
+    // The first serial port is wired to IRQ 4
+    Com1 = PIC_1_OFFSET + 4,
+    // IRQ 7 and 15 are also raised for spurious interrupts
+    SpuriousPrimary = PIC_1_OFFSET + 7,
+    SpuriousSecondary = PIC_2_OFFSET + 7,
 }
 
 impl InterruptIndex {
@@ -118,8 +216,123 @@ impl InterruptIndex {
     }
 }
 
+/* Drivers sometimes need to keep their device from interrupting while they reconfigure it. Disabling interrupts
+altogether would also hold up the timer and every other device, so instead the device's own line can be masked in the
+PIC's interrupt mask register (IMR) for a while. Lines 0-7 belong to the primary PIC and 8-15 to the secondary, which is
+cascaded through line 2 of the primary. Once interrupts are routed through an IOAPIC, these functions will program its
+redirection entries instead. */
+pub const IRQ_TIMER: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_CASCADE: u8 = 2;
+pub const IRQ_COM1: u8 = 4;
+
+fn update_masks(irq: u8, f: impl FnOnce(u8, u8) -> u8) {
+    assert!(irq < 16, "IRQ {} does not exist", irq);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        let [mut primary, mut secondary] = pics.read_masks();
+        if irq < 8 {
+            primary = f(primary, 1 << irq);
+        } else {
+            secondary = f(secondary, 1 << (irq - 8));
+        }
+        pics.write_masks(primary, secondary);
+    });
+}
+
+/// Stops the PIC from delivering interrupts on line `irq`.
+pub fn mask(irq: u8) {
+    update_masks(irq, |mask, bit| mask | bit);
+}
+
+/// Lets the PIC deliver interrupts on line `irq` again.
+pub fn unmask(irq: u8) {
+    update_masks(irq, |mask, bit| mask & !bit);
+    // Lines on the secondary PIC only reach the CPU through the cascade line.
+    if irq >= 8 {
+        update_masks(IRQ_CASCADE, |mask, bit| mask & !bit);
+    }
+}
+
+pub fn is_masked(irq: u8) -> bool {
+    assert!(irq < 16, "IRQ {} does not exist", irq);
+    let [primary, secondary] = x86_64::instructions::interrupts::without_interrupts(|| unsafe { PICS.lock().read_masks() });
+    let masks = u16::from(primary) | u16::from(secondary) << 8;
+    masks & 1 << irq != 0
+}
+
+/// Runs `f` with line `irq` masked, and restores its previous state afterwards.
+pub fn with_masked<R>(irq: u8, f: impl FnOnce() -> R) -> R {
+    let was_masked = is_masked(irq);
+    mask(irq);
+    let result = f();
+    if !was_masked {
+        unmask(irq);
+    }
+    result
+}
+
+#[test_case]
+fn test_mask_unmask() {
+    // IRQ 5 is not used by the kernel.
+    let was_masked = is_masked(5);
+    unmask(5);
+    assert!(!is_masked(5));
+    with_masked(5, || assert!(is_masked(5)));
+    assert!(!is_masked(5));
+    if was_masked {
+        mask(5);
+    }
+}
+
 use crate::print;
 
+/* Code running in a hardware interrupt handler may have interrupted anybody, including code that holds the heap lock.
+If the handler then allocates, it spins on that lock forever. To catch this before it happens for real, every IRQ handler
+marks itself with an InterruptGuard, and the allocator checks in_interrupt() when the `debug-alloc` feature is enabled.
+
+The nesting depth is counted per CPU, since each CPU handles its own interrupts. */
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPT_DEPTH: [AtomicUsize; crate::cpu::MAX_CPUS] = [ZERO; crate::cpu::MAX_CPUS];
+
+/// Whether the current CPU is running a hardware interrupt handler.
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH[crate::cpu::current_cpu()].load(Ordering::Relaxed) > 0
+}
+
+/// Marks the current CPU as being in interrupt context until the guard is dropped.
+pub struct InterruptGuard {
+    cpu: usize,
+}
+
+impl InterruptGuard {
+    pub fn enter() -> InterruptGuard {
+        let cpu = crate::cpu::current_cpu();
+        INTERRUPT_DEPTH[cpu].fetch_add(1, Ordering::Relaxed);
+        InterruptGuard { cpu }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        INTERRUPT_DEPTH[self.cpu].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_interrupt_guard() {
+    assert!(!in_interrupt());
+    {
+        let _outer = InterruptGuard::enter();
+        let _inner = InterruptGuard::enter();
+        assert!(in_interrupt());
+    }
+    assert!(!in_interrupt());
+}
+
 /* Define an interrupt handler for the timer interrupt so we can run our kernel without crashes. The CPU treats internal
 and external interrupts the same way (i.e with the same InterruptStackFrame arg). 
 
@@ -127,8 +340,12 @@ When we run the code with this handler, we see that the code only prints a singl
 explicit End Of Interrupt (EOI) signal from the handler. This tells the controller that the interrupt was processed and we
 can accept another of the same type. */
 extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame)
 {
+    let _guard = InterruptGuard::enter();
+    let _audit = audit::Audit::enter(&stack_frame);
+    timer_tick(&stack_frame);
+
     /* Notify the PIC that the interrupt was handled. The notify_end_of_interrupt method determines if the primary of secondary
     PIC sent the interrupt. It then sends the EOI using the CMD and DATA ports of the respective controller. The operation is
     unsafe because we can notify with the wrong interrupt index and cause the kernel to hang as a result. */
@@ -138,6 +355,28 @@ extern "x86-interrupt" fn timer_interrupt_handler(
     }
 }
 
+/* Everything that happens on a timer tick, whether the tick comes from the PIT or the local APIC timer (see apic.rs). */
+fn timer_tick(stack_frame: &InterruptStackFrame) {
+    crate::time::tick();
+    crate::idle::account_tick();
+    crate::trace!("irq_timer", stack_frame.instruction_pointer.as_u64());
+    crate::profiler::record(stack_frame.instruction_pointer.as_u64());
+    crate::time::wheel::expire();
+}
+
+extern "x86-interrupt" fn apic_timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter();
+    let _audit = audit::Audit::enter(&stack_frame);
+    timer_tick(&stack_frame);
+    crate::apic::timer_interrupt();
+}
+
+/* The local APIC raises its spurious vector when an interrupt it was about to deliver went away. A spurious interrupt
+is not in service, so it must not be acknowledged with an EOI. */
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    SPURIOUS_APIC.fetch_add(1, Ordering::Relaxed);
+}
+
 /* We can cause a deadlock by adding a print statement to an interrupt, since the underlying writer may already be locked by 
 the kernel before the interrupt is raised (so the interrupt can never acquire the writer lock). To fix this, we can disable
 interrupts as long as the writer is locked (see vga_buffer.rs). Interrupts should only ever be disabled for a short time to
@@ -146,33 +385,58 @@ reduce the worse case interrupt latency. */
 /* Let's add an interrupt handler function for keyboard interrupts so can we can catch the keystroke events that are already
 sent to the CPU. */
 extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame)
 {
     /* To find out which key was pressed, we need to read the query the keyboard controller. We do this by reading the data port
     of the PS/2 controller which is the I/O port with number 0x60. */
-    use x86_64::instructions::port::Port;
-    // Use the scancode converter of an external crate rather than writing our own
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+    use crate::ps2;
+
+    let _guard = InterruptGuard::enter();
+    let _audit = audit::Audit::enter(&stack_frame);
 
     lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
-                HandleControl::Ignore)
-            );
+        static ref KEYBOARD: Mutex<KeyboardDecoder> = Mutex::new(KeyboardDecoder::new(ps2::scancode_set()));
     }
 
     let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
+    let scancode = ps2::read_data();
+    crate::trace!("irq_keyboard", scancode);
+
+    /* The controller may have been reinitialized since the last interrupt, e.g. by ps2::init, possibly with a different
+    scancode set. A decoder that is in the middle of a sequence, or decodes the old set, would turn the following bytes
+    into garbage. */
+    if keyboard.set != ps2::scancode_set() || keyboard.reinits != ps2::reinit_count() {
+        *keyboard = KeyboardDecoder::new(ps2::scancode_set());
+    }
 
-    let scancode: u8 = unsafe { port.read() };
     // Convert the scancode to a keyevent, which contains the type of key event (press or release) as well as the key itself.
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        // Tell the keyboard to process the keyevent and produce a decoded key that we output.
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+    // Extended keys such as the arrows, the right hand modifiers and the numpad's Enter and / arrive as an 0xe0 prefix
+    // followed by a second byte, which the decoder combines into a single event.
+    let result = if ps2::is_error_byte(scancode) {
+        // The keyboard lost track of its own state.
+        Err(None)
+    } else {
+        keyboard.add_byte(scancode).map_err(Some)
+    };
+    match result {
+        // Hand the key to the console's line discipline, which decides what to echo (see tty.rs).
+        Ok(Some(DecodedKey::Unicode(character))) => {
+            crate::tty::CONSOLE.lock().receive(character, |echo| print!("{}", echo))
+        }
+        Ok(Some(DecodedKey::RawKey(key))) => crate::tty::CONSOLE.lock().receive_key(key),
+        Ok(None) => {}
+        // A key the layout doesn't know, which says nothing about the state of the keyboard.
+        Err(Some(pc_keyboard::Error::UnknownKeyCode)) => {
+            crate::debug!("keyboard: unknown scancode {:#x}", scancode);
+        }
+        Err(error) => {
+            if let Some(error) = error {
+                crate::warn!("keyboard: bad scancode {:#x}: {:?}", scancode, error);
             }
+            // The keyboard lost track of its own state. Resetting it polls for too long for an interrupt handler, so
+            // the idle loop does it, and the decoder starts from scratch in the meantime.
+            *keyboard = KeyboardDecoder::new(ps2::scancode_set());
+            ps2::request_reinit();
         }
     }
 
@@ -182,6 +446,124 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     }
 }
 
+extern "x86-interrupt" fn com1_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter();
+    let _audit = audit::Audit::enter(&stack_frame);
+    crate::serial::handle_interrupt();
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Com1.as_u8());
+    }
+}
+
+/* A PIC raises its lowest priority line, IRQ 7 on the primary and IRQ 15 on the secondary, when the device that
+requested an interrupt withdraws the request before the CPU acknowledges it, e.g. because of electrical noise. Such a
+spurious interrupt is not in service, so it must not get an EOI: that would acknowledge whichever real interrupt is in
+service instead. The only way to tell a spurious IRQ 7 from a real one is the PIC's in-service register (ISR).
+
+A spurious IRQ 15 still went through the cascade line of the primary PIC, which did consider it real. So the primary,
+and only the primary, gets an EOI. */
+const PIC_EOI: u8 = 0x20;
+// OCW3 to make the next read of the command port return the in-service register.
+const PIC_READ_ISR: u8 = 0x0b;
+
+static SPURIOUS_PRIMARY: AtomicUsize = AtomicUsize::new(0);
+static SPURIOUS_SECONDARY: AtomicUsize = AtomicUsize::new(0);
+static SPURIOUS_APIC: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of spurious interrupts since boot, by the controller that raised them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpuriousCounts {
+    pub primary: usize,
+    pub secondary: usize,
+    pub apic: usize,
+}
+
+pub fn spurious_counts() -> SpuriousCounts {
+    SpuriousCounts {
+        primary: SPURIOUS_PRIMARY.load(Ordering::Relaxed),
+        secondary: SPURIOUS_SECONDARY.load(Ordering::Relaxed),
+        apic: SPURIOUS_APIC.load(Ordering::Relaxed),
+    }
+}
+
+/* Whether line 7 of the PIC owning `ports` is really in service. */
+fn line_7_in_service(ports: &PortRange) -> bool {
+    let mut command = ports.port::<u8>(0);
+    unsafe {
+        command.write(PIC_READ_ISR);
+        command.read() & 1 << 7 != 0
+    }
+}
+
+extern "x86-interrupt" fn spurious_primary_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter();
+    if !line_7_in_service(&PIC_1_PORTS) {
+        SPURIOUS_PRIMARY.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // A real IRQ 7, although no driver uses it yet.
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SpuriousPrimary.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn spurious_secondary_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter();
+    if !line_7_in_service(&PIC_2_PORTS) {
+        SPURIOUS_SECONDARY.fetch_add(1, Ordering::Relaxed);
+        unsafe { PIC_1_PORTS.write_only::<u8>(0).write(PIC_EOI) };
+        return;
+    }
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SpuriousSecondary.as_u8());
+    }
+}
+
+#[test_case]
+fn test_spurious_irq7_is_counted() {
+    // A software interrupt on the IRQ 7 vector looks exactly like a spurious one: nothing is in service.
+    let before = spurious_counts().primary;
+    unsafe { core::arch::asm!("int 39") };
+    assert_eq!(spurious_counts().primary, before + 1);
+}
+
+/* pc_keyboard's Keyboard is generic over the scancode set, but the set is only known once the PS/2 controller has been
+initialized (see ps2.rs), so the handler keeps one decoder for each set and uses the one that matches. Control+letter
+is mapped to the ASCII control characters, which the line discipline uses for editing, e.g. Ctrl+U to kill a line. */
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1, ScancodeSet2};
+
+struct KeyboardDecoder {
+    set: crate::ps2::ScancodeSet,
+    // The reinitialization count of the controller the decoder was created for.
+    reinits: usize,
+    set1: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    set2: Keyboard<layouts::Us104Key, ScancodeSet2>,
+}
+
+impl KeyboardDecoder {
+    fn new(set: crate::ps2::ScancodeSet) -> KeyboardDecoder {
+        KeyboardDecoder {
+            set,
+            reinits: crate::ps2::reinit_count(),
+            set1: Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::MapLettersToUnicode),
+            set2: Keyboard::new(layouts::Us104Key, ScancodeSet2, HandleControl::MapLettersToUnicode),
+        }
+    }
+
+    /// Feeds one byte from the data port to the decoder and returns the decoded key once a key press is complete.
+    fn add_byte(&mut self, byte: u8) -> Result<Option<DecodedKey>, pc_keyboard::Error> {
+        use crate::ps2::ScancodeSet;
+        // Tell the keyboard to process the keyevent and produce a decoded key.
+        Ok(match self.set {
+            ScancodeSet::Set1 => self.set1.add_byte(byte)?.and_then(|event| self.set1.process_keyevent(event)),
+            ScancodeSet::Set2 => self.set2.add_byte(byte)?.and_then(|event| self.set2.process_keyevent(event)),
+        })
+    }
+}
+
 /* We use multilevel page tables in x86-64. Page size is 4Kib, and each page entry is 8 bytes, so there are 512 entries in a single page.
 Virtual address supports 4 page level indices + an offset for the retrieved physical address to map it to the correct final physical address.  */
 /* Define handler function for page faults. 
@@ -192,6 +574,7 @@ This buffer is not updated transparently and needs to be flushed manually on pag
 The bootloader already sets up a 4-level page table for us and so the kernel already runs using virtual addresses. */
 use x86_64::structures::idt::PageFaultErrorCode;
 use crate::hlt_loop;
+use crate::symbols::Symbolized;
 
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
@@ -200,9 +583,47 @@ extern "x86-interrupt" fn page_fault_handler(
     /* The CR2 register is automatically set by the CPU on a page fault and contains the accessed virtual address that caused the page fault.  */
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    /* The fault may have hit while the VGA writer, the serial queue or the heap was locked, e.g. when the allocator ran
+    off the end of the heap, so the report must not take any lock. */
+    let address = Cr2::read();
+    let cause = crate::allocator::guard_fault(address.as_u64());
+    let instruction = Symbolized(stack_frame.instruction_pointer.as_u64());
+    crate::emergency_println!("EXCEPTION: PAGE FAULT");
+    crate::emergency_serial_println!("EXCEPTION: PAGE FAULT");
+    crate::emergency_println!("Accessed Address: {:?}", address);
+    crate::emergency_serial_println!("Accessed Address: {:?}", address);
+    if let Some(cause) = cause {
+        crate::emergency_println!("Cause: {}", cause);
+        crate::emergency_serial_println!("Cause: {}", cause);
+    }
+    crate::emergency_println!("Error Code: {}", PageFaultError(error_code));
+    crate::emergency_serial_println!("Error Code: {}", PageFaultError(error_code));
+    crate::emergency_println!("Instruction: {}", instruction);
+    crate::emergency_serial_println!("Instruction: {}", instruction);
+    crate::emergency_println!("{:#?}", stack_frame);
+    crate::emergency_serial_println!("{:#?}", stack_frame);
     hlt_loop();
-}
\ No newline at end of file
+}
+
+/* The segment related faults all report the offending selector in their error code. None of them can be resolved by
+the kernel yet, so the handlers print the decoded error code and panic. */
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    panic!("EXCEPTION: INVALID TSS\nSelector: {}\n{:#?}", SelectorErrorCode(error_code), stack_frame);
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    panic!("EXCEPTION: SEGMENT NOT PRESENT\nSelector: {}\n{:#?}", SelectorErrorCode(error_code), stack_frame);
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    panic!("EXCEPTION: STACK SEGMENT FAULT\nSelector: {}\n{:#?}", SelectorErrorCode(error_code), stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT\nSelector: {}\nInstruction: {}\n{:#?}",
+        SelectorErrorCode(error_code),
+        Symbolized(stack_frame.instruction_pointer.as_u64()),
+        stack_frame
+    );
+}
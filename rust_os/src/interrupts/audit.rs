@@ -0,0 +1,111 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+
+/* Interrupt entry audit, enabled by the `redzone-audit` feature.
+
+An interrupt that arrives in kernel mode aligns RSP down to 16 bytes and pushes its stack frame right below it, into
+what would be the interrupted code's red zone. That is only safe because the kernel is built with "disable-redzone" (see
+target_triple_config.json): otherwise a leaf function could keep data in the 128 bytes below RSP, and every interrupt
+would overwrite it.
+
+The compiler generates the entry and exit code of the x86-interrupt handlers, so a toolchain regression, or a hand
+written entry stub later on, could push the frame somewhere else or leave the stack misaligned for the Rust code it
+calls. With the audit enabled, every IRQ handler checks on entry that the frame sits where the CPU puts it: 16-byte
+aligned, so that the handler is entered with RSP at 8 modulo 16 like after a call, and directly below the interrupted
+RSP. The bytes between the frame and the interrupted RSP, which the alignment skipped, get a canary that must still be
+there on exit, when nothing but the handler has run on this part of the stack. A violation is reported as a BUG.
+
+The checks use the address of the handler's stack frame argument, which the x86-interrupt ABI passes in place. The
+handler's own RSP is of no use: by the time it runs any Rust code, the prologue has already realigned it. */
+
+const CANARY: u64 = 0xdead_c0de_dead_c0de;
+const FRAME_SIZE: u64 = core::mem::size_of::<InterruptStackFrameValue>() as u64;
+
+static AUDITS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn enabled() -> bool {
+    cfg!(feature = "redzone-audit")
+}
+
+/// The number of interrupts audited since boot.
+pub fn audit_count() -> usize {
+    AUDITS.load(Ordering::Relaxed)
+}
+
+/// Audits one interrupt from entry until it is dropped at the end of the handler.
+pub struct Audit {
+    canary: Option<(u64, u64)>,
+}
+
+impl Audit {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Audit {
+        if !enabled() {
+            return Audit { canary: None };
+        }
+        AUDITS.fetch_add(1, Ordering::Relaxed);
+        let frame = stack_frame as *const InterruptStackFrame as u64;
+        let interrupted_rsp = stack_frame.stack_pointer.as_u64();
+        crate::kassert!(
+            (frame + FRAME_SIZE) & 0xf == 0,
+            "interrupt stack frame misaligned: frame={:#x}",
+            frame
+        );
+
+        // An interrupt from user mode switches to the privilege stack, away from the interrupted one.
+        if stack_frame.code_segment & 3 != 0 {
+            return Audit { canary: None };
+        }
+        let region = canary_region(frame, interrupted_rsp);
+        crate::kassert!(
+            region.is_some(),
+            "interrupt stack frame at {:#x} is not below the interrupted rsp={:#x}",
+            frame,
+            interrupted_rsp
+        );
+        for address in words(region) {
+            // Safety: the words lie between the frame and the interrupted RSP, in the red zone nobody uses.
+            unsafe { core::ptr::write_volatile(address as *mut u64, CANARY) };
+        }
+        Audit { canary: region }
+    }
+}
+
+impl Drop for Audit {
+    fn drop(&mut self) {
+        for address in words(self.canary) {
+            let word = unsafe { core::ptr::read_volatile(address as *const u64) };
+            crate::kassert!(
+                word == CANARY,
+                "interrupt handler overwrote the red zone below the interrupted stack at {:#x}: {:#x}",
+                address,
+                word
+            );
+        }
+    }
+}
+
+/* The words between the end of a stack frame at `frame` and the interrupted RSP, or None if the frame doesn't end in
+the 16 bytes below the interrupted RSP. */
+fn canary_region(frame: u64, interrupted_rsp: u64) -> Option<(u64, u64)> {
+    let end = frame + FRAME_SIZE;
+    if end > interrupted_rsp || interrupted_rsp - end >= 16 {
+        return None;
+    }
+    Some((end, interrupted_rsp & !7))
+}
+
+fn words(region: Option<(u64, u64)>) -> impl Iterator<Item = u64> {
+    let (start, end) = region.unwrap_or((0, 0));
+    (start..end).step_by(8)
+}
+
+#[test_case]
+fn test_canary_region() {
+    // The CPU aligns an interrupted RSP of 8 modulo 16 down, leaving one word between it and the frame.
+    assert_eq!(canary_region(0x1000 - FRAME_SIZE, 0x1008), Some((0x1000, 0x1008)));
+    assert_eq!(words(canary_region(0x1000 - FRAME_SIZE, 0x1008)).count(), 1);
+    assert_eq!(words(canary_region(0x1000 - FRAME_SIZE, 0x1000)).count(), 0);
+    // A frame on another stack, e.g. an interrupt stack, has no red zone of the interrupted code below it.
+    assert_eq!(canary_region(0x8000 - FRAME_SIZE, 0x1000), None);
+    assert_eq!(canary_region(0x1000 - FRAME_SIZE, 0x2000), None);
+}
@@ -0,0 +1,126 @@
+use core::fmt;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+/* Several exceptions push an error code onto the stack before calling the handler. The raw u64 is hard to read, so this
+module decodes the two formats we care about into structs with Display implementations.
+
+Segment related exceptions (invalid TSS, segment not present, stack segment fault, general protection fault) use the
+selector error code format:
+
+    bit 0      EXT: the exception was caused by an event external to the program (e.g. a hardware interrupt)
+    bits 1-2   the table the index refers to: 0 = GDT, 1 = IDT, 2 = LDT, 3 = IDT
+    bits 3-15  the index of the descriptor in that table
+
+An error code of 0 means the fault wasn't caused by a particular segment selector. */
+
+/// The descriptor table referenced by a selector error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// A decoded selector error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorError {
+    pub external: bool,
+    pub table: DescriptorTable,
+    pub index: u16,
+}
+
+impl SelectorError {
+    /// Decodes `error_code`, returning `None` if it doesn't refer to a selector.
+    pub fn decode(error_code: u64) -> Option<SelectorError> {
+        if error_code & 0xffff == 0 {
+            return None;
+        }
+        let table = match (error_code >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        };
+        Some(SelectorError {
+            external: error_code & 1 != 0,
+            table,
+            index: ((error_code >> 3) & 0x1fff) as u16,
+        })
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.table {
+            DescriptorTable::Gdt => write!(f, "GDT entry {} (selector {:#x})", self.index, self.index << 3)?,
+            DescriptorTable::Ldt => write!(f, "LDT entry {} (selector {:#x})", self.index, self.index << 3 | 0b100)?,
+            // For the IDT, the index is the interrupt vector.
+            DescriptorTable::Idt => write!(f, "IDT vector {}", self.index)?,
+        }
+        if self.external {
+            write!(f, ", during delivery of an external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a raw selector error code, including the case that it doesn't refer to a selector.
+pub struct SelectorErrorCode(pub u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match SelectorError::decode(self.0) {
+            Some(error) => write!(f, "{}", error),
+            None => write!(f, "no selector ({:#x})", self.0),
+        }
+    }
+}
+
+/// A page fault error code with a readable description, e.g. "kernel-mode write to a non-present page".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultError(pub PageFaultErrorCode);
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" };
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let cause = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a page that doesn't allow it"
+        } else {
+            "a non-present page"
+        };
+        write!(f, "{}-mode {} {}", mode, access, cause)?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in a page table entry")?;
+        }
+        if code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            write!(f, ", protection key violation")?;
+        }
+        if code.contains(PageFaultErrorCode::SHADOW_STACK) {
+            write!(f, ", shadow stack access")?;
+        }
+        write!(f, " ({:#x})", code.bits())
+    }
+}
+
+#[test_case]
+fn test_selector_error_decoding() {
+    assert_eq!(SelectorError::decode(0), None);
+    // GDT entry 2, not external
+    assert_eq!(
+        SelectorError::decode(0x10),
+        Some(SelectorError { external: false, table: DescriptorTable::Gdt, index: 2 })
+    );
+    // IDT vector 13, external
+    assert_eq!(
+        SelectorError::decode(13 << 3 | 0b011),
+        Some(SelectorError { external: true, table: DescriptorTable::Idt, index: 13 })
+    );
+    assert_eq!(SelectorError::decode(0b100).map(|e| e.table), Some(DescriptorTable::Ldt));
+}
@@ -0,0 +1,148 @@
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::structures::paging::mapper::FlagUpdateError;
+use x86_64::VirtAddr;
+
+/* The layout of the kernel image. linker.ld starts every section of the image on a page of its own and exports the
+boundaries as symbols, so the kernel knows where its code, constants and variables are. init uses that to map each
+section with the permissions it needs, instead of trusting whatever the bootloader chose:
+
+    .text      read-only, executable
+    .rodata    read-only, not executable (this includes the symbol table, .ksymtab)
+    .data      writable, not executable
+    .bss       writable, not executable
+
+No page of the image is both writable and executable, so a stray write can't change the code and data can't be run as
+code. The symbolizer uses the bounds of .text to tell code addresses from everything else. */
+
+extern "C" {
+    static __kernel_start: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __bss_start: u8;
+    static __bss_end: u8;
+    static __kernel_end: u8;
+}
+
+/// A section of the kernel image.
+#[derive(Debug, Clone, Copy)]
+pub struct Section {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+}
+
+impl Section {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/* Only the address of a linker symbol means anything, its value must never be read. */
+fn symbol(symbol: &'static u8) -> VirtAddr {
+    VirtAddr::from_ptr(symbol)
+}
+
+/// The sections of the kernel image, in address order.
+pub fn sections() -> [Section; 4] {
+    use PageTableFlags as Flags;
+
+    let present = Flags::PRESENT;
+    unsafe {
+        [
+            Section { name: ".text", start: symbol(&__text_start), end: symbol(&__text_end), flags: present },
+            Section {
+                name: ".rodata",
+                start: symbol(&__rodata_start),
+                end: symbol(&__rodata_end),
+                flags: present | Flags::NO_EXECUTE,
+            },
+            Section {
+                name: ".data",
+                start: symbol(&__data_start),
+                end: symbol(&__data_end),
+                flags: present | Flags::WRITABLE | Flags::NO_EXECUTE,
+            },
+            Section {
+                name: ".bss",
+                start: symbol(&__bss_start),
+                end: symbol(&__bss_end),
+                flags: present | Flags::WRITABLE | Flags::NO_EXECUTE,
+            },
+        ]
+    }
+}
+
+/// The start and end of the whole image.
+pub fn bounds() -> (VirtAddr, VirtAddr) {
+    unsafe { (symbol(&__kernel_start), symbol(&__kernel_end)) }
+}
+
+/// Whether `addr` lies in the kernel's code.
+pub fn is_text(addr: u64) -> bool {
+    sections()[0].contains(VirtAddr::new_truncate(addr))
+}
+
+/// Maps every section of the image with its own permissions and reports the layout.
+pub fn init(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), FlagUpdateError> {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    /* NO_EXECUTE is reserved unless EFER.NXE is set, and without CR0.WP the kernel itself could still write to read-only
+    pages. */
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    for section in sections().iter() {
+        if section.size() == 0 {
+            continue;
+        }
+        let first = Page::<Size4KiB>::containing_address(section.start);
+        let last = Page::containing_address(section.end - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            // Safety: the flags only take permissions away that the section's contents don't need.
+            unsafe { mapper.update_flags(page, section.flags)?.flush() };
+        }
+    }
+    report();
+    Ok(())
+}
+
+/// Logs the layout of the kernel image.
+pub fn report() {
+    let (start, end) = bounds();
+    crate::info!("kimage: {:#x}..{:#x} ({} KiB)", start.as_u64(), end.as_u64(), (end - start) / 1024);
+    for section in sections().iter() {
+        crate::info!(
+            "kimage: {:<8} {:#x}..{:#x} {:>6} KiB {}{}",
+            section.name,
+            section.start.as_u64(),
+            section.end.as_u64(),
+            section.size() / 1024,
+            if section.flags.contains(PageTableFlags::WRITABLE) { "rw" } else { "r-" },
+            if section.flags.contains(PageTableFlags::NO_EXECUTE) { "-" } else { "x" }
+        );
+    }
+}
+
+#[test_case]
+fn test_sections_are_ordered_and_page_aligned() {
+    let sections = sections();
+    for pair in sections.windows(2) {
+        assert!(pair[0].end <= pair[1].start, "{} overlaps {}", pair[0].name, pair[1].name);
+    }
+    for section in sections.iter() {
+        assert!(section.start.is_aligned(4096u64) && section.end.is_aligned(4096u64), "{} is not page aligned", section.name);
+    }
+    assert!(is_text(report as fn() as usize as u64));
+    assert!(!is_text(&sections as *const _ as u64));
+}
@@ -16,6 +16,38 @@ pub mod interrupts;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod console;
+pub mod symbols;
+pub mod profiler;
+pub mod trace;
+pub mod log;
+pub mod bootinfo;
+pub mod fwcfg;
+pub mod config;
+pub mod pit;
+pub mod time;
+pub mod selftest;
+pub mod debug;
+pub mod ps2;
+pub mod tty;
+pub mod sync;
+pub mod shrinker;
+pub mod boot;
+pub mod dmesg;
+pub mod apic;
+pub mod bug;
+pub mod stackwatch;
+pub mod portio;
+pub mod cmos;
+pub mod speaker;
+pub mod shutdown;
+pub mod power;
+pub mod kimage;
+pub mod idle;
+pub mod pstore;
+pub mod memtest;
+pub mod cpu;
+pub mod registry;
 
 /* The standard library alloc crate, used for dynamic memory allocation. */
 extern crate alloc;
@@ -36,18 +68,226 @@ where
     }
 }
 
+/* Tests that are expected to panic. Since the kernel is built with panic=abort, a panic can't be caught and unwound
+like in std. Instead, when a ShouldPanic test is running, the panic handler treats the panic as the test passing and
+continues with the next test right there, on top of the stack of the panicked test. The abandoned stack frames are never
+returned to, and count towards MAX_ABANDONED_TESTS like those of failed tests.
+
+Use the should_panic_case! macro to declare such a test:
+
+    should_panic_case! {
+        fn index_out_of_bounds() {
+            let v = [1, 2, 3];
+            v[core::hint::black_box(3)];
+        }
+    }
+*/
+pub struct ShouldPanic {
+    pub name: &'static str,
+    pub test: fn(),
+}
+
+impl Testable for ShouldPanic {
+    fn run(&self) {
+        serial_print!("{}...\t", self.name);
+        EXPECTING_PANIC.store(true, Ordering::SeqCst);
+        (self.test)();
+        EXPECTING_PANIC.store(false, Ordering::SeqCst);
+        serial_println!("[test did not panic]");
+        test_failed(QemuExitCode::Failed);
+    }
+}
+
+/// Declares a `#[test_case]` that passes only if the given function panics.
+#[macro_export]
+macro_rules! should_panic_case {
+    ($(#[$meta:meta])* fn $name:ident() $body:block) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        const $name: $crate::ShouldPanic = $crate::ShouldPanic {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            test: {
+                $(#[$meta])*
+                fn $name() $body
+                $name
+            },
+        };
+    };
+}
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/* The state the panic handler needs to resume the test run: the list of tests, the index of the one that is
+currently running and the number of tests that failed so far. */
+static EXPECTING_PANIC: AtomicBool = AtomicBool::new(false);
+static CURRENT_TEST: AtomicUsize = AtomicUsize::new(0);
+static FAILED_TESTS: AtomicUsize = AtomicUsize::new(0);
+static ABANDONED_TESTS: AtomicUsize = AtomicUsize::new(0);
+// Whether interrupts were enabled when the current test started, which a panicking test can't restore on its own.
+static INTERRUPTS_ENABLED: AtomicBool = AtomicBool::new(false);
+// The exit code of the first failure, which the run exits with once all tests are done.
+static FAILURE: AtomicU32 = AtomicU32::new(QemuExitCode::Success as u32);
+// The timer that ends the run if the current test hangs.
+static WATCHDOG: Mutex<Option<time::wheel::TimerHandle>> = Mutex::new(None);
+static TESTS: Mutex<Option<TestList>> = Mutex::new(None);
+
+#[derive(Clone, Copy)]
+struct TestList(&'static [&'static dyn Testable]);
+
+/* Safety: tests only ever run on the bootstrap processor, so the list is never actually shared between CPUs. */
+unsafe impl Send for TestList {}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
-    for test in tests {
+    /* Safety: the slice lives in the frame of the generated test_main, which never returns while tests are running:
+    either run_tests_from exits QEMU, or a panic handler continues the run on top of the current stack. */
+    let tests: &'static [&'static dyn Testable] = unsafe { core::mem::transmute(tests) };
+    *TESTS.lock() = Some(TestList(tests));
+    bug::set_testing(true);
+    run_tests_from(0);
+}
+
+fn run_tests_from(first: usize) -> ! {
+    let TestList(tests) = TESTS.lock().expect("test runner not started");
+    for (index, test) in tests.iter().enumerate().skip(first) {
+        CURRENT_TEST.store(index, Ordering::SeqCst);
+        INTERRUPTS_ENABLED.store(x86_64::instructions::interrupts::are_enabled(), Ordering::SeqCst);
+        arm_watchdog();
         test.run();
     }
-    exit_qemu(QemuExitCode::Success);
+    if stackwatch::enabled() {
+        stackwatch::report();
+    }
+    let failed = FAILED_TESTS.load(Ordering::SeqCst);
+    if failed > 0 {
+        serial_println!("{} of {} tests failed", failed, tests.len());
+        speaker::signal(false);
+        exit_qemu(first_failure());
+    } else {
+        speaker::signal(true);
+        exit_qemu(QemuExitCode::Success);
+    }
+    hlt_loop();
+}
+
+/* A failing test doesn't end the run: like a test that panics on purpose, it is abandoned and the run continues with the
+next test, so that one failure doesn't hide the results of all later tests. Only the summary at the end decides the exit
+code. Every abandoned test leaves its stack frames behind, failed or not, so after MAX_ABANDONED_TESTS of them the run
+stops before the stack runs out. Build with the `test-fail-fast` feature to stop at the first failure instead. */
+const MAX_ABANDONED_TESTS: usize = 32;
+
+fn test_failed(code: QemuExitCode) -> ! {
+    let _ = FAILURE.compare_exchange(QemuExitCode::Success as u32, code as u32, Ordering::SeqCst, Ordering::SeqCst);
+    let failed = FAILED_TESTS.fetch_add(1, Ordering::SeqCst) + 1;
+    if cfg!(feature = "test-fail-fast") {
+        serial_println!("stopping after {} failed tests", failed);
+        speaker::signal(false);
+        exit_qemu(first_failure());
+        hlt_loop();
+    }
+    continue_after_abandoned_test();
+}
+
+/* Continues the run on top of the stack frames of the current test, unless too many are piled up already. The tests
+that weren't run then count as a failure. */
+fn continue_after_abandoned_test() -> ! {
+    let abandoned = ABANDONED_TESTS.fetch_add(1, Ordering::SeqCst) + 1;
+    if abandoned >= MAX_ABANDONED_TESTS {
+        serial_println!("stopping after {} failed or panicking tests, the stack is running out", abandoned);
+        let _ = FAILURE.compare_exchange(
+            QemuExitCode::Success as u32,
+            QemuExitCode::Failed as u32,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        speaker::signal(false);
+        exit_qemu(first_failure());
+        hlt_loop();
+    }
+    run_tests_from(CURRENT_TEST.load(Ordering::SeqCst) + 1);
+}
+
+fn first_failure() -> QemuExitCode {
+    match FAILURE.load(Ordering::SeqCst) {
+        0x12 => QemuExitCode::Panic,
+        0x13 => QemuExitCode::Assertion,
+        0x14 => QemuExitCode::DoubleFault,
+        0x15 => QemuExitCode::Timeout,
+        _ => QemuExitCode::Failed,
+    }
+}
+
+/* A test that hangs would otherwise only be noticed when bootimage kills QEMU after its test-timeout, which reports
+neither which test hung nor that it hung at all. Instead, every test starts a timer that ends the run with the Timeout
+exit code once the test has run for TEST_TIMEOUT_SECS. The run can't be continued from the timer interrupt, since the
+hung test might hold any lock. */
+const TEST_TIMEOUT_SECS: u64 = 60;
+
+fn arm_watchdog() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut watchdog = WATCHDOG.lock();
+        if let Some(timer) = watchdog.take() {
+            time::wheel::cancel(timer);
+        }
+        let timeout = TEST_TIMEOUT_SECS * u64::from(config::TIMER_HZ);
+        *watchdog = time::wheel::schedule_in(timeout, test_timed_out, CURRENT_TEST.load(Ordering::SeqCst)).ok();
+    });
+}
+
+fn test_timed_out(test: usize) {
+    /* A test that runs the virtual clock may advance it much further than the timeout on purpose, so the watchdog
+    waits for real time to pass again. */
+    if time::virtual_clock_enabled() {
+        *WATCHDOG.lock() = time::wheel::schedule_in(u64::from(config::TIMER_HZ), test_timed_out, test).ok();
+        return;
+    }
+    emergency_serial_println!("[timeout]\n");
+    emergency_serial_println!("Error: test #{} ran for more than {} s\n", test, TEST_TIMEOUT_SECS);
+    exit_qemu(QemuExitCode::Timeout);
+    hlt_loop();
+}
+
+/* The panicking test's stack frames are abandoned, so a lock it held would never be released and the next test would
+hang on its first print or allocation. Other global state a test may have changed on its way out is reset as well. */
+unsafe fn reset_poisoned_state() {
+    vga_buffer::WRITER.force_unlock();
+    tty::CONSOLE.force_unlock();
+    allocator::force_unlock();
+    time::wheel::force_unlock();
+    time::disable_virtual_clock();
+    /* Not simply enabled: a test kernel that never ran init has no IDT and unremapped PICs, and the first timer
+    interrupt would arrive as a double fault. */
+    if INTERRUPTS_ENABLED.load(Ordering::SeqCst) {
+        x86_64::instructions::interrupts::enable();
+    } else {
+        x86_64::instructions::interrupts::disable();
+    }
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
+    // Panics outside of a test, e.g. in init, can't be continued from.
+    let running = match TESTS.try_lock() {
+        Some(tests) => tests.is_some(),
+        // The panic happened in run_tests_from itself, which couldn't take the lock again.
+        None => false,
+    };
+    let from_bug = bug::take_panicking_from_bug();
+    if EXPECTING_PANIC.swap(false, Ordering::SeqCst) {
+        unsafe { reset_poisoned_state() };
+        serial_println!("[ok]");
+        continue_after_abandoned_test();
+    }
+    emergency_serial_println!("[failed]\n");
+    emergency_serial_println!("Error: {}\n", info);
+    symbols::backtrace(|address| emergency_serial_println!("  at {}", symbols::Symbolized(address)));
+    let code = QemuExitCode::for_panic(info, from_bug);
+    if running {
+        unsafe { reset_poisoned_state() };
+        test_failed(code);
+    }
+    speaker::signal(false);
+    exit_qemu(code);
     loop {}
 }
 
@@ -100,26 +340,105 @@ pub enum QemuExitCode {
     It is mapped back to exit code = 0 in the context of cargo test. */
     Success = 0x10, // 16 in binary
     Failed = 0x11, // 17 in binary
+    /* The failure categories, so that the host can tell why a run failed without parsing the serial output. QEMU
+    exits with 35 for Failed, 37 for Panic, 39 for Assertion, 41 for DoubleFault and 43 for Timeout. */
+    Panic = 0x12,
+    Assertion = 0x13,
+    DoubleFault = 0x14,
+    Timeout = 0x15,
+}
+
+impl QemuExitCode {
+    /// The exit status of the QEMU process after the code is written to the isa-debug-exit port.
+    pub const fn host_status(self) -> u32 {
+        (self as u32) << 1 | 1
+    }
+
+    /* Sorts a test's panic into a category by the start of its message. A failed kassert! or a bug! counts as a
+    failed assertion, even though its message starts with "BUG at". */
+    fn for_panic(info: &PanicInfo, from_bug: bool) -> QemuExitCode {
+        use core::fmt::Write;
+
+        if from_bug {
+            return QemuExitCode::Assertion;
+        }
+        let mut message = MessagePrefix { bytes: [0; 32], len: 0 };
+        let _ = write!(message, "{}", info.message());
+        let message = &message.bytes[..message.len];
+        if message.starts_with(b"assertion") {
+            QemuExitCode::Assertion
+        } else if message.starts_with(b"EXCEPTION: DOUBLE FAULT") {
+            QemuExitCode::DoubleFault
+        } else {
+            QemuExitCode::Panic
+        }
+    }
+}
+
+/* Keeps the first bytes of whatever is written to it, to classify a panic message without allocating. */
+struct MessagePrefix {
+    bytes: [u8; 32],
+    len: usize,
+}
+
+impl core::fmt::Write for MessagePrefix {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let count = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_exit_code_host_status() {
+    assert_eq!(QemuExitCode::Success.host_status(), 33);
+    assert_eq!(QemuExitCode::Timeout.host_status(), 43);
 }
 
 /* The function creates a new Port at 0xf4, which is the iobase of the isa-debug-exit device. Then it writes the passed 
 exit code to the port. */
 pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
+    lazy_static::lazy_static! {
+        static ref EXIT_PORT: portio::PortRange = portio::claim_or_panic(0xf4..=0xf7, "isa-debug-exit");
+    }
+
+    // Send the output that is still queued for the serial port, or the host never sees the last test results.
+    serial::flush();
 
     unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
+        EXIT_PORT.write_only::<u32>(0).write(exit_code as u32);
     }
 }
 
-/* Initialize the CPU interrupt handler. */
+/* Initialize the CPU interrupt handler. Every step is reported as a boot stage, see boot.rs. */
 pub fn init() {
-    interrupts::init_idt();
-    gdt::init();
+    // First, so that the filter from the `log=` kernel parameter applies to everything init logs.
+    if let Some(directives) = fwcfg::command_line().and_then(|cmdline| bootinfo::param(cmdline, "log")) {
+        let _ = boot::stage("log filter", || log::parse_directives(directives));
+    }
+    boot::step("interrupt descriptor table", interrupts::init_idt);
+    boot::step("gdt and tss", gdt::init);
     /* The interrupts::enable function of the x86_64 crate executes the special sti instruction to enable external hardware interrupts.  */
-    unsafe { interrupts::PICS.lock().initialize() };
+    boot::step("8259 pic", interrupts::init_pics);
+    boot::step("pit timer", || pit::set_frequency(config::TIMER_HZ));
+    boot::step("cmos", cmos::report);
+    boot::step("realtime clock", time::clock::init);
+    boot::step("serial interrupts", serial::enable_interrupts);
+    console::init(fwcfg::command_line());
+    // Registered first, so that it runs last and sends what the other hooks logged.
+    let _ = shutdown::register("serial", serial::flush);
+    /* Bring the PS/2 controller and keyboard into a known state. Without a working keyboard, IRQ1 is masked like in the
+    presets that don't use one. */
+    let keyboard = config::KEYBOARD && boot::stage("ps2 keyboard", ps2::init).is_ok();
+    if !keyboard {
+        interrupts::mask(interrupts::IRQ_KEYBOARD);
+    }
     x86_64::instructions::interrupts::enable();
+    /* The local APIC timer is calibrated against the PIT, so it can only take over once the PIT is ticking. */
+    if apic::x2apic_supported() {
+        let _ = boot::stage("x2apic timer", apic::init_timer);
+    }
 }
 
 pub fn hlt_loop() -> ! {
@@ -0,0 +1,295 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/* A small logging facade. Every message has a level and the module it comes from, and is printed both to the VGA
+buffer and to the serial port if the level is enabled for that module.
+
+Which levels are enabled is decided by a set of filter directives, each mapping a module path prefix to the most verbose
+level that is still printed, e.g. `interrupts=debug`. The directive with the longest matching prefix wins, and modules
+without a matching directive use the default level. Directives can be changed at runtime with `set_level`, or
+set all at once from a string like `info,interrupts=debug,memory=off` with `parse_directives`. That is also the format
+of the `log=` kernel command line parameter, which init applies before anything else.
+
+Every printed message is also kept in the kernel message buffer, see dmesg.rs. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Level> {
+        match s {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn from_u8(value: u8) -> Level {
+        match value {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/* The filter table has a fixed size and stores the module names inline, so that it can be used before the heap is
+initialized and from interrupt handlers. */
+const MAX_DIRECTIVES: usize = 16;
+const MAX_MODULE_LEN: usize = 48;
+
+#[derive(Clone, Copy)]
+struct Directive {
+    module: [u8; MAX_MODULE_LEN],
+    len: usize,
+    level: Level,
+}
+
+impl Directive {
+    fn module(&self) -> &str {
+        // Safety: the bytes were copied from a &str in set_level and are cut at the original string's length.
+        unsafe { core::str::from_utf8_unchecked(&self.module[..self.len]) }
+    }
+}
+
+struct Filter {
+    directives: [Option<Directive>; MAX_DIRECTIVES],
+}
+
+static DEFAULT_LEVEL: AtomicU8 = AtomicU8::new(crate::config::DEFAULT_LOG_LEVEL as u8);
+static FILTER: Mutex<Filter> = Mutex::new(Filter { directives: [None; MAX_DIRECTIVES] });
+
+/// Errors returned when changing the log filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterError {
+    /// The filter already holds `MAX_DIRECTIVES` module directives.
+    TooManyDirectives,
+    /// The module path is longer than `MAX_MODULE_LEN` bytes.
+    ModuleTooLong,
+    /// A directive in a directive string has an unknown level name.
+    InvalidLevel,
+}
+
+pub fn set_default_level(level: Level) {
+    DEFAULT_LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+pub fn default_level() -> Level {
+    Level::from_u8(DEFAULT_LEVEL.load(Ordering::SeqCst))
+}
+
+/* Module paths are given relative to the crate, e.g. `interrupts` rather than `rust_os::interrupts`, since that is what
+one would type at a shell. */
+fn strip_crate(module_path: &str) -> &str {
+    module_path.strip_prefix("rust_os::").unwrap_or(module_path)
+}
+
+/// Sets the most verbose level printed for `module` and all of its submodules, e.g. `set_level("net::tcp", Level::Debug)`.
+pub fn set_level(module: &str, level: Level) -> Result<(), FilterError> {
+    let module = strip_crate(module);
+    if module.len() > MAX_MODULE_LEN {
+        return Err(FilterError::ModuleTooLong);
+    }
+    without_interrupts(|| {
+        let mut filter = FILTER.lock();
+        if let Some(directive) = filter.directives.iter_mut().flatten().find(|d| d.module() == module) {
+            directive.level = level;
+            return Ok(());
+        }
+        let slot = filter.directives.iter_mut().find(|d| d.is_none()).ok_or(FilterError::TooManyDirectives)?;
+        let mut directive = Directive { module: [0; MAX_MODULE_LEN], len: module.len(), level };
+        directive.module[..module.len()].copy_from_slice(module.as_bytes());
+        *slot = Some(directive);
+        Ok(())
+    })
+}
+
+/// Removes the directive for `module`, so that it falls back to a shorter prefix or the default level.
+pub fn clear_level(module: &str) {
+    let module = strip_crate(module);
+    without_interrupts(|| {
+        let mut filter = FILTER.lock();
+        for slot in filter.directives.iter_mut() {
+            if matches!(slot, Some(d) if d.module() == module) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// Applies a comma separated list of directives. A directive is either a bare level, which sets the default level,
+/// or `module=level`. This is the format of the `log=` kernel command line parameter.
+pub fn parse_directives(directives: &str) -> Result<(), FilterError> {
+    for directive in directives.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            Some((module, level)) => {
+                let level = Level::parse(level.trim()).ok_or(FilterError::InvalidLevel)?;
+                set_level(module.trim(), level)?;
+            }
+            None => set_default_level(Level::parse(directive).ok_or(FilterError::InvalidLevel)?),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the most verbose level printed for messages from `module_path`.
+pub fn level_for(module_path: &str) -> Level {
+    let module = strip_crate(module_path);
+    without_interrupts(|| {
+        let filter = FILTER.lock();
+        filter
+            .directives
+            .iter()
+            .flatten()
+            .filter(|d| is_module_prefix(d.module(), module))
+            .max_by_key(|d| d.len)
+            .map_or_else(default_level, |d| d.level)
+    })
+}
+
+/// `net` is a prefix of `net` and `net::tcp`, but not of `network`.
+fn is_module_prefix(prefix: &str, module: &str) -> bool {
+    match module.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+pub fn enabled(level: Level, module_path: &str) -> bool {
+    level != Level::Off && level <= level_for(module_path)
+}
+
+/// Prints all filter directives.
+pub fn dump_filter() {
+    crate::println!("log: default level {}", default_level());
+    if crate::serial::nonblocking() {
+        crate::println!("log: non-blocking serial output, {} messages dropped", crate::serial::dropped_messages());
+    }
+    without_interrupts(|| {
+        let filter = FILTER.lock();
+        for directive in filter.directives.iter().flatten() {
+            crate::println!("log: {} = {}", directive.module(), directive.level);
+        }
+    });
+}
+
+/// Handles the arguments of the `log` shell command: `set <module> <level>`, `default <level>`, `clear <module>`,
+/// `nonblocking on|off` or `show`.
+pub fn command(args: &str) -> Result<(), FilterError> {
+    let mut words = args.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("set"), Some(module), Some(level)) => {
+            set_level(module, Level::parse(level).ok_or(FilterError::InvalidLevel)?)
+        }
+        (Some("default"), Some(level), None) => {
+            set_default_level(Level::parse(level).ok_or(FilterError::InvalidLevel)?);
+            Ok(())
+        }
+        (Some("clear"), Some(module), None) => {
+            clear_level(module);
+            Ok(())
+        }
+        (Some("nonblocking"), Some(mode @ ("on" | "off")), None) => {
+            crate::serial::set_nonblocking(mode == "on");
+            Ok(())
+        }
+        _ => {
+            dump_filter();
+            Ok(())
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module_path: &'static str, args: fmt::Arguments) {
+    if !enabled(level, module_path) {
+        return;
+    }
+    crate::println!("[{}] {}: {}", level, strip_crate(module_path), args);
+    crate::serial_println!("[{}] {}: {}", level, strip_crate(module_path), args);
+    crate::dmesg::record(format_args!("[{}] {}: {}", level, strip_crate(module_path), args));
+}
+
+/// Logs a message at the given level: `log!(Level::Info, "heap at {:#x}", start)`.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::log::_log($level, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[test_case]
+fn test_longest_prefix_wins() {
+    set_level("test_log", Level::Warn).unwrap();
+    set_level("test_log::inner", Level::Debug).unwrap();
+    assert!(!enabled(Level::Info, "rust_os::test_log::other"));
+    assert!(enabled(Level::Debug, "rust_os::test_log::inner::deep"));
+    assert_eq!(level_for("rust_os::test_logger"), default_level());
+    clear_level("test_log");
+    clear_level("test_log::inner");
+}
+
+#[test_case]
+fn test_parse_directives() {
+    let default = default_level();
+    parse_directives("warn, test_parse=trace").unwrap();
+    assert_eq!(default_level(), Level::Warn);
+    assert_eq!(level_for("rust_os::test_parse::x"), Level::Trace);
+    assert_eq!(parse_directives("test_parse=loud"), Err(FilterError::InvalidLevel));
+    clear_level("test_parse");
+    set_default_level(default);
+}
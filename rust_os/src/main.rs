@@ -8,7 +8,8 @@
 
 use core::panic::PanicInfo;
 use alloc::{vec, boxed::Box, vec::Vec, rc::Rc};
-use rust_os::{println, hlt_loop};
+use rust_os::println;
+use rust_os::bootinfo::BootInformation;
 use bootloader::{BootInfo, entry_point};
 
 extern crate alloc;
@@ -24,16 +25,28 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     
     println!("Hello World{}", "!");
     rust_os::init();
+    rust_os::config::dump();
 
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    // Without an offset, physical memory is identity mapped.
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset().unwrap_or(0));
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    rust_os::boot::stage("kernel image", || rust_os::kimage::init(&mut mapper))
+        .expect("failed to protect the kernel image");
+    memory::map_report(boot_info);
     let mut frame_allocator = unsafe {
-        memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
+        memory::BootInfoFrameAllocator::init(boot_info)
     };
+    // before any frame is allocated, so that the persistent region is never handed out
+    rust_os::pstore::init(&mut frame_allocator);
+    if rust_os::memtest::requested(boot_info.command_line()) {
+        rust_os::memtest::run(&mut frame_allocator);
+    }
 
     // initialize the kernel heap
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    rust_os::boot::stage("kernel heap", || allocator::init_heap(&mut mapper, &mut frame_allocator))
         .expect("heap initialization failed");
+    // from now on, the heap may grow when it runs out of memory
+    allocator::enable_growth(mapper, frame_allocator);
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
@@ -53,21 +66,34 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     core::mem::drop(reference_counted);
     println!("reference count is {} now", Rc::strong_count(&cloned_reference));
 
+    if rust_os::selftest::requested(boot_info.command_line()) {
+        rust_os::selftest::run();
+    }
+
     /* Use conditional compilation to add the call to test_main only in test contexts because 
     the function is not generated on a normal run. */
     #[cfg(test)]
     /* test_main is generated by the test framework and it just invokves the test_runner. */
     test_main();
 
-    hlt_loop();
+    rust_os::idle::run();
 }
 
 /// This function is called on panic.
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    hlt_loop();
+    // The panic may have happened while the VGA or serial writer was locked, so bypass both locks.
+    rust_os::emergency_println!("{}", info);
+    rust_os::emergency_serial_println!("{}", info);
+    rust_os::symbols::backtrace(|address| {
+        let address = rust_os::symbols::Symbolized(address);
+        rust_os::emergency_println!("  at {}", address);
+        rust_os::emergency_serial_println!("  at {}", address);
+    });
+    rust_os::pstore::record_panic(info);
+    rust_os::speaker::signal(false);
+    rust_os::hlt_loop();
 }
 
 #[cfg(test)]
@@ -37,13 +37,20 @@ To make page table frames accessible to our kernel, there are a number of approa
 We will proceed with approach 3 because it gives us a lot of flexibility (being able to access arbitrary physical memory from 
 the kernel). */
 
+pub mod refcount;
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use x86_64::structures::paging::OffsetPageTable;
 use x86_64::{
     structures::paging::PageTable,
     VirtAddr,
 };
 
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
     let level_4_table = active_level_4_table(physical_memory_offset);
     /* Translating virtual to physical addresses is a common task in an OS kernel, therefore the x86_64 crate provides an 
     abstraction for it. OffsetPageTable implements the Mapper trait, which allows for functions to be executed on pages. 
@@ -75,9 +82,44 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     &mut *page_table_ptr // unsafe
 }
 
+/// The virtual address at which the kernel can access physical address `addr`. Only valid after `init`.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst);
+    assert!(offset != 0, "physical memory is not mapped yet");
+    VirtAddr::new(offset + addr.as_u64())
+}
+
+/// Whether `addr` is mapped in the active page table. Reads the tables without taking any lock, so it can be used by the
+/// panic handler. Always false before `init`.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PageTableFlags;
+
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst);
+    if offset == 0 {
+        return false;
+    }
+    let mut table_addr = Cr3::read().0.start_address();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, &index) in indexes.iter().enumerate() {
+        // Safety: all of physical memory is mapped at `offset`, and the table is only read.
+        let table = unsafe { &*VirtAddr::new(offset + table_addr.as_u64()).as_ptr::<PageTable>() };
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        // A huge page ends the walk on level 3 (1 GiB) or level 2 (2 MiB).
+        if flags.contains(PageTableFlags::HUGE_PAGE) && level > 0 {
+            return true;
+        }
+        table_addr = table[index].addr();
+    }
+    true
+}
+
 use x86_64::{
     PhysAddr,
-    structures::paging::{Page, PhysFrame, Mapper, Size4KiB, FrameAllocator}
+    structures::paging::{Page, PhysFrame, Mapper, Size4KiB, FrameAllocator, FrameDeallocator}
 };
 
 /// Creates an example mapping for the given page to frame `0xb8000`.
@@ -100,51 +142,496 @@ pub fn create_example_mapping(
     map_to_result.expect("map_to failed").flush();
 }
 
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use crate::bootinfo::{BootInformation, MemoryArea, MemoryKind};
+
+/* A freed frame still holds whatever its last owner wrote to it. Once frames are handed to processes, that would leak
+one process's memory into the next, so freed frames are zeroed before they are reused. When that happens is a policy:
+
+  - OnFree zeroes a frame as soon as it is freed, which keeps the cost on the freeing side and leaves no stale data in
+    memory at any time.
+  - OnAllocate puts freed frames on a dirty list and zeroes them only when they are handed out again, unless the idle
+    loop got to them first (see scrub). Freeing is cheap, and most of the zeroing happens when the CPU has nothing else
+    to do.
+  - Never hands frames out as they are. Only for measuring what the zeroing costs.
+
+With either of the first two policies, every frame the allocator returns is zeroed, including frames that come straight
+from the memory map, which may still hold data of the bootloader. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ZeroPolicy {
+    Never,
+    OnFree,
+    OnAllocate,
+}
+
+static ZERO_POLICY: AtomicU8 = AtomicU8::new(crate::config::FRAME_ZERO_POLICY as u8);
+
+pub fn zero_policy() -> ZeroPolicy {
+    match ZERO_POLICY.load(Ordering::Relaxed) {
+        0 => ZeroPolicy::Never,
+        1 => ZeroPolicy::OnFree,
+        _ => ZeroPolicy::OnAllocate,
+    }
+}
+
+/// Changes when freed frames are zeroed. Frames that are already on the dirty list stay there until they are scrubbed
+/// or allocated.
+pub fn set_zero_policy(policy: ZeroPolicy) {
+    ZERO_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/* Fills `frame` with zeros through the physical memory mapping. */
+fn zero_frame(frame: PhysFrame) {
+    let virt = phys_to_virt(frame.start_address());
+    // Safety: the frame is free, so nothing else accesses it, and all of physical memory is mapped.
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frame.size() as usize) };
+}
+
+/* Free frames are kept in intrusive lists, linked through the first 8 bytes of each frame, so the lists need no
+memory of their own. The link is cleared when a frame leaves a list. */
+#[derive(Debug, Default)]
+struct FrameList {
+    head: Option<PhysFrame>,
+    len: usize,
+}
+
+impl FrameList {
+    fn link(frame: PhysFrame) -> *mut u64 {
+        phys_to_virt(frame.start_address()).as_mut_ptr()
+    }
+
+    fn push(&mut self, frame: PhysFrame) {
+        let next = self.head.map_or(0, |head| head.start_address().as_u64());
+        // Safety: the frame is free and mapped, see zero_frame.
+        unsafe { Self::link(frame).write(next) };
+        self.head = Some(frame);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<PhysFrame> {
+        let frame = self.head?;
+        // Safety: the frame is on the list, so its link was written by push.
+        let next = unsafe { Self::link(frame).replace(0) };
+        self.head = match next {
+            0 => None,
+            next => Some(PhysFrame::containing_address(PhysAddr::new(next))),
+        };
+        self.len -= 1;
+        Some(frame)
+    }
+}
+
+/* Physical memory is split into zones by what can address it. Legacy ISA DMA only reaches the first 16 MiB, and devices
+with 32 bit DMA only reach the first 4 GiB. Frames in the lower zones are taken only when the higher ones are
+exhausted, or when a DMA buffer asks for them, so they are still there when a driver needs them. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16 MiB.
+    Dma,
+    /// From 16 MiB to 4 GiB.
+    Normal,
+    /// Above 4 GiB.
+    High,
+}
+
+const ZONE_COUNT: usize = 3;
+const DMA_LIMIT: u64 = 16 << 20;
+const NORMAL_LIMIT: u64 = 4 << 30;
+
+impl Zone {
+    pub const ALL: [Zone; ZONE_COUNT] = [Zone::Dma, Zone::Normal, Zone::High];
+
+    pub fn of(frame: PhysFrame) -> Zone {
+        match frame.start_address().as_u64() {
+            addr if addr < DMA_LIMIT => Zone::Dma,
+            addr if addr < NORMAL_LIMIT => Zone::Normal,
+            _ => Zone::High,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Zone::Dma => "dma",
+            Zone::Normal => "normal",
+            Zone::High => "high",
+        }
+    }
+}
+
+/// What an allocated frame is used for. Frames are counted by owner, so a report can tell where memory went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    KernelHeap,
+    /// Page tables, and frames allocated through the `FrameAllocator` trait, which `Mapper::map_to` uses for them.
+    PageTables,
+    /// Memory of user processes, all counted together. Per-process accounting belongs to the processes themselves, once
+    /// there are any.
+    Process,
+    /// DMA buffers, which always come from the DMA zone.
+    Dma,
+}
+
+const OWNER_COUNT: usize = 4;
+
+impl Owner {
+    fn index(self) -> usize {
+        match self {
+            Owner::KernelHeap => 0,
+            Owner::PageTables => 1,
+            Owner::Process => 2,
+            Owner::Dma => 3,
+        }
+    }
+
+    /* The zones to take frames from, in order of preference. */
+    fn zones(self) -> &'static [Zone] {
+        match self {
+            Owner::Dma => &[Zone::Dma],
+            _ => &[Zone::Normal, Zone::High, Zone::Dma],
+        }
+    }
+}
+
+const OWNER_NAMES: [&str; OWNER_COUNT] = ["kernel heap", "page tables", "processes", "dma buffers"];
+
+/* The free frames of one zone: the frames of the memory map that were never handed out, which are taken in order, and
+the frames that were freed since. */
+#[derive(Debug, Default)]
+struct ZoneFrames {
+    next: usize,
+    // Freed frames that have been zeroed, and freed frames that still hold their old contents.
+    clean: FrameList,
+    dirty: FrameList,
+}
+
+/// The number of physical ranges that can be kept from the frame allocator with `reserve`.
+pub const MAX_RESERVED: usize = 32;
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+    // The usable areas of the memory map, as (start, end) physical addresses, copied so that the boot information
+    // doesn't have to outlive the allocator.
+    usable: [(u64, u64); MAX_MAP_AREAS],
+    usable_len: usize,
+    zones: [ZoneFrames; ZONE_COUNT],
+    owned: [usize; OWNER_COUNT],
+    // Physical ranges of usable memory that are never handed out, as (start, end) addresses.
+    reserved: [Option<(u64, u64)>; MAX_RESERVED],
 }
 
 impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map, which is passed from the bootloader.
+    /// Create a FrameAllocator from the memory map of the passed boot information, with either boot protocol.
     ///
     /// This function is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+    /// as usable in it are really unused. GRUB reports the kernel image and the Multiboot2
+    /// information as available memory, so on that path they must be reserved first.
+    pub unsafe fn init(boot_info: &impl BootInformation) -> Self {
+        let mut allocator = BootInfoFrameAllocator {
+            usable: [(0, 0); MAX_MAP_AREAS],
+            usable_len: 0,
+            zones: Default::default(),
+            owned: [0; OWNER_COUNT],
+            reserved: [None; MAX_RESERVED],
+        };
+        let mut dropped = 0;
+        boot_info.for_each_memory_area(&mut |area| {
+            if area.kind != MemoryKind::Usable || area.start >= area.end {
+                return;
+            }
+            match allocator.usable.get_mut(allocator.usable_len) {
+                Some(slot) => {
+                    *slot = (area.start, area.end);
+                    allocator.usable_len += 1;
+                }
+                None => dropped += 1,
+            }
+        });
+        if dropped > 0 {
+            crate::warn!("memory map: {} usable areas past the first {} are not used", dropped, MAX_MAP_AREAS);
+        }
+        allocator
+    }
+
+    /// Keeps the frames in `start..end` of the memory map from ever being allocated, e.g. because they hold data that
+    /// must survive a reboot or because they are broken. Must be called before the first frame is allocated, since
+    /// frames of the memory map are handed out by their position among the usable frames.
+    pub fn reserve(&mut self, start: PhysAddr, end: PhysAddr) -> Result<(), crate::registry::Full> {
+        assert!(self.zones.iter().all(|zone| zone.next == 0), "frames must be reserved before the first allocation");
+        crate::registry::insert(&mut self.reserved, (start.as_u64(), end.as_u64()))?;
+        Ok(())
+    }
+
+    /// How many more ranges `reserve` can take.
+    pub fn reserve_slots_left(&self) -> usize {
+        self.reserved.iter().filter(|r| r.is_none()).count()
+    }
+
+    /// Whether the frame at `addr` was reserved with `reserve`.
+    pub fn is_reserved(&self, addr: PhysAddr) -> bool {
+        reserved(&self.reserved, addr.as_u64())
+    }
+
+    /// The usable regions of the memory map, as (start, end) physical addresses, including reserved frames.
+    pub fn usable_regions(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.usable[..self.usable_len].iter().copied()
+    }
+
+    /// The number of freed frames that have not been zeroed yet.
+    pub fn dirty_frames(&self) -> usize {
+        self.zones.iter().map(|zone| zone.dirty.len).sum()
+    }
+
+    /// Zeroes up to `budget` dirty frames and returns how many it zeroed. Called from the idle loop, so that allocations
+    /// find clean frames.
+    pub fn scrub(&mut self, budget: usize) -> usize {
+        let mut scrubbed = 0;
+        for zone in self.zones.iter_mut() {
+            while scrubbed < budget {
+                let frame = match zone.dirty.pop() {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                zero_frame(frame);
+                zone.clean.push(frame);
+                scrubbed += 1;
+            }
+        }
+        scrubbed
+    }
+
+    /// Allocates a frame for `owner`, from the zones that suit it.
+    pub fn allocate_frame_for(&mut self, owner: Owner) -> Option<PhysFrame> {
+        let frame = owner.zones().iter().find_map(|&zone| self.allocate_in(zone))?;
+        self.owned[owner.index()] += 1;
+        Some(frame)
+    }
+
+    /// Returns a frame that was allocated for `owner`.
+    ///
+    /// This function is unsafe because the frame must not be used anymore, and must have been allocated for `owner`.
+    pub unsafe fn deallocate_frame_for(&mut self, frame: PhysFrame, owner: Owner) {
+        self.owned[owner.index()] -= 1;
+        let zone = &mut self.zones[Zone::of(frame) as usize];
+        if zero_policy() == ZeroPolicy::OnFree {
+            zero_frame(frame);
+            zone.clean.push(frame);
+        } else {
+            zone.dirty.push(frame);
+        }
+    }
+
+    fn allocate_in(&mut self, zone: Zone) -> Option<PhysFrame> {
+        // Clean frames are ready to use, and their link has already been cleared.
+        if let Some(frame) = self.zones[zone as usize].clean.pop() {
+            return Some(frame);
+        }
+        let frame = match self.zones[zone as usize].dirty.pop() {
+            Some(frame) => frame,
+            None => {
+                let next = self.zones[zone as usize].next;
+                let frame = self.usable_frames().filter(|&frame| Zone::of(frame) == zone).nth(next)?;
+                self.zones[zone as usize].next += 1;
+                frame
+            }
+        };
+        if zero_policy() != ZeroPolicy::Never {
+            zero_frame(frame);
+        }
+        Some(frame)
+    }
+
+    /// Counts the free frames of every zone and the frames held by every owner.
+    pub fn report(&self) -> MemoryReport {
+        let mut total = [0; ZONE_COUNT];
+        for frame in self.usable_frames() {
+            total[Zone::of(frame) as usize] += 1;
+        }
+        let mut free = [0; ZONE_COUNT];
+        for (i, zone) in self.zones.iter().enumerate() {
+            free[i] = total[i] - zone.next + zone.clean.len + zone.dirty.len;
+        }
+        MemoryReport { total, free, owned: self.owned }
+    }
+}
+
+/// Free and total memory by zone, and allocated memory by owner, in frames.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryReport {
+    pub total: [usize; ZONE_COUNT],
+    pub free: [usize; ZONE_COUNT],
+    pub owned: [usize; OWNER_COUNT],
+}
+
+impl MemoryReport {
+    pub fn free_frames(&self) -> usize {
+        self.free.iter().sum()
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total.iter().sum()
+    }
+
+    pub fn owned_by(&self, owner: Owner) -> usize {
+        self.owned[owner.index()]
+    }
+}
+
+/* Sizes are printed in KiB, one line per zone and owner, in the style of /proc/meminfo. */
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kib = |frames: usize| frames * 4;
+        writeln!(f, "{:<16} {:>10} kB", "MemTotal:", kib(self.total_frames()))?;
+        write!(f, "{:<16} {:>10} kB", "MemFree:", kib(self.free_frames()))?;
+        for zone in Zone::ALL.iter() {
+            let i = *zone as usize;
+            write!(f, "\nzone {:<11} {:>10} kB free of {} kB", zone.name(), kib(self.free[i]), kib(self.total[i]))?;
+        }
+        for (name, &frames) in OWNER_NAMES.iter().zip(self.owned.iter()) {
+            write!(f, "\n{:<16} {:>10} kB", name, kib(frames))?;
+        }
+        Ok(())
+    }
+}
+
+/// The current memory usage, or None before the frame allocator has been handed to the heap (see
+/// `allocator::enable_growth`) or while it is in use.
+pub fn free_memory_report() -> Option<MemoryReport> {
+    crate::allocator::with_frame_allocator(|frames| frames.report())
+}
+
+/* The memory map comes from the firmware (E820 on a BIOS), and firmware gets it wrong more often than one would hope.
+If a usable area overlaps one the firmware still uses, the frame allocator hands out memory that something else
+writes to, and the resulting corruption shows up far from its cause. So map_report prints the map once at boot, with
+totals per kind, and warns about areas that overlap or are empty.
+
+The ACPI tables describe memory as well, but reading them needs an ACPI table parser, which the kernel doesn't have
+yet. Until it does, the only ACPI check is that no usable area covers the BIOS area where the RSDP, the root of the
+ACPI tables, is found. */
+
+/// The number of memory areas that map_report checks for overlaps and the frame allocator takes usable memory from.
+/// The bootloader passes at most 64.
+const MAX_MAP_AREAS: usize = 64;
+/// The BIOS read-only memory area, which holds the RSDP.
+const RSDP_AREA: (u64, u64) = (0xe0000, 0x100000);
+
+/// Prints the memory map of the bootloader and checks it for overlapping and empty areas. Returns the number of
+/// problems found.
+pub fn map_report(boot_info: &impl BootInformation) -> usize {
+    let empty = MemoryArea { start: 0, end: 0, kind: MemoryKind::Reserved };
+    let mut areas = [empty; MAX_MAP_AREAS];
+    let mut count = 0;
+    let mut totals = [0u64; MemoryKind::ALL.len()];
+    boot_info.for_each_memory_area(&mut |area| {
+        let size = area.end.saturating_sub(area.start);
+        let (start, end, kind) = (area.start, area.end, area.kind.name());
+        crate::info!("memory map: {:#012x}..{:#012x} {:>10} KiB {}", start, end, size / 1024, kind);
+        totals[area.kind as usize] += size;
+        if count < MAX_MAP_AREAS {
+            areas[count] = area;
+        }
+        count += 1;
+    });
+    if count > MAX_MAP_AREAS {
+        crate::warn!("memory map: only the first {} of {} areas are checked", MAX_MAP_AREAS, count);
+    }
+    for (kind, &total) in MemoryKind::ALL.iter().zip(totals.iter()) {
+        if total > 0 {
+            crate::info!("memory map: {:>10} KiB {}", total / 1024, kind.name());
+        }
+    }
+    crate::info!("memory map: {:>10} KiB in {} areas", totals.iter().sum::<u64>() / 1024, count);
+
+    let areas = &areas[..count.min(MAX_MAP_AREAS)];
+    let mut problems = 0;
+    for area in areas.iter().filter(|area| area.start >= area.end) {
+        crate::warn!("memory map: empty area {:#x}..{:#x} ({})", area.start, area.end, area.kind.name());
+        problems += 1;
+    }
+    for_each_overlap(areas, |a, b| {
+        crate::warn!(
+            "memory map: {:#x}..{:#x} ({}) overlaps {:#x}..{:#x} ({})",
+            a.start,
+            a.end,
+            a.kind.name(),
+            b.start,
+            b.end,
+            b.kind.name()
+        );
+        problems += 1;
+    });
+    let (rsdp_start, rsdp_end) = RSDP_AREA;
+    for area in areas.iter().filter(|area| area.kind == MemoryKind::Usable) {
+        if area.start < rsdp_end && rsdp_start < area.end {
+            crate::warn!("memory map: usable area {:#x}..{:#x} covers the RSDP area", area.start, area.end);
+            problems += 1;
+        }
+    }
+    problems
+}
+
+/* Calls `f` for every pair of areas that share memory. Quadratic, but the map is short. */
+fn for_each_overlap(areas: &[MemoryArea], mut f: impl FnMut(&MemoryArea, &MemoryArea)) {
+    for (i, a) in areas.iter().enumerate() {
+        for b in areas[i + 1..].iter() {
+            if a.start < b.end && b.start < a.end {
+                f(a, b);
+            }
         }
     }
 }
 
 impl BootInfoFrameAllocator {
     /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr());
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        // get usable regions from memory map, as address ranges
+        let addr_ranges = self.usable_regions().map(|(start, end)| start..end);
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // skip the reserved frames
+        let ranges = self.reserved;
+        let frame_addresses = frame_addresses.filter(move |&addr| !reserved(&ranges, addr));
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 }
 
+fn reserved(ranges: &[Option<(u64, u64)>], addr: u64) -> bool {
+    ranges.iter().flatten().any(|&(start, end)| start <= addr && addr < end)
+}
+
 /* Marks the BootInfoFrameAllocator as a frame allocator, allowing it to be used in the map_to function in create_example_mapping.
 Implementing the FrameAllocator is unsafe because the implementer must guarantee that the allocator yields only unused frames. */
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        self.allocate_frame_for(Owner::PageTables)
     }
-}
\ No newline at end of file
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// Returns `frame` to the allocator, zeroing it first if the policy says so.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate_frame_for(frame, Owner::PageTables)
+    }
+}
+
+#[test_case]
+fn test_zones() {
+    let frame = |addr: u64| PhysFrame::containing_address(PhysAddr::new(addr));
+    assert_eq!(Zone::of(frame(0xb8000)), Zone::Dma);
+    assert_eq!(Zone::of(frame(DMA_LIMIT)), Zone::Normal);
+    assert_eq!(Zone::of(frame(NORMAL_LIMIT - 1)), Zone::Normal);
+    assert_eq!(Zone::of(frame(NORMAL_LIMIT)), Zone::High);
+}
+
+#[test_case]
+fn test_map_overlaps() {
+    let area = |start, end| MemoryArea { start, end, kind: MemoryKind::Usable };
+    let areas = [area(0, 0x9f000), area(0x100000, 0x200000), area(0x1ff000, 0x300000), area(0x300000, 0x400000)];
+    let mut overlaps = 0;
+    for_each_overlap(&areas, |a, b| {
+        assert_eq!((a.start, b.start), (0x100000, 0x1ff000));
+        overlaps += 1;
+    });
+    assert_eq!(overlaps, 1);
+}
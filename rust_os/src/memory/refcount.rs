@@ -0,0 +1,126 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{Mapper, Page, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::PageTableFlags;
+use super::{BootInfoFrameAllocator, Owner};
+use crate::registry;
+
+/* Frame reference counts. A frame that backs more than one mapping, like a copy-on-write page after a fork, a page of a
+shared library or the vDSO page, may only be freed when the last of those mappings goes away. So whoever maps a frame
+a second time takes a reference with share, and whoever unmaps a frame drops one with release, which tells it whether
+it held the last reference and has to free the frame.
+
+Almost every frame has exactly one mapping, so the table only has entries for shared frames: a frame without an entry
+has a count of one. The table is a fixed-size array instead of a map on the heap, because the heap grows by allocating
+frames, and frames are released while the frame allocator is locked. */
+
+const MAX_SHARED: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    frame: PhysFrame,
+    count: u32,
+}
+
+static SHARED: Mutex<[Option<Entry>; MAX_SHARED]> = Mutex::new([None; MAX_SHARED]);
+
+
+/// The number of mappings of the allocated frame `frame`.
+pub fn count(frame: PhysFrame) -> u32 {
+    without_interrupts(|| {
+        SHARED.lock().iter().flatten().find(|e| e.frame == frame).map_or(1, |e| e.count)
+    })
+}
+
+/// Takes another reference to `frame`, for a new mapping of it. Returns the new count.
+pub fn share(frame: PhysFrame) -> Result<u32, registry::Full> {
+    without_interrupts(|| {
+        let mut shared = SHARED.lock();
+        if let Some(entry) = shared.iter_mut().flatten().find(|e| e.frame == frame) {
+            entry.count += 1;
+            return Ok(entry.count);
+        }
+        registry::insert(&mut *shared, Entry { frame, count: 2 })?;
+        Ok(2)
+    })
+}
+
+/// Drops a reference to `frame`. Returns true if it was the last one, in which case the caller must free the frame.
+pub fn release(frame: PhysFrame) -> bool {
+    without_interrupts(|| {
+        let mut shared = SHARED.lock();
+        let slot = match shared.iter_mut().find(|e| matches!(e, Some(e) if e.frame == frame)) {
+            Some(slot) => slot,
+            None => return true,
+        };
+        if let Some(entry) = slot {
+            entry.count -= 1;
+            if entry.count == 1 {
+                *slot = None;
+            }
+        }
+        false
+    })
+}
+
+#[derive(Debug)]
+pub enum MapSharedError {
+    Share(registry::Full),
+    Map(MapToError<Size4KiB>),
+}
+
+/// Maps `page` to `frame`, which is already mapped elsewhere, and takes a reference to it.
+pub fn map_shared(
+    mapper: &mut impl Mapper<Size4KiB>,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frames: &mut BootInfoFrameAllocator,
+) -> Result<(), MapSharedError> {
+    share(frame).map_err(MapSharedError::Share)?;
+    // Safety: the frame stays allocated as long as this mapping holds its reference.
+    match unsafe { mapper.map_to(page, frame, flags, frames) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(error) => {
+            release(frame);
+            Err(MapSharedError::Map(error))
+        }
+    }
+}
+
+/// Unmaps `page` and drops its reference to the frame behind it, which is freed if that was the last reference. The
+/// frame must have been allocated for `owner`. Returns whether the frame was freed.
+pub fn unmap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    page: Page,
+    frames: &mut BootInfoFrameAllocator,
+    owner: Owner,
+) -> Result<bool, UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    if !release(frame) {
+        return Ok(false);
+    }
+    // Safety: the page was the frame's last mapping.
+    unsafe { frames.deallocate_frame_for(frame, owner) };
+    Ok(true)
+}
+
+#[test_case]
+fn test_share_and_release() {
+    use x86_64::PhysAddr;
+
+    // The frame is never accessed, only counted.
+    let frame = PhysFrame::containing_address(PhysAddr::new(0x7fff_f000));
+    assert_eq!(count(frame), 1);
+    assert_eq!(share(frame), Ok(2));
+    assert_eq!(share(frame), Ok(3));
+    assert!(!release(frame));
+    assert!(!release(frame));
+    assert_eq!(count(frame), 1);
+    assert!(release(frame));
+}
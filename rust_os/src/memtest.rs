@@ -0,0 +1,179 @@
+use x86_64::PhysAddr;
+use crate::memory::{self, BootInfoFrameAllocator};
+
+/* A boot-time memory test for old hardware, where a bad RAM module shows up as random crashes long after the memory was
+handed out. With `memtest=on` on the kernel command line (or the `memtest` cargo feature), every usable frame of the
+memory map is tested before the frame allocator hands out the first one, and frames that fail are reserved, so they are
+never used.
+
+Two tests run, in the tradition of memtest86:
+
+  - Walking ones writes each of the 64 single-bit patterns to the first word of every frame and reads it back, which
+    finds data lines that are stuck or shorted together.
+  - Address in address first writes every word's own address to it, across all of memory, and only then reads
+    everything back, and then repeats that with the complement. An address line fault makes two addresses share the
+    same cell, so the first of them reads back the other's address.
+
+Frames that are already reserved, like the persistent panic log (see pstore.rs), are skipped so their contents
+survive. Testing takes a few seconds per GiB. */
+
+const WORDS_PER_FRAME: usize = 4096 / 8;
+
+/// Whether the memory test was requested through the `memtest` feature or the kernel command line.
+pub fn requested(command_line: Option<&str>) -> bool {
+    cfg!(feature = "memtest")
+        || command_line.and_then(|cmdline| crate::bootinfo::param(cmdline, "memtest")) == Some("on")
+}
+
+/// Tests every usable frame and reserves the ones that fail. Returns the number of bad frames.
+pub fn run(frames: &mut BootInfoFrameAllocator) -> usize {
+    let tested = |f: &mut dyn FnMut(PhysAddr, &mut [u64])| {
+        for (start, end) in frames.usable_regions() {
+            for addr in (start..end).step_by(4096) {
+                let addr = PhysAddr::new(addr);
+                if !frames.is_reserved(addr) {
+                    f(addr, frame_words(addr));
+                }
+            }
+        }
+    };
+
+    let mut bad = BadFrames::new(frames.reserve_slots_left());
+    tested(&mut |addr, words| {
+        if !walking_ones(&mut words[0]) {
+            bad.add(addr);
+        }
+    });
+    for &invert in [false, true].iter() {
+        tested(&mut |addr, words| write_addresses(words, addr.as_u64(), invert));
+        tested(&mut |addr, words| {
+            if !check_addresses(words, addr.as_u64(), invert) {
+                bad.add(addr);
+            }
+        });
+    }
+
+    let total: u64 = frames.usable_regions().map(|(start, end)| (end - start) / 4096).sum();
+    if bad.count == 0 {
+        crate::info!("memtest: {} frames passed", total);
+        return 0;
+    }
+    crate::error!("memtest: {} of {} frames failed", bad.count, total);
+    for &(start, end) in bad.ranges[..bad.len].iter() {
+        crate::error!("memtest: reserving bad memory {:#x}..{:#x}", start, end);
+        // BadFrames uses no more ranges than there are slots left.
+        frames.reserve(PhysAddr::new(start), PhysAddr::new(end)).expect("memtest: no reserve slot left");
+    }
+    if bad.unreserved() > 0 {
+        crate::error!("memtest: no reserve slots left, {} bad frames stay in use", bad.unreserved());
+    }
+    bad.count
+}
+
+fn frame_words(addr: PhysAddr) -> &'static mut [u64] {
+    let virt = memory::phys_to_virt(addr);
+    // Safety: the frame is usable and not handed out yet, and all of physical memory is mapped.
+    unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u64>(), WORDS_PER_FRAME) }
+}
+
+fn walking_ones(word: &mut u64) -> bool {
+    (0..64).all(|bit| {
+        let pattern = 1u64 << bit;
+        // Safety: `word` is a valid reference.
+        unsafe {
+            core::ptr::write_volatile(word, pattern);
+            core::ptr::read_volatile(word) == pattern
+        }
+    })
+}
+
+fn address_pattern(address: u64, invert: bool) -> u64 {
+    if invert {
+        !address
+    } else {
+        address
+    }
+}
+
+fn write_addresses(words: &mut [u64], base: u64, invert: bool) {
+    for (i, word) in words.iter_mut().enumerate() {
+        // Safety: `word` is a valid reference.
+        unsafe { core::ptr::write_volatile(word, address_pattern(base + i as u64 * 8, invert)) };
+    }
+}
+
+fn check_addresses(words: &[u64], base: u64, invert: bool) -> bool {
+    words.iter().enumerate().all(|(i, word)| {
+        // Safety: `word` is a valid reference.
+        unsafe { core::ptr::read_volatile(word) == address_pattern(base + i as u64 * 8, invert) }
+    })
+}
+
+/* Bad frames, merged into ranges of adjacent frames, since bad memory usually fails in blocks. There are only as many
+ranges as the frame allocator has reserve slots left. Once they are used up, a bad frame extends the closest range
+instead, which gives up the good frames in between rather than using a bad one. */
+struct BadFrames {
+    ranges: [(u64, u64); memory::MAX_RESERVED],
+    len: usize,
+    limit: usize,
+    count: usize,
+}
+
+impl BadFrames {
+    fn new(slots: usize) -> BadFrames {
+        BadFrames { ranges: [(0, 0); memory::MAX_RESERVED], len: 0, limit: slots.min(memory::MAX_RESERVED), count: 0 }
+    }
+
+    fn add(&mut self, addr: PhysAddr) {
+        let (start, end) = (addr.as_u64(), addr.as_u64() + 4096);
+        // The frame may have failed an earlier test already.
+        if self.ranges[..self.len].iter().any(|r| r.0 <= start && start < r.1) {
+            return;
+        }
+        self.count += 1;
+        if let Some(range) = self.ranges[..self.len].iter_mut().find(|r| r.1 == start) {
+            range.1 = end;
+            return;
+        }
+        if self.len < self.limit {
+            self.ranges[self.len] = (start, end);
+            self.len += 1;
+            return;
+        }
+        let distance = |r: &&mut (u64, u64)| if start < r.0 { r.0 - end } else { start - r.1 };
+        if let Some(range) = self.ranges[..self.len].iter_mut().min_by_key(distance) {
+            *range = (range.0.min(start), range.1.max(end));
+        }
+    }
+
+    /* Bad frames that no range covers, because there were no reserve slots at all. */
+    fn unreserved(&self) -> usize {
+        if self.len == 0 {
+            self.count
+        } else {
+            0
+        }
+    }
+}
+
+#[test_case]
+fn test_patterns() {
+    let mut words = [0u64; 8];
+    assert!(walking_ones(&mut words[0]));
+    write_addresses(&mut words, 0x1000, true);
+    assert!(check_addresses(&words, 0x1000, true));
+    // A cell that another address wrote to, as with an address line fault.
+    words[3] = !0x1000;
+    assert!(!check_addresses(&words, 0x1000, true));
+
+    let mut bad = BadFrames::new(2);
+    bad.add(PhysAddr::new(0x5000));
+    bad.add(PhysAddr::new(0x6000));
+    bad.add(PhysAddr::new(0x5000));
+    bad.add(PhysAddr::new(0x9000));
+    assert_eq!((bad.count, &bad.ranges[..bad.len]), (3, &[(0x5000, 0x7000), (0x9000, 0xa000)][..]));
+    // Without a free slot, the closest range grows to cover the frame.
+    bad.add(PhysAddr::new(0xc000));
+    bad.add(PhysAddr::new(0x2000));
+    assert_eq!((bad.count, &bad.ranges[..bad.len]), (5, &[(0x2000, 0x7000), (0x9000, 0xd000)][..]));
+}
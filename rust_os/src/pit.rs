@@ -0,0 +1,87 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use lazy_static::lazy_static;
+use crate::portio::{self, PortRange};
+
+/* The Programmable Interval Timer (Intel 8253/8254) has three channels driven by a 1.193182 MHz oscillator. Channel 0
+is wired to IRQ 0, which is our timer interrupt; channel 2 drives the PC speaker. Each channel counts down from a
+16-bit reload value (the divisor) and fires when it reaches zero, so the output frequency is BASE_FREQUENCY / divisor.
+
+Without programming, channel 0 runs with the maximum divisor of 65536, which gives roughly 18.2 interrupts per second. */
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+// Register offsets from the first PIT port.
+const CHANNEL_0_DATA: u16 = 0;
+const CHANNEL_2_DATA: u16 = 2;
+const COMMAND: u16 = 3;
+
+lazy_static! {
+    static ref PORTS: PortRange = portio::claim_or_panic(0x40..=0x43, "pit");
+}
+
+// The divisor channel 0 was last programmed with; 65536 until then.
+static DIVISOR: AtomicU32 = AtomicU32::new(1 << 16);
+
+/// Converts a frequency to the closest divisor that fits the 16-bit reload register.
+pub fn divisor_for(hz: u32) -> u16 {
+    let divisor = BASE_FREQUENCY / hz.max(1);
+    divisor.clamp(1, u32::from(u16::MAX)) as u16
+}
+
+/// Programs channel 0 to fire the timer interrupt `hz` times per second.
+pub fn set_frequency(hz: u32) {
+    let divisor = divisor_for(hz);
+    let mut command = PORTS.write_only::<u8>(COMMAND);
+    let mut data = PORTS.port::<u8>(CHANNEL_0_DATA);
+    unsafe {
+        // Channel 0, access mode lobyte/hibyte, mode 3 (square wave generator), binary counting.
+        command.write(0b0011_0110);
+        data.write((divisor & 0xff) as u8);
+        data.write((divisor >> 8) as u8);
+    }
+    DIVISOR.store(u32::from(divisor), Ordering::SeqCst);
+}
+
+/* Channel 0 keeps counting when IRQ 0 is masked, e.g. after the local APIC timer has taken over the tick, so its count
+can still be used to time a short window. In mode 3 the count goes down by two per oscillator cycle, from the divisor
+(rounded down to even) to zero, twice per period. */
+fn channel_0_count() -> u16 {
+    let mut command = PORTS.write_only::<u8>(COMMAND);
+    let mut data = PORTS.port::<u8>(CHANNEL_0_DATA);
+    unsafe {
+        // Latch channel 0's count, so that the two bytes belong to the same value.
+        command.write(0b0000_0000);
+        let low = data.read();
+        let high = data.read();
+        u16::from_le_bytes([low, high])
+    }
+}
+
+/// Busy waits until `cycles` cycles of the PIT's oscillator have passed, by polling channel 0's count. Channel 0 must
+/// have been programmed by `set_frequency`, and nothing may keep the CPU away for half a period of it or longer, or
+/// the wait takes longer than asked.
+pub fn spin_cycles(cycles: u64) {
+    let reload = u64::from(DIVISOR.load(Ordering::SeqCst) & !1);
+    // Counted in steps of the count, two per cycle, so that no half cycles are lost to rounding.
+    let mut elapsed = 0;
+    let mut last = u64::from(channel_0_count());
+    while elapsed < cycles * 2 {
+        let count = u64::from(channel_0_count());
+        // A higher count than before means the counter was reloaded in between.
+        elapsed += if count <= last { last - count } else { last + reload - count };
+        last = count;
+        core::hint::spin_loop();
+    }
+}
+
+/// Programs channel 2, which drives the PC speaker, to output a square wave of `hz`.
+pub fn set_channel_2_frequency(hz: u32) {
+    let divisor = divisor_for(hz);
+    let mut command = PORTS.write_only::<u8>(COMMAND);
+    let mut data = PORTS.port::<u8>(CHANNEL_2_DATA);
+    unsafe {
+        // Channel 2, access mode lobyte/hibyte, mode 3 (square wave generator), binary counting.
+        command.write(0b1011_0110);
+        data.write((divisor & 0xff) as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
@@ -0,0 +1,132 @@
+use core::fmt;
+use core::ops::RangeInclusive;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::{Port, PortRead, PortReadOnly, PortWrite, PortWriteOnly};
+use crate::registry;
+
+/* I/O ports are a global resource, and two drivers that both think they own a port will confuse the device and each
+other. So instead of creating ports from bare numbers wherever they are needed, a driver claims the range of ports its
+device decodes, and then creates ports only through its claim, by offset into the range:
+
+    let ps2 = PortRange::claim(0x60..=0x60, "ps2")?;
+    let mut data = ps2.port::<u8>(0);
+
+A claim that overlaps an existing one fails with the name of the current owner, so conflicts show up as soon as the
+second driver initializes instead of as misbehaving hardware. Dropping a PortRange releases the claim. The registry
+is a fixed-size table, so ports can be claimed before the heap exists. */
+
+const MAX_CLAIMS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct Claim {
+    start: u16,
+    end: u16,
+    owner: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortError {
+    /// Some of the ports are already claimed by the given owner.
+    Conflict(&'static str),
+    Full(registry::Full),
+}
+
+impl fmt::Display for PortError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortError::Conflict(owner) => write!(f, "ports already claimed by {}", owner),
+            PortError::Full(full) => write!(f, "too many port claims, {}", full),
+        }
+    }
+}
+
+static CLAIMS: Mutex<[Option<Claim>; MAX_CLAIMS]> = Mutex::new([None; MAX_CLAIMS]);
+
+/// Exclusive ownership of a range of I/O ports.
+#[derive(Debug)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    /// Claims the ports in `range` for `owner`.
+    pub fn claim(range: RangeInclusive<u16>, owner: &'static str) -> Result<PortRange, PortError> {
+        let (start, end) = (*range.start(), *range.end());
+        assert!(start <= end, "empty port range");
+        without_interrupts(|| {
+            let mut claims = CLAIMS.lock();
+            if let Some(claim) = claims.iter().flatten().find(|c| c.start <= end && start <= c.end) {
+                return Err(PortError::Conflict(claim.owner));
+            }
+            registry::insert(&mut *claims, Claim { start, end, owner }).map_err(PortError::Full)?;
+            Ok(PortRange { start, end })
+        })
+    }
+
+    /// The first port of the range.
+    pub fn base(&self) -> u16 {
+        self.start
+    }
+
+    fn address(&self, offset: u16) -> u16 {
+        assert!(offset <= self.end - self.start, "port offset {:#x} outside of {:#x}..={:#x}", offset, self.start, self.end);
+        self.start + offset
+    }
+
+    /// The port at `offset` from the start of the range.
+    pub fn port<T: PortRead + PortWrite>(&self, offset: u16) -> Port<T> {
+        Port::new(self.address(offset))
+    }
+
+    /// The port at `offset`, for registers that can only be read.
+    pub fn read_only<T: PortRead>(&self, offset: u16) -> PortReadOnly<T> {
+        PortReadOnly::new(self.address(offset))
+    }
+
+    /// The port at `offset`, for registers that can only be written.
+    pub fn write_only<T: PortWrite>(&self, offset: u16) -> PortWriteOnly<T> {
+        PortWriteOnly::new(self.address(offset))
+    }
+}
+
+impl Drop for PortRange {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            for slot in CLAIMS.lock().iter_mut() {
+                if matches!(slot, Some(c) if c.start == self.start && c.end == self.end) {
+                    *slot = None;
+                }
+            }
+        });
+    }
+}
+
+/// Claims `range` for a driver that can't work without it, and panics with both owners' names on a conflict.
+pub fn claim_or_panic(range: RangeInclusive<u16>, owner: &'static str) -> PortRange {
+    let (start, end) = (*range.start(), *range.end());
+    PortRange::claim(range, owner)
+        .unwrap_or_else(|error| panic!("{} can't claim ports {:#x}..={:#x}: {}", owner, start, end, error))
+}
+
+/// Prints all claimed port ranges.
+pub fn dump() {
+    let mut claims = without_interrupts(|| *CLAIMS.lock());
+    claims.sort_unstable_by_key(|c| c.map_or(u16::MAX, |c| c.start));
+    for claim in claims.iter().flatten() {
+        crate::println!("ports {:#06x}..={:#06x} {}", claim.start, claim.end, claim.owner);
+    }
+}
+
+#[test_case]
+fn test_conflicting_claims() {
+    // 0xe0..=0xef are not used by any device the kernel knows about.
+    let first = PortRange::claim(0xe0..=0xe7, "test a").unwrap();
+    assert_eq!(PortRange::claim(0xe4..=0xeb, "test b").unwrap_err(), PortError::Conflict("test a"));
+    let second = PortRange::claim(0xe8..=0xef, "test b").unwrap();
+    drop(first);
+    let third = PortRange::claim(0xe0..=0xe7, "test c").unwrap();
+    assert_eq!(third.base(), 0xe0);
+    drop((second, third));
+}
@@ -0,0 +1,54 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::portio::PortRange;
+
+/* Powering off and rebooting. Both first run the shutdown hooks (see shutdown.rs), so that no subsystem loses state.
+
+Without an ACPI interpreter the kernel can't look up the PM1a control port in the FADT, so shutdown tries the ports the
+common virtual machines use for it: QEMU's q35 and newer i440fx machines at 0x604, older QEMU and Bochs at 0xb004, and
+VirtualBox at 0x4004. Writing SLP_EN with sleep type 0 to any of them enters S5, soft off. On real hardware none of
+these works, and the CPU halts instead.
+
+Reboot pulses the reset line through the PS/2 controller, and if the machine is still running after that, triple faults
+by loading an empty IDT. */
+
+const SLEEP_ENABLE: u16 = 1 << 13;
+const PM1A_CONTROL_PORTS: [u16; 3] = [0x604, 0xb004, 0x4004];
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/* Runs the shutdown hooks, once, no matter how often shutdown or reboot is called. */
+fn prepare(action: &str) {
+    x86_64::instructions::interrupts::disable();
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    crate::info!("power: {}", action);
+    crate::shutdown::run_hooks();
+}
+
+/// Runs the shutdown hooks and powers the machine off.
+pub fn shutdown() -> ! {
+    prepare("shutting down");
+    for &port in PM1A_CONTROL_PORTS.iter() {
+        // Skip ports a driver has claimed for something else.
+        if let Ok(range) = PortRange::claim(port..=port + 1, "acpi pm1a") {
+            unsafe { range.write_only::<u16>(0).write(SLEEP_ENABLE) };
+        }
+    }
+    crate::emergency_serial_println!("power: shutdown failed, halting");
+    crate::hlt_loop();
+}
+
+/// Runs the shutdown hooks and resets the machine.
+pub fn reboot() -> ! {
+    prepare("rebooting");
+    crate::ps2::pulse_reset();
+    /* A triple fault always resets the CPU: with an empty IDT, the breakpoint can't be delivered, neither can the
+    resulting double fault. */
+    unsafe {
+        let idt = x86_64::structures::DescriptorTablePointer { limit: 0, base: x86_64::VirtAddr::new(0) };
+        x86_64::instructions::tables::lidt(&idt);
+    }
+    x86_64::instructions::interrupts::int3();
+    crate::hlt_loop();
+}
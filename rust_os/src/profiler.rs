@@ -0,0 +1,100 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use crate::println;
+use crate::symbols;
+
+/* A sampling profiler. While it is enabled, the timer interrupt handler passes the instruction pointer of the code it
+interrupted to `record`. Over many ticks, the functions that show up most often are the ones the CPU spends the most
+time in.
+
+The samples are kept in a fixed-size ring buffer of atomics so that recording never allocates or takes a lock, which
+matters because it runs inside an interrupt handler that may have interrupted the allocator or the VGA writer. Once the
+buffer is full, the oldest samples are overwritten. */
+
+const SAMPLE_CAPACITY: usize = 4096;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SAMPLE: AtomicU64 = AtomicU64::new(0);
+static SAMPLES: [AtomicU64; SAMPLE_CAPACITY] = [EMPTY_SAMPLE; SAMPLE_CAPACITY];
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Throws away all recorded samples.
+pub fn reset() {
+    NEXT.store(0, Ordering::SeqCst);
+}
+
+/// Records one sample. Called from the timer interrupt handler with the interrupted instruction pointer.
+pub fn record(rip: u64) {
+    if !is_enabled() {
+        return;
+    }
+    let index = NEXT.fetch_add(1, Ordering::Relaxed);
+    SAMPLES[index % SAMPLE_CAPACITY].store(rip, Ordering::Relaxed);
+}
+
+/// Number of samples currently held in the ring buffer.
+pub fn sample_count() -> usize {
+    NEXT.load(Ordering::Relaxed).min(SAMPLE_CAPACITY)
+}
+
+/* The report groups samples by the function they fall into. Without an embedded symbol table, samples are grouped by
+their exact address instead. The number of distinct groups is bounded so that the report works without the heap; samples
+that don't fit are counted as "other". */
+const MAX_BUCKETS: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    key: u64,
+    name: Option<&'static str>,
+    count: usize,
+}
+
+/// Prints the `n` functions with the most samples, most frequent first.
+pub fn report(n: usize) {
+    let mut buckets = [Bucket { key: 0, name: None, count: 0 }; MAX_BUCKETS];
+    let mut used = 0;
+    let mut other = 0;
+
+    let total = sample_count();
+    for sample in SAMPLES.iter().take(total) {
+        let rip = sample.load(Ordering::Relaxed);
+        let (key, name) = match symbols::resolve(rip) {
+            Some(symbol) => (symbol.address, Some(symbol.name)),
+            None => (rip, None),
+        };
+        match buckets[..used].iter_mut().find(|bucket| bucket.key == key) {
+            Some(bucket) => bucket.count += 1,
+            None if used < MAX_BUCKETS => {
+                buckets[used] = Bucket { key, name, count: 1 };
+                used += 1;
+            }
+            None => other += 1,
+        }
+    }
+
+    buckets[..used].sort_unstable_by_key(|bucket| core::cmp::Reverse(bucket.count));
+
+    println!("profile: {} samples", total);
+    for bucket in buckets[..used].iter().take(n) {
+        let percent = bucket.count * 100 / total;
+        match bucket.name {
+            Some(name) => println!("{:>6} {:>3}%  {}", bucket.count, percent, name),
+            None => println!("{:>6} {:>3}%  {:#x}", bucket.count, percent, bucket.key),
+        }
+    }
+    if other > 0 {
+        println!("{:>6}       (other)", other);
+    }
+}
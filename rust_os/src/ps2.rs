@@ -0,0 +1,298 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+use lazy_static::lazy_static;
+use crate::portio::{self, PortRange};
+
+/* The 8042 PS/2 controller sits between the CPU and the keyboard. The CPU talks to the controller through two ports:
+0x64 is the status register when read and the command register when written, and 0x60 is the data port, used both for
+controller command arguments/responses and for bytes to and from the keyboard itself.
+
+The firmware leaves the controller in whatever state it likes. Most BIOSes enable "translation", where the controller
+converts the scancode set 2 bytes that every keyboard sends by default into the older set 1, but some don't, and USB
+legacy emulation is known to get this wrong. Instead of guessing, init() resets the controller and the keyboard to a
+known state: translation off and scancode set 2. If the keyboard refuses to switch sets, translation is turned back on
+and the keyboard handler decodes set 1 instead.
+
+All of this is done by polling, with IRQ1 disabled in the controller's configuration byte, so it works before
+interrupts are enabled. A keyboard reset can poll for a long time, so when the keyboard interrupt handler sees the
+keyboard lose track of its state, it only requests a reinitialization, which the idle loop carries out. */
+
+const DATA: u16 = 0x60;
+const STATUS_COMMAND: u16 = 0x64;
+
+// Status register bits.
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+
+// Controller commands.
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_PORT_2: u8 = 0xa7;
+const SELF_TEST: u8 = 0xaa;
+const TEST_PORT_1: u8 = 0xab;
+const DISABLE_PORT_1: u8 = 0xad;
+const ENABLE_PORT_1: u8 = 0xae;
+const PULSE_RESET: u8 = 0xfe;
+
+// Configuration byte bits.
+const CONFIG_PORT_1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT_2_IRQ: u8 = 1 << 1;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// Keyboard commands and replies.
+const KEYBOARD_SCANCODE_SET: u8 = 0xf0;
+const KEYBOARD_ENABLE_SCANNING: u8 = 0xf4;
+const KEYBOARD_RESET: u8 = 0xff;
+const KEYBOARD_ACK: u8 = 0xfa;
+const KEYBOARD_RESEND: u8 = 0xfe;
+const KEYBOARD_SELF_TEST_PASSED: u8 = 0xaa;
+
+/* Polling limits. The controller answers within microseconds, but a keyboard reset can take several hundred
+milliseconds on real hardware, and we have no timer to measure that with yet. */
+const POLL_LIMIT: usize = 1_000_000;
+const RESEND_LIMIT: usize = 3;
+
+/// The scancode set the keyboard handler has to decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScancodeSet {
+    /// Set 1, either sent by the keyboard or produced by the controller's translation.
+    Set1 = 1,
+    /// Set 2, untranslated.
+    Set2 = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller or the keyboard did not answer in time.
+    Timeout,
+    /// The controller self-test returned the given byte instead of 0x55.
+    ControllerSelfTest(u8),
+    /// The first port's interface test returned the given error code.
+    PortTest(u8),
+    /// The keyboard did not acknowledge a command, answering with the given byte.
+    NoAck(u8),
+    /// The keyboard's self-test after a reset returned the given byte instead of 0xaa.
+    KeyboardSelfTest(u8),
+}
+
+/* The controller decodes 0x60 and 0x64 only. Port 0x61 in between belongs to the PC speaker and 0x62/0x63 to nothing. */
+lazy_static! {
+    static ref DATA_PORT: PortRange = portio::claim_or_panic(DATA..=DATA, "ps2");
+    static ref STATUS_COMMAND_PORT: PortRange = portio::claim_or_panic(STATUS_COMMAND..=STATUS_COMMAND, "ps2");
+}
+
+/* Until init() has run, assume the firmware's usual setup of a translated set 1, which is what the keyboard handler
+decoded before the controller was initialized explicitly. */
+static ACTIVE_SET: AtomicU8 = AtomicU8::new(ScancodeSet::Set1 as u8);
+static REINITS: AtomicUsize = AtomicUsize::new(0);
+static REINIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The scancode set the keyboard currently sends, as seen on the data port.
+pub fn scancode_set() -> ScancodeSet {
+    match ACTIVE_SET.load(Ordering::Relaxed) {
+        2 => ScancodeSet::Set2,
+        _ => ScancodeSet::Set1,
+    }
+}
+
+/// How often the keyboard was reinitialized after an error since boot.
+pub fn reinit_count() -> usize {
+    REINITS.load(Ordering::Relaxed)
+}
+
+/// Initializes the controller and the keyboard and returns the scancode set the keyboard sends.
+pub fn init() -> Result<ScancodeSet, Ps2Error> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut controller = Controller::new();
+        let set = controller.init()?;
+        ACTIVE_SET.store(set as u8, Ordering::SeqCst);
+        Ok(set)
+    })
+}
+
+/// Asks for the controller and the keyboard to be reinitialized, because the keyboard reported an error or broke the
+/// protocol. Called by the keyboard interrupt handler.
+pub fn request_reinit() {
+    REINIT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Carries out a reinitialization that `request_reinit` asked for, if any. Called by the idle loop.
+pub fn reinit_if_requested() {
+    if REINIT_REQUESTED.swap(false, Ordering::SeqCst) {
+        let _ = reinit();
+    }
+}
+
+/// Reinitializes the controller and the keyboard.
+pub fn reinit() -> Result<ScancodeSet, Ps2Error> {
+    REINITS.fetch_add(1, Ordering::Relaxed);
+    let result = init();
+    match result {
+        Ok(set) => crate::warn!("keyboard reinitialized, using scancode set {}", set as u8),
+        Err(error) => crate::error!("keyboard reinitialization failed: {:?}", error),
+    }
+    result
+}
+
+/// Reads the byte the keyboard sent. Called by the keyboard interrupt handler.
+pub fn read_data() -> u8 {
+    unsafe { DATA_PORT.read_only::<u8>(0).read() }
+}
+
+/// Reads the controller's status register.
+pub fn read_status() -> u8 {
+    unsafe { STATUS_COMMAND_PORT.read_only::<u8>(0).read() }
+}
+
+/// Pulses the CPU reset line, which the controller drives for historical reasons. Used by power::reboot.
+pub fn pulse_reset() {
+    let _ = Controller::new().command(PULSE_RESET);
+}
+
+/// Whether a byte received from the keyboard signals an error rather than a scancode. Both 0x00 and 0xff mean key
+/// detection error or internal buffer overrun, in every scancode set.
+pub fn is_error_byte(byte: u8) -> bool {
+    byte == 0x00 || byte == 0xff
+}
+
+struct Controller {
+    data: Port<u8>,
+    status_command: Port<u8>,
+}
+
+impl Controller {
+    fn new() -> Controller {
+        Controller { data: DATA_PORT.port(0), status_command: STATUS_COMMAND_PORT.port(0) }
+    }
+
+    fn init(&mut self) -> Result<ScancodeSet, Ps2Error> {
+        // Disable both ports so that the keyboard can't send anything while we reconfigure the controller, and throw
+        // away anything that is still waiting in the output buffer.
+        self.command(DISABLE_PORT_1)?;
+        self.command(DISABLE_PORT_2)?;
+        self.flush();
+
+        // Interrupts and translation stay off until the keyboard has been set up.
+        let config = self.command_with_reply(READ_CONFIG)?;
+        let config = config & !(CONFIG_PORT_1_IRQ | CONFIG_PORT_2_IRQ | CONFIG_TRANSLATION);
+        self.write_config(config)?;
+
+        match self.command_with_reply(SELF_TEST)? {
+            0x55 => {}
+            reply => return Err(Ps2Error::ControllerSelfTest(reply)),
+        }
+        // Some controllers reset themselves during the self-test.
+        self.write_config(config)?;
+
+        match self.command_with_reply(TEST_PORT_1)? {
+            0x00 => {}
+            reply => return Err(Ps2Error::PortTest(reply)),
+        }
+        self.command(ENABLE_PORT_1)?;
+
+        self.keyboard_command(KEYBOARD_RESET)?;
+        match self.read()? {
+            KEYBOARD_SELF_TEST_PASSED => {}
+            reply => return Err(Ps2Error::KeyboardSelfTest(reply)),
+        }
+
+        let (set, config) = match self.select_set_2() {
+            Ok(()) => (ScancodeSet::Set2, config | CONFIG_PORT_1_IRQ),
+            // The keyboard still sends its default set 2, so let the controller translate it to set 1.
+            Err(_) => (ScancodeSet::Set1, config | CONFIG_PORT_1_IRQ | CONFIG_TRANSLATION),
+        };
+
+        self.keyboard_command(KEYBOARD_ENABLE_SCANNING)?;
+        self.write_config(config)?;
+        Ok(set)
+    }
+
+    /* Selects scancode set 2 and reads it back, since some keyboards acknowledge the command but ignore it. */
+    fn select_set_2(&mut self) -> Result<(), Ps2Error> {
+        self.keyboard_command(KEYBOARD_SCANCODE_SET)?;
+        self.keyboard_command(2)?;
+        self.keyboard_command(KEYBOARD_SCANCODE_SET)?;
+        self.keyboard_command(0)?;
+        match self.read()? {
+            2 => Ok(()),
+            reply => Err(Ps2Error::NoAck(reply)),
+        }
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.command(WRITE_CONFIG)?;
+        self.write(config)
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.status_command.write(command) };
+        Ok(())
+    }
+
+    fn command_with_reply(&mut self, command: u8) -> Result<u8, Ps2Error> {
+        self.command(command)?;
+        self.read()
+    }
+
+    /* Bytes written to the data port without a preceding controller command go to the keyboard. The keyboard
+    acknowledges every byte with 0xfa, or asks for it to be sent again with 0xfe. */
+    fn keyboard_command(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..RESEND_LIMIT {
+            self.write(byte)?;
+            match self.read()? {
+                KEYBOARD_ACK => return Ok(()),
+                KEYBOARD_RESEND => continue,
+                reply => return Err(Ps2Error::NoAck(reply)),
+            }
+        }
+        Err(Ps2Error::NoAck(KEYBOARD_RESEND))
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<u8, Ps2Error> {
+        for _ in 0..POLL_LIMIT {
+            if self.status() & OUTPUT_FULL != 0 {
+                return Ok(unsafe { self.data.read() });
+            }
+            core::hint::spin_loop();
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn wait_input_empty(&mut self) -> Result<(), Ps2Error> {
+        for _ in 0..POLL_LIMIT {
+            if self.status() & INPUT_FULL == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn flush(&mut self) {
+        // Bounded, because a missing controller reads as 0xff, which has every status bit set.
+        for _ in 0..POLL_LIMIT {
+            if self.status() & OUTPUT_FULL == 0 {
+                return;
+            }
+            unsafe { self.data.read() };
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.status_command.read() }
+    }
+}
+
+#[test_case]
+fn test_init_selects_a_scancode_set() {
+    let set = init().expect("keyboard initialization failed");
+    assert_eq!(scancode_set(), set);
+}
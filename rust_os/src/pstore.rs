@@ -0,0 +1,120 @@
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+use crate::memory::{self, BootInfoFrameAllocator};
+
+/* A panic log that survives a warm reboot, in the spirit of Linux's pstore. On real hardware without a serial cable, a
+panic is only visible on the screen, and a reboot erases it. RAM, on the other hand, keeps its contents across a warm
+reboot: the firmware doesn't clear it, and as long as the kernel never hands the memory out, nothing else writes to it.
+
+So init reserves the last PSTORE_SIZE bytes of the highest usable region of the memory map, which is the same region on
+every boot of the same machine. On a panic, the panic message and the tail of the trace ring are written there,
+followed by a header with a magic number, the length and a checksum. On the next boot, init finds a valid header,
+logs the stored text and clears the header, so every crash is reported exactly once. A cold boot leaves random contents
+in the region, which the checksum rejects. */
+
+const PSTORE_SIZE: u64 = 16 * 1024;
+const MAGIC: u64 = u64::from_le_bytes(*b"PSTORE01");
+const HEADER_SIZE: usize = 16;
+const DATA_SIZE: usize = PSTORE_SIZE as usize - HEADER_SIZE;
+/// Trace records per CPU that are saved with a panic.
+const TRACE_RECORDS: usize = 32;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    len: u32,
+    checksum: u32,
+}
+
+// The physical address of the region, 0 until init has reserved it.
+static REGION: AtomicU64 = AtomicU64::new(0);
+
+/// Reserves the persistent region and logs the panic that a previous boot left there, if any.
+pub fn init(frames: &mut BootInfoFrameAllocator) {
+    let end = match frames.usable_regions().map(|(_, end)| end & !0xfff).max() {
+        Some(end) => end,
+        None => return,
+    };
+    let start = end - PSTORE_SIZE;
+    if frames.reserve(PhysAddr::new(start), PhysAddr::new(end)).is_err() {
+        crate::warn!("pstore: no room to reserve {:#x}..{:#x}", start, end);
+        return;
+    }
+    REGION.store(start, Ordering::SeqCst);
+
+    let (header, data) = region();
+    let len = header.len as usize;
+    if header.magic != MAGIC || len > DATA_SIZE || checksum(&data[..len]) != header.checksum {
+        return;
+    }
+    crate::warn!("pstore: the previous boot crashed:");
+    for line in core::str::from_utf8(&data[..len]).unwrap_or("<invalid utf-8>").lines() {
+        crate::warn!("pstore: {}", line);
+    }
+    header.magic = 0;
+}
+
+/* The header and the data of the region. Only valid once init has set REGION. */
+fn region() -> (&'static mut Header, &'static mut [u8]) {
+    let start = memory::phys_to_virt(PhysAddr::new(REGION.load(Ordering::SeqCst)));
+    /* Safety: the region is reserved, so only this module accesses it, and all of physical memory is mapped. Only
+    init and record_panic use it, and they never run at the same time. */
+    unsafe {
+        let header = &mut *start.as_mut_ptr::<Header>();
+        let data = core::slice::from_raw_parts_mut((start + HEADER_SIZE).as_mut_ptr::<u8>(), DATA_SIZE);
+        (header, data)
+    }
+}
+
+/// Saves the panic message and the most recent trace records, to be reported on the next boot. Takes no locks, so it
+/// can be called from the panic handler.
+pub fn record_panic(info: &PanicInfo) {
+    if REGION.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let (header, data) = region();
+    // Invalidate the old contents first, in case the machine resets while the new ones are written.
+    header.magic = 0;
+    let mut writer = Writer { data, len: 0 };
+    let _ = writeln!(writer, "panic after {} ms: {}", crate::time::uptime_ms(), info);
+    crate::trace::for_each_recent(TRACE_RECORDS, |name, cpu, tsc, [a, b]| {
+        let _ = writeln!(writer, "trace cpu{} tsc={} {} {:#x} {:#x}", cpu, tsc, name, a, b);
+    });
+    let len = writer.len;
+    header.len = len as u32;
+    header.checksum = checksum(&writer.data[..len]);
+    // The magic number marks the contents as complete, so it must not be written before them.
+    core::sync::atomic::compiler_fence(Ordering::SeqCst);
+    header.magic = MAGIC;
+}
+
+/* Writes into the data area, and drops what doesn't fit. */
+struct Writer {
+    data: &'static mut [u8],
+    len: usize,
+}
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(self.data.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.data[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/* FNV-1a. */
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193))
+}
+
+#[test_case]
+fn test_checksum() {
+    assert_eq!(checksum(b""), 0x811c_9dc5);
+    assert_eq!(checksum(b"a"), 0xe40c_292c);
+}
@@ -0,0 +1,33 @@
+use core::fmt;
+
+/* Subsystems keep what is registered with them, e.g. shutdown hooks, shrinkers, port claims or watched stacks, in a
+fixed table of `Option` slots behind a lock, so that registering works before the heap exists. */
+
+/// Every slot of a registry is in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full {
+    /// The number of slots the registry has.
+    pub capacity: usize,
+}
+
+impl fmt::Display for Full {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "all {} slots in use", self.capacity)
+    }
+}
+
+/// Puts `value` into the first free slot of `slots` and returns the slot's index.
+pub fn insert<T>(slots: &mut [Option<T>], value: T) -> Result<usize, Full> {
+    let index = slots.iter().position(Option::is_none).ok_or(Full { capacity: slots.len() })?;
+    slots[index] = Some(value);
+    Ok(index)
+}
+
+#[test_case]
+fn test_insert() {
+    let mut slots = [None, Some(1), None];
+    assert_eq!(insert(&mut slots, 2), Ok(0));
+    assert_eq!(insert(&mut slots, 3), Ok(2));
+    assert_eq!(insert(&mut slots, 4), Err(Full { capacity: 3 }));
+    assert_eq!(slots, [Some(2), Some(1), Some(3)]);
+}
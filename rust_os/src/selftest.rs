@@ -0,0 +1,89 @@
+use alloc::{boxed::Box, vec::Vec};
+
+/* A boot-time health check. Real hardware has no QEMU test harness, so with `selftest=on` on the kernel command line
+(or the `selftest` cargo feature, which `debug-heavy` enables) the kernel runs a few quick invariant checks after
+initialization and logs PASS or FAIL for each of them. It must run after the heap has been initialized. */
+
+/// Whether the self-test was requested through the `selftest` feature or the kernel command line.
+pub fn requested(command_line: Option<&str>) -> bool {
+    cfg!(feature = "selftest")
+        || command_line.and_then(|cmdline| crate::bootinfo::param(cmdline, "selftest")) == Some("on")
+}
+
+struct Check {
+    name: &'static str,
+    run: fn() -> Result<(), &'static str>,
+}
+
+const CHECKS: [Check; 4] = [
+    Check { name: "idt loaded", run: check_idt },
+    Check { name: "heap round trip", run: check_heap },
+    Check { name: "timer ticking", run: check_timer },
+    Check { name: "keyboard controller present", run: check_keyboard_controller },
+];
+
+/// Runs all checks and returns the number of failures.
+pub fn run() -> usize {
+    let mut failures = 0;
+    for check in CHECKS.iter() {
+        match (check.run)() {
+            Ok(()) => crate::info!("selftest: {:<30} PASS", check.name),
+            Err(reason) => {
+                failures += 1;
+                crate::error!("selftest: {:<30} FAIL ({})", check.name, reason);
+            }
+        }
+    }
+    crate::info!("selftest: {} of {} checks passed", CHECKS.len() - failures, CHECKS.len());
+    failures
+}
+
+/* The IDT register must point at our IDT, with the limit of a full 256-entry table. */
+fn check_idt() -> Result<(), &'static str> {
+    use x86_64::instructions::tables::sidt;
+    use x86_64::structures::idt::InterruptDescriptorTable;
+
+    let pointer = sidt();
+    if pointer.base.as_u64() != crate::interrupts::idt_address() {
+        return Err("IDTR does not point at the kernel IDT");
+    }
+    if usize::from(pointer.limit) != core::mem::size_of::<InterruptDescriptorTable>() - 1 {
+        return Err("unexpected IDT limit");
+    }
+    Ok(())
+}
+
+/* Allocate and free a few blocks and check that the contents survive. */
+fn check_heap() -> Result<(), &'static str> {
+    let boxed = Box::new(0x1234_5678_u64);
+    let mut values: Vec<u64> = (0..256).collect();
+    values.push(*boxed);
+    if values.iter().take(256).sum::<u64>() != 255 * 256 / 2 || values[256] != 0x1234_5678 {
+        return Err("heap contents corrupted");
+    }
+    Ok(())
+}
+
+/* With interrupts enabled, the tick count must advance. hlt returns on every interrupt, so if the timer is not firing
+we give up after a bounded number of other interrupts (or hang, if there are none at all). */
+fn check_timer() -> Result<(), &'static str> {
+    if !x86_64::instructions::interrupts::are_enabled() {
+        return Err("interrupts are disabled");
+    }
+    let start = crate::time::ticks();
+    for _ in 0..1000 {
+        x86_64::instructions::hlt();
+        if crate::time::ticks() >= start + 2 {
+            return Ok(());
+        }
+    }
+    Err("tick count did not advance")
+}
+
+/* Reading the status register of a PS/2 controller that isn't there returns the floating bus value 0xff. */
+fn check_keyboard_controller() -> Result<(), &'static str> {
+    if crate::ps2::read_status() == 0xff {
+        return Err("status port reads 0xff");
+    }
+    Ok(())
+}
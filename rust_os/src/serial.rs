@@ -1,31 +1,317 @@
 use uart_16550::SerialPort;
-use spin::Mutex;
 use lazy_static::lazy_static;
 
 /* Now we wish to print test result back to the host system's console. An easy way to do this is to use a serial port,
 which is an old inteface standard. QEMU can redirect the bytes to the host system's standard output. */
 
 /* Use a lazy_static like we did for the vga buffer. 
-By using lazy_static we can ensure that the init method is called exactly once on its first use. */
+By using lazy_static we can ensure that the init method is called exactly once on its first use. The SerialPort itself
+is not kept: all output goes through TX_QUEUE and the emergency path below, which access the registers through PORTS,
+so PORTS is the only owner of COM1. */
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    /* The UART's eight registers, claimed and set up together. */
+    static ref PORTS: PortRange = {
+        let ports = portio::claim_or_panic(COM1..=COM1 + 7, "serial");
         /* Pass the address of the first IO port of the Uart. */
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
+        unsafe { SerialPort::new(ports.base()) }.init();
+        ports
     };
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
+    write_on_channel(Channel::Log, args);
+}
+
+/// Writes interactive shell output. Only differs from `serial_print!` when console framing is enabled.
+pub fn shell_print(args: ::core::fmt::Arguments) {
+    write_on_channel(Channel::Shell, args);
+}
+
+use crate::console::{self, Channel};
+
+/* Output is not written to the UART directly. Writing byte by byte means spinning on the line status register for every
+byte, at 115200 baud roughly 87 microseconds each, and doing it with interrupts disabled so that messages don't
+interleave. A long log line kept interrupts off for several milliseconds.
+
+Instead, formatted output goes into TX_QUEUE, which takes a few hundred nanoseconds per line, and the queue is drained
+into the UART's 16-byte transmit FIFO. Once enable_interrupts has run, the UART raises IRQ 4 whenever the FIFO runs
+empty and the interrupt handler refills it, so printing never waits for the wire. Before that, and whenever the queue is
+full, the queue is drained by polling, as before. */
+const TX_QUEUE_SIZE: usize = 4096;
+const FIFO_SIZE: usize = 16;
+
+static TX_QUEUE: MpscQueue<u8, TX_QUEUE_SIZE> = MpscQueue::new();
+static TX_INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+// Register offsets from COM1.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const INTERRUPT_IDENTIFICATION: u16 = 2;
+const LINE_STATUS: u16 = 5;
+const RECEIVE_DATA_INTERRUPT: u8 = 1 << 0;
+const TRANSMIT_EMPTY_INTERRUPT: u8 = 1 << 1;
+const DATA_READY: u8 = 1 << 0;
+
+/* Everything written through a QueueWriter is queued, and byte-stuffed if it is the payload of a frame. */
+struct QueueWriter {
+    framed: bool,
+}
+
+impl QueueWriter {
+    fn push(&mut self, byte: u8) {
+        while TX_QUEUE.push(byte).is_err() {
+            flush();
+        }
+    }
+}
+
+impl core::fmt::Write for QueueWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if self.framed {
+            console::encode_payload(s.as_bytes(), |byte| self.push(byte));
+        } else {
+            s.bytes().for_each(|byte| self.push(byte));
+        }
+        Ok(())
+    }
+}
+
+fn write_on_channel(channel: Channel, args: ::core::fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    // The UART is set up on first use.
+    lazy_static::initialize(&PORTS);
+    // Interrupts are still disabled while formatting, so that messages don't interleave, but only for as long as it
+    // takes to copy them into the queue.
     interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+        let framed = console::framing_enabled();
+        if NONBLOCKING.load(Ordering::Relaxed) && !reserve(args, framed) {
+            return;
+        }
+        let mut writer = QueueWriter { framed: false };
+        if framed {
+            writer.push(console::FRAME_DELIMITER);
+            writer.push(channel as u8);
+        }
+        writer.framed = framed;
+        writer.write_fmt(args).expect("Printing to serial failed");
+        if framed {
+            writer.framed = false;
+            writer.push(console::FRAME_DELIMITER);
+        }
+        if TX_INTERRUPT.load(Ordering::Relaxed) {
+            start_transmit();
+        } else {
+            flush();
+        }
+    });
+}
+
+/* In non-blocking mode, a message that doesn't fit into the queue is dropped as a whole rather than waiting for the
+UART, so that heavy logging from an interrupt handler can't stall it. The number of dropped messages is counted, and
+reported in the output stream at most once per second, as soon as there is room again. */
+static NONBLOCKING: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static REPORTED: AtomicUsize = AtomicUsize::new(0);
+static LAST_REPORT: AtomicU64 = AtomicU64::new(0);
+
+/// Selects whether printing waits for room in the transmit queue (the default) or drops the message.
+pub fn set_nonblocking(nonblocking: bool) {
+    NONBLOCKING.store(nonblocking, Ordering::SeqCst);
+}
+
+pub fn nonblocking() -> bool {
+    NONBLOCKING.load(Ordering::SeqCst)
+}
+
+/// The number of messages dropped in non-blocking mode since boot.
+pub fn dropped_messages() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/* Counts the bytes a message takes in the queue without writing it anywhere. */
+struct CountingWriter {
+    framed: bool,
+    len: usize,
+}
+
+impl core::fmt::Write for CountingWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.len += if self.framed {
+            s.bytes().map(|byte| if byte == console::FRAME_DELIMITER || byte == console::FRAME_ESCAPE { 2 } else { 1 }).sum()
+        } else {
+            s.len()
+        };
+        Ok(())
+    }
+}
+
+/* Decides whether a message may be queued in non-blocking mode, and queues the drop report first if one is due. Called
+with interrupts disabled, so the free space can't shrink between the check and the write on this CPU. */
+fn reserve(args: ::core::fmt::Arguments, framed: bool) -> bool {
+    use core::fmt::Write;
+    let mut counter = CountingWriter { framed, len: 0 };
+    let _ = counter.write_fmt(args);
+    // The delimiters and the channel byte of a frame.
+    let needed = counter.len + if framed { 3 } else { 0 };
+    let free = TX_QUEUE_SIZE - TX_QUEUE.len();
+    if needed > free {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+
+    let dropped = DROPPED.load(Ordering::Relaxed);
+    let reported = REPORTED.load(Ordering::Relaxed);
+    let now = crate::time::ticks();
+    let report_due = now >= LAST_REPORT.load(Ordering::Relaxed) + u64::from(crate::config::TIMER_HZ);
+    // 64 bytes is plenty for the report line.
+    if dropped > reported && report_due && needed + 64 <= free {
+        REPORTED.store(dropped, Ordering::Relaxed);
+        LAST_REPORT.store(now, Ordering::Relaxed);
+        let mut writer = QueueWriter { framed: false };
+        if framed {
+            writer.push(console::FRAME_DELIMITER);
+            writer.push(Channel::Log as u8);
+        }
+        let _ = writeln!(writer, "[serial: {} messages dropped]", dropped - reported);
+        if framed {
+            writer.push(console::FRAME_DELIMITER);
+        }
+    }
+    true
+}
+
+/* Fills the transmit FIFO if it is empty, and keeps the transmit interrupt enabled for as long as there is more to
+send. Must be called with interrupts disabled, which is what makes the consumer handle always available here: it is
+only ever held with interrupts disabled on this CPU. */
+fn start_transmit() {
+    let mut consumer = match TX_QUEUE.try_consumer() {
+        Some(consumer) => consumer,
+        None => return,
+    };
+    let mut line_status = PORTS.read_only::<u8>(LINE_STATUS);
+    let mut data = PORTS.write_only::<u8>(DATA);
+    let mut interrupt_enable = PORTS.port::<u8>(INTERRUPT_ENABLE);
+    unsafe {
+        if line_status.read() & TRANSMIT_EMPTY != 0 {
+            for _ in 0..FIFO_SIZE {
+                match consumer.pop() {
+                    Some(byte) => data.write(byte),
+                    None => break,
+                }
+            }
+        }
+        let enable = if TX_QUEUE.is_empty() { 0 } else { TRANSMIT_EMPTY_INTERRUPT };
+        interrupt_enable.write(RECEIVE_DATA_INTERRUPT | enable);
+    }
+}
+
+/// Writes all queued output to the UART by polling. Used before the transmit interrupt is enabled, when the queue is
+/// full, and before exiting QEMU, so that no output is lost.
+pub fn flush() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(mut consumer) = TX_QUEUE.try_consumer() {
+            while let Some(byte) = consumer.pop() {
+                EmergencySerial.send(byte);
+            }
+        }
     });
 }
 
+/// The number of bytes waiting to be sent.
+pub fn pending() -> usize {
+    TX_QUEUE.len()
+}
+
+/// Switches to interrupt driven output, and passes received bytes to the console (see console.rs). Called once the IDT
+/// and the PICs are set up.
+pub fn enable_interrupts() {
+    lazy_static::initialize(&PORTS);
+    flush();
+    unsafe { PORTS.port::<u8>(INTERRUPT_ENABLE).write(RECEIVE_DATA_INTERRUPT) };
+    crate::interrupts::unmask(crate::interrupts::IRQ_COM1);
+    TX_INTERRUPT.store(true, Ordering::SeqCst);
+}
+
+/// Called by the COM1 interrupt handler.
+pub fn handle_interrupt() {
+    // Reading the identification register acknowledges a pending transmit empty interrupt.
+    unsafe { PORTS.read_only::<u8>(INTERRUPT_IDENTIFICATION).read() };
+    // The receive interrupt stays pending until the receive FIFO is empty.
+    let mut line_status = PORTS.read_only::<u8>(LINE_STATUS);
+    let mut data = PORTS.read_only::<u8>(DATA);
+    while unsafe { line_status.read() } & DATA_READY != 0 {
+        console::receive(unsafe { data.read() });
+    }
+    start_transmit();
+}
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use crate::sync::lockfree::MpscQueue;
+use crate::portio::{self, PortRange};
+
+/* Like the VGA buffer, the serial port has an emergency path for the panic handler that doesn't take any lock. It
+polls the UART's line status register and writes the data register directly, which works whether or not PORTS has
+been initialized, as long as the port was set up by the firmware or by an earlier print. For the same reason it uses
+the port numbers directly instead of going through the port claim, which a panic during early boot may precede. */
+const COM1: u16 = 0x3f8;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+struct EmergencySerial;
+
+impl EmergencySerial {
+    fn send(&mut self, byte: u8) {
+        use x86_64::instructions::port::Port;
+        let mut line_status: Port<u8> = Port::new(COM1 + LINE_STATUS);
+        let mut data: Port<u8> = Port::new(COM1 + DATA);
+        unsafe {
+            while line_status.read() & TRANSMIT_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            data.write(byte);
+        }
+    }
+}
+
+impl core::fmt::Write for EmergencySerial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if console::framing_enabled() {
+            console::encode_payload(s.as_bytes(), |byte| self.send(byte));
+        } else {
+            s.bytes().for_each(|byte| self.send(byte));
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _emergency_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    // Queued output came first, so send it first, unless the panic happened while the queue was being drained.
+    if let Some(mut consumer) = TX_QUEUE.try_consumer() {
+        while let Some(byte) = consumer.pop() {
+            EmergencySerial.send(byte);
+        }
+    }
+    let framed = console::framing_enabled();
+    if framed {
+        EmergencySerial.send(console::FRAME_DELIMITER);
+        EmergencySerial.send(Channel::Log as u8);
+    }
+    let _ = EmergencySerial.write_fmt(args);
+    if framed {
+        EmergencySerial.send(console::FRAME_DELIMITER);
+    }
+}
+
+/// Prints to the serial port without taking any lock, appending a newline. Only for the panic handler.
+#[macro_export]
+macro_rules! emergency_serial_println {
+    ($($arg:tt)*) => ($crate::serial::_emergency_print(format_args!("{}\n", format_args!($($arg)*))));
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -44,4 +330,31 @@ macro_rules! serial_println {
 }
 
 /* To see the serial output from QEMU, we need to use the -serial argument to redirect the output to stdout.
-See Cargo.toml. */
\ No newline at end of file
+See Cargo.toml. */
+#[test_case]
+fn test_queued_output_drains() {
+    for i in 0..100 {
+        crate::serial_print!("{} ", i);
+    }
+    crate::serial_println!();
+    // With the transmit interrupt enabled, the queue drains in the background.
+    let start = crate::time::ticks();
+    while pending() > 0 && crate::time::ticks() < start + 100 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(pending(), 0);
+}
+
+#[test_case]
+fn test_nonblocking_drops_whole_messages() {
+    let dropped = dropped_messages();
+    set_nonblocking(true);
+    // With interrupts disabled the queue hardly drains, so it overflows after about 50 of these lines.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for i in 0..200 {
+            crate::serial_println!("{:0100}", i);
+        }
+    });
+    set_nonblocking(false);
+    assert!(dropped_messages() >= dropped + 50);
+}
@@ -0,0 +1,120 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::registry;
+
+/* Caches make the kernel faster, but the memory they hold can always be given back. A subsystem that keeps such a
+cache registers a shrinker with two callbacks: `count` returns how many bytes it could free right now, and `scan` frees
+up to the given number of bytes and returns how many it actually freed.
+
+The allocator calls shrink() when the heap runs out of memory and its OOM policy is Reclaim (see allocator.rs), and
+drop_caches() empties every cache at once, e.g. to find out how much memory is really in use. Shrinkers run with the
+heap unlocked, so they can free memory, but they must not allocate. */
+
+const MAX_SHRINKERS: usize = 16;
+
+#[derive(Clone, Copy)]
+pub struct Shrinker {
+    pub name: &'static str,
+    pub count: fn() -> usize,
+    pub scan: fn(usize) -> usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShrinkerError {
+    Full(registry::Full),
+    /// A shrinker with the same name is already registered.
+    AlreadyRegistered,
+}
+
+static SHRINKERS: Mutex<[Option<Shrinker>; MAX_SHRINKERS]> = Mutex::new([None; MAX_SHRINKERS]);
+
+pub fn register(shrinker: Shrinker) -> Result<(), ShrinkerError> {
+    without_interrupts(|| {
+        let mut shrinkers = SHRINKERS.lock();
+        if shrinkers.iter().flatten().any(|s| s.name == shrinker.name) {
+            return Err(ShrinkerError::AlreadyRegistered);
+        }
+        registry::insert(&mut *shrinkers, shrinker).map_err(ShrinkerError::Full)?;
+        Ok(())
+    })
+}
+
+pub fn unregister(name: &str) {
+    without_interrupts(|| {
+        for slot in SHRINKERS.lock().iter_mut() {
+            if matches!(slot, Some(s) if s.name == name) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/* The registry is copied before the callbacks run, so that a shrinker can't deadlock by registering or unregistering
+from its own callback. */
+fn snapshot() -> [Option<Shrinker>; MAX_SHRINKERS] {
+    without_interrupts(|| *SHRINKERS.lock())
+}
+
+/// The number of bytes all caches could free right now.
+pub fn reclaimable() -> usize {
+    snapshot().iter().flatten().map(|s| (s.count)()).sum()
+}
+
+/// Frees at least `target` bytes if possible, starting with the caches that hold the most memory. Returns the number of
+/// bytes freed.
+pub fn shrink(target: usize) -> usize {
+    let mut shrinkers = snapshot();
+    shrinkers.sort_unstable_by_key(|s| core::cmp::Reverse(s.map_or(0, |s| (s.count)())));
+    let mut freed = 0;
+    for shrinker in shrinkers.iter().flatten() {
+        if freed >= target {
+            break;
+        }
+        freed += (shrinker.scan)(target - freed);
+    }
+    freed
+}
+
+/// Empties all caches and returns the number of bytes freed.
+pub fn drop_caches() -> usize {
+    snapshot().iter().flatten().map(|s| (s.scan)(usize::MAX)).sum()
+}
+
+/// Prints every shrinker with the number of bytes it could free.
+pub fn dump() {
+    for shrinker in snapshot().iter().flatten() {
+        crate::println!("shrinker: {:<24} {} bytes", shrinker.name, (shrinker.count)());
+    }
+}
+
+/// Handles the `drop_caches` shell command.
+pub fn command(_args: &str) {
+    let freed = drop_caches();
+    crate::println!("drop_caches: freed {} bytes", freed);
+}
+
+#[test_case]
+fn test_shrink_and_drop_caches() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static CACHED: AtomicUsize = AtomicUsize::new(0);
+    fn count() -> usize {
+        CACHED.load(Ordering::SeqCst)
+    }
+    fn scan(target: usize) -> usize {
+        let freed = target.min(CACHED.load(Ordering::SeqCst));
+        CACHED.fetch_sub(freed, Ordering::SeqCst);
+        freed
+    }
+
+    let shrinker = Shrinker { name: "test_cache", count, scan };
+    register(shrinker).unwrap();
+    assert_eq!(register(shrinker), Err(ShrinkerError::AlreadyRegistered));
+    CACHED.store(1000, Ordering::SeqCst);
+    assert!(reclaimable() >= 1000);
+    assert!(shrink(300) >= 300);
+    assert_eq!(count(), 700);
+    drop_caches();
+    assert_eq!(count(), 0);
+    unregister("test_cache");
+}
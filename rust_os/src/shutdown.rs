@@ -0,0 +1,73 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::registry;
+
+/* Subsystems that hold state which must reach the hardware before the machine powers off register a shutdown hook, e.g.
+the serial port flushes its transmit queue. power::shutdown and power::reboot run the hooks in the reverse order of
+their registration, which is the reverse of the order the subsystems were initialized in: a subsystem is torn down
+before the ones it was built on. Hooks run with interrupts disabled and must not block on other CPUs. */
+
+const MAX_HOOKS: usize = 16;
+
+#[derive(Clone, Copy)]
+pub struct Hook {
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookError {
+    Full(registry::Full),
+    /// A hook with the same name is already registered.
+    AlreadyRegistered,
+}
+
+static HOOKS: Mutex<[Option<Hook>; MAX_HOOKS]> = Mutex::new([None; MAX_HOOKS]);
+
+pub fn register(name: &'static str, run: fn()) -> Result<(), HookError> {
+    without_interrupts(|| {
+        let mut hooks = HOOKS.lock();
+        if hooks.iter().flatten().any(|h| h.name == name) {
+            return Err(HookError::AlreadyRegistered);
+        }
+        // Hooks stay in registration order, so the first free slot is after all registered ones.
+        registry::insert(&mut *hooks, Hook { name, run }).map_err(HookError::Full)?;
+        Ok(())
+    })
+}
+
+pub fn unregister(name: &str) {
+    without_interrupts(|| {
+        let mut hooks = HOOKS.lock();
+        if let Some(index) = hooks.iter().position(|h| matches!(h, Some(h) if h.name == name)) {
+            // Close the gap to keep the registration order.
+            hooks[index..].rotate_left(1);
+            hooks[MAX_HOOKS - 1] = None;
+        }
+    });
+}
+
+/// Runs all hooks, the most recently registered first. The registry is copied first, so that a hook may unregister.
+pub fn run_hooks() {
+    let hooks = without_interrupts(|| *HOOKS.lock());
+    for hook in hooks.iter().rev().flatten() {
+        crate::info!("shutdown: {}", hook.name);
+        (hook.run)();
+    }
+}
+
+#[test_case]
+fn test_hooks_keep_registration_order() {
+    fn nothing() {}
+    register("test a", nothing).unwrap();
+    register("test b", nothing).unwrap();
+    register("test c", nothing).unwrap();
+    assert_eq!(register("test b", nothing), Err(HookError::AlreadyRegistered));
+    unregister("test b");
+    let names = without_interrupts(|| *HOOKS.lock());
+    let mut names = names.iter().flatten().map(|h| h.name).filter(|name| name.starts_with("test "));
+    assert_eq!(names.next(), Some("test a"));
+    assert_eq!(names.next(), Some("test c"));
+    unregister("test a");
+    unregister("test c");
+}
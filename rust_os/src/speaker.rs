@@ -0,0 +1,57 @@
+use lazy_static::lazy_static;
+use crate::portio::{self, PortRange};
+
+/* The PC speaker is driven by channel 2 of the PIT (see pit.rs) through two gates in the system control port 0x61:
+bit 0 connects the PIT clock to channel 2, and bit 1 connects channel 2's output to the speaker. The other bits of the
+port control unrelated things like the NMI sources, so they are preserved.
+
+On real hardware without a screen or serial console, beeps are the only way to tell how a run ended. With the `beep`
+feature, a test run that passes ends with one short high beep, and a failed test run or a kernel panic with three low
+ones. */
+
+const GATE: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+
+lazy_static! {
+    static ref PORT: PortRange = portio::claim_or_panic(0x61..=0x61, "speaker");
+}
+
+/// Plays a tone of `hz` for `duration_ms` milliseconds. Works with interrupts disabled, e.g. in the panic handler.
+pub fn beep(hz: u32, duration_ms: u64) {
+    crate::pit::set_channel_2_frequency(hz);
+    let mut port = PORT.port::<u8>(0);
+    unsafe {
+        let control = port.read();
+        port.write(control | GATE | SPEAKER);
+        delay_ms(duration_ms);
+        port.write(control & !(GATE | SPEAKER));
+    }
+}
+
+/* The timer tick may not be running, so wait by reading the control port: every access to an ISA port takes about a
+microsecond, which is plenty accurate for a beep. */
+fn delay_ms(ms: u64) {
+    let mut port = PORT.read_only::<u8>(0);
+    for _ in 0..ms * 1000 {
+        unsafe { port.read() };
+    }
+}
+
+pub fn enabled() -> bool {
+    cfg!(feature = "beep")
+}
+
+/// Beeps once if `passed`, three times otherwise, when the `beep` feature is enabled.
+pub fn signal(passed: bool) {
+    if !enabled() {
+        return;
+    }
+    if passed {
+        beep(880, 150);
+    } else {
+        for _ in 0..3 {
+            beep(220, 300);
+            delay_ms(150);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+use crate::registry;
+
+/* Stack high-water marks. Stack sizes are a guess: too small and the kernel overflows into the guard page, too large
+and the memory is wasted. To replace the guess with a measurement, build with the `stack-watermark` feature. Every stack
+that is registered here is then filled with a known pattern before it is first used. A stack grows down, so the lowest
+byte that no longer holds the pattern marks the deepest point the stack ever reached.
+
+Scanning a stack is cheap but not free, so it only happens on demand: report() prints the usage of every registered
+stack, and unregister() logs the usage of a stack that goes away, e.g. when a thread exits. Without the feature, stacks
+are not filled and their usage is unknown.
+
+So far the registered stacks are the interrupt stacks in gdt.rs, which every CPU has a set of. Thread stacks will be
+registered here once there is a scheduler. */
+
+// The stacks of every CPU, plus room for as many stacks that don't belong to a CPU.
+const MAX_STACKS: usize = crate::cpu::MAX_CPUS * crate::gdt::STACKS_PER_CPU + 16;
+const PATTERN: u8 = 0xa5;
+
+#[derive(Debug, Clone, Copy)]
+struct Stack {
+    name: &'static str,
+    // The CPU a per-CPU stack belongs to.
+    cpu: Option<usize>,
+    bottom: VirtAddr,
+    size: usize,
+}
+
+static STACKS: Mutex<[Option<Stack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+/// Whether stacks are pattern-filled, i.e. whether their usage can be measured.
+pub fn enabled() -> bool {
+    cfg!(feature = "stack-watermark")
+}
+
+/// Registers the stack of `size` bytes starting at `bottom` and fills it with the pattern. `cpu` is the CPU the stack
+/// belongs to, if it is one of a set of per-CPU stacks.
+///
+/// This function is unsafe because the stack must be valid for writes and must not be in use yet.
+pub unsafe fn register(
+    name: &'static str,
+    cpu: Option<usize>,
+    bottom: VirtAddr,
+    size: usize,
+) -> Result<(), registry::Full> {
+    if enabled() {
+        core::ptr::write_bytes(bottom.as_mut_ptr::<u8>(), PATTERN, size);
+    }
+    without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        registry::insert(&mut *stacks, Stack { name, cpu, bottom, size })?;
+        Ok(())
+    })
+}
+
+/// Removes the stack starting at `bottom`, logging how much of it was used.
+pub fn unregister(bottom: VirtAddr) {
+    let stack = without_interrupts(|| {
+        STACKS.lock().iter_mut().find(|s| matches!(s, Some(s) if s.bottom == bottom)).and_then(Option::take)
+    });
+    if let Some(stack) = stack {
+        if let Some(used) = stack.used() {
+            crate::info!("stack {}: used {} of {} bytes", stack.name(), used, stack.size);
+        }
+    }
+}
+
+impl Stack {
+    fn name(&self) -> StackName {
+        StackName { name: self.name, cpu: self.cpu }
+    }
+
+    fn used(&self) -> Option<usize> {
+        if !enabled() {
+            return None;
+        }
+        // Safety: the stack was registered as valid memory of this size.
+        let bytes = unsafe { core::slice::from_raw_parts(self.bottom.as_ptr::<u8>(), self.size) };
+        Some(high_water(bytes))
+    }
+}
+
+/* A stack's name, prefixed with its CPU for per-CPU stacks: "cpu0 double fault". Honors the width, so report() can
+line the names up. */
+struct StackName {
+    name: &'static str,
+    cpu: Option<usize>,
+}
+
+impl fmt::Display for StackName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = f.width().unwrap_or(0);
+        let len = match self.cpu {
+            Some(cpu) => {
+                write!(f, "cpu{} {}", cpu, self.name)?;
+                // "cpu", the digits of the CPU number and a space.
+                4 + cpu.checked_ilog10().unwrap_or(0) as usize + 1 + self.name.len()
+            }
+            None => {
+                f.write_str(self.name)?;
+                self.name.len()
+            }
+        };
+        for _ in len..width {
+            f.write_str(" ")?;
+        }
+        Ok(())
+    }
+}
+
+/// The number of bytes at the top of a pattern-filled stack that were written to.
+pub fn high_water(stack: &[u8]) -> usize {
+    let untouched = stack.iter().take_while(|&&byte| byte == PATTERN).count();
+    stack.len() - untouched
+}
+
+/// The high-water mark of the stack registered as `name` for `cpu`, if stacks are pattern-filled.
+pub fn usage(name: &str, cpu: Option<usize>) -> Option<usize> {
+    let stack =
+        without_interrupts(|| STACKS.lock().iter().flatten().find(|s| s.name == name && s.cpu == cpu).copied())?;
+    stack.used()
+}
+
+/// Prints the high-water mark of every registered stack.
+pub fn report() {
+    let stacks = without_interrupts(|| *STACKS.lock());
+    if !enabled() {
+        crate::println!("stacks: build with the stack-watermark feature to measure stack usage");
+    }
+    for stack in stacks.iter().flatten() {
+        match stack.used() {
+            Some(used) => crate::println!(
+                "stack {:<24} {:>6} of {:>6} bytes used ({}%)",
+                stack.name(),
+                used,
+                stack.size,
+                used * 100 / stack.size
+            ),
+            None => crate::println!("stack {:<24} {:>6} bytes", stack.name(), stack.size),
+        }
+    }
+}
+
+#[test_case]
+fn test_high_water() {
+    let mut stack = [PATTERN; 64];
+    assert_eq!(high_water(&stack), 0);
+    // A stack grows down from its end.
+    stack[40] = 0;
+    stack[63] = 0;
+    assert_eq!(high_water(&stack), 24);
+}
+
+#[test_case]
+fn test_interrupt_stacks_are_registered_per_cpu() {
+    use core::fmt::Write;
+
+    struct Line {
+        bytes: [u8; 32],
+        len: usize,
+    }
+
+    impl Write for Line {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.bytes[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+            self.len += s.len();
+            Ok(())
+        }
+    }
+
+    let stacks = without_interrupts(|| *STACKS.lock());
+    let cpu = crate::cpu::current_cpu();
+    assert!(stacks.iter().flatten().any(|s| s.name == "double fault" && s.cpu == Some(cpu)));
+
+    let mut line = Line { bytes: [0; 32], len: 0 };
+    write!(line, "{:<12}|{}", StackName { name: "nmi", cpu: Some(1) }, StackName { name: "thread", cpu: None }).unwrap();
+    assert_eq!(&line.bytes[..line.len], b"cpu1 nmi    |thread");
+}
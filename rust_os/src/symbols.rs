@@ -0,0 +1,105 @@
+/* A sorted table of (start address, name) pairs for every function in the kernel image. It is generated by build.rs
+from the symbol map of a previous build and linked into the dedicated `.ksymtab` section. */
+mod table {
+    include!(concat!(env!("OUT_DIR"), "/symbols.rs"));
+}
+
+/* The table, read with a volatile load so that the compiler knows neither its length nor its contents. The code that
+uses it must be the same whether the table is empty or not, or embedding it would move the functions it describes (see
+build.rs). */
+fn table() -> &'static [(u64, &'static str)] {
+    // Safety: SYMBOLS is an initialized static.
+    unsafe { core::ptr::read_volatile(&table::SYMBOLS) }
+}
+
+/// A resolved symbol: the name of the enclosing function and the offset of the address into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: u64,
+    pub offset: u64,
+}
+
+impl core::fmt::Display for Symbol {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// Returns the function containing `addr`, or `None` if the address lies outside of the kernel's code, before the first
+/// known symbol, or no symbol table was embedded.
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    // Past the end of .text, the last function would otherwise claim every address.
+    if !crate::kimage::is_text(addr) {
+        return None;
+    }
+    let symbols = table();
+    /* Binary search for the last symbol that starts at or before addr. partition_point returns the index of the first
+    symbol that starts after addr, so the one we want is right before it. */
+    let index = symbols.partition_point(|&(start, _)| start <= addr);
+    if index == 0 {
+        return None;
+    }
+    let (start, name) = symbols[index - 1];
+    Some(Symbol { name, address: start, offset: addr - start })
+}
+
+/// Number of symbols in the embedded table.
+pub fn count() -> usize {
+    table().len()
+}
+
+/// A helper for printing an address together with its symbol, if one is known.
+pub struct Symbolized(pub u64);
+
+impl core::fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match resolve(self.0) {
+            Some(symbol) => write!(f, "{:#x} <{}>", self.0, symbol),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+/// The most frames `backtrace` follows.
+const MAX_FRAMES: usize = 32;
+
+/* The kernel is built with frame pointers (see target_triple_config.json), so every function starts by pushing the
+caller's RBP and pointing RBP at it. The stack therefore holds a linked list of frames, each a saved RBP followed by the
+return address into the caller. The walk stops at a null or unmapped frame pointer, or one that doesn't lead further up
+the stack, so a corrupted stack cuts the backtrace short instead of faulting in the panic handler. */
+
+/// Calls `f` with the return address of every frame on the current stack, innermost first.
+#[inline(never)]
+pub fn backtrace(mut f: impl FnMut(u64)) {
+    let mut frame: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags)) };
+    for _ in 0..MAX_FRAMES {
+        let readable = |addr: u64| x86_64::VirtAddr::try_new(addr).is_ok_and(crate::memory::is_mapped);
+        if frame == 0 || !frame.is_multiple_of(8) || !readable(frame) || !readable(frame + 8) {
+            return;
+        }
+        // Safety: both words are mapped, and the stack below the current frame is not written to during the walk.
+        let (next, return_address) = unsafe { (*(frame as *const u64), *((frame + 8) as *const u64)) };
+        if return_address == 0 {
+            return;
+        }
+        f(return_address);
+        if next <= frame {
+            return;
+        }
+        frame = next;
+    }
+}
+
+#[test_case]
+fn test_backtrace() {
+    #[inline(never)]
+    fn inner() -> usize {
+        let mut frames = 0;
+        backtrace(|_| frames += 1);
+        frames
+    }
+    // At least inner's caller and this test's caller are on the stack.
+    assert!(core::hint::black_box(inner()) >= 2);
+}
@@ -0,0 +1,3 @@
+/* Synchronization primitives that the `spin` crate doesn't provide. */
+
+pub mod lockfree;
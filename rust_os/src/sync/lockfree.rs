@@ -0,0 +1,177 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/* A bounded multi-producer, single-consumer queue that never takes a lock and never allocates. Interrupt handlers can
+push into it even if they interrupted another producer halfway through a push, and it can be a `static` that is usable
+before the heap exists. This makes it the building block for handing data from interrupt handlers to the rest of the
+kernel, e.g. a stream of scancodes or deferred interrupt work.
+
+The algorithm is Dmitry Vyukov's bounded queue. Every slot has a sequence number that says whose turn it is: a producer
+may write the slot for position `pos` once its sequence is `pos`, and the consumer may read it once it is `pos + 1`.
+After reading, the consumer sets it to `pos + N`, handing the slot to the producer one lap later. Producers reserve a
+position by advancing `tail` with a compare-and-swap, so two producers can never write the same slot.
+
+Vyukov's version initializes the sequence of slot `i` to `i`. To keep `new` a const fn that works for any N, the slots
+here store their sequence minus their index instead, which starts out as zero for every slot. */
+
+struct Slot<T> {
+    // Sequence number minus the slot's index, with wrapping arithmetic.
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Slot<T> = Slot { sequence: AtomicUsize::new(0), value: UnsafeCell::new(MaybeUninit::uninit()) };
+}
+
+pub struct MpscQueue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    consumer_taken: AtomicBool,
+}
+
+/* Values are moved in by one CPU or interrupt handler and out by another, so T must be Send. The sequence numbers
+make sure that every slot is accessed by at most one side at a time. */
+unsafe impl<T: Send, const N: usize> Sync for MpscQueue<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for MpscQueue<T, N> {}
+
+impl<T, const N: usize> MpscQueue<T, N> {
+    // Evaluated when `new` is instantiated, so that a queue without slots fails to compile instead of dividing by zero.
+    const NOT_EMPTY: () = assert!(N > 0, "an MpscQueue needs at least one slot");
+
+    pub const fn new() -> MpscQueue<T, N> {
+        let () = Self::NOT_EMPTY;
+        MpscQueue {
+            slots: [Slot::EMPTY; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            consumer_taken: AtomicBool::new(false),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// The number of queued values. Only a snapshot if producers are pushing concurrently.
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire)).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends `value` to the queue, or gives it back if the queue is full. Safe to call from any context, including
+    /// interrupt handlers.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let index = pos % N;
+            let slot = &self.slots[index];
+            let sequence = slot.sequence.load(Ordering::Acquire).wrapping_add(index);
+            match sequence.wrapping_sub(pos) as isize {
+                0 => match self.tail.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // Safety: winning the compare-and-swap for `pos` gives us exclusive access to the slot until
+                        // we publish it by updating its sequence.
+                        unsafe { (*slot.value.get()).as_mut_ptr().write(value) };
+                        slot.sequence.store(pos.wrapping_add(1).wrapping_sub(index), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                },
+                // The slot still holds the value from the previous lap, so the queue is full.
+                difference if difference < 0 => return Err(value),
+                // Another producer has claimed `pos` in the meantime.
+                _ => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Returns the consumer handle, or `None` if somebody else holds it. There is only one consumer at a time, which
+    /// is what keeps `pop` simple.
+    pub fn try_consumer(&self) -> Option<Consumer<'_, T, N>> {
+        if self.consumer_taken.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        Some(Consumer { queue: self })
+    }
+
+    /* Only called through Consumer, so there is a single caller at a time. */
+    fn pop(&self) -> Option<T> {
+        let pos = self.head.load(Ordering::Relaxed);
+        let index = pos % N;
+        let slot = &self.slots[index];
+        let sequence = slot.sequence.load(Ordering::Acquire).wrapping_add(index);
+        if sequence != pos.wrapping_add(1) {
+            // Either empty, or the producer that reserved this slot hasn't finished writing it yet.
+            return None;
+        }
+        // Safety: the sequence says the slot was written for `pos`, and only the consumer reads slots.
+        let value = unsafe { (*slot.value.get()).as_ptr().read() };
+        slot.sequence.store(pos.wrapping_add(N).wrapping_sub(index), Ordering::Release);
+        self.head.store(pos.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Default for MpscQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for MpscQueue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+/// The exclusive right to pop values from an `MpscQueue`. Dropping it lets somebody else become the consumer.
+pub struct Consumer<'a, T, const N: usize> {
+    queue: &'a MpscQueue<T, N>,
+}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    /// Removes the oldest value from the queue.
+    pub fn pop(&mut self) -> Option<T> {
+        self.queue.pop()
+    }
+}
+
+impl<T, const N: usize> Drop for Consumer<'_, T, N> {
+    fn drop(&mut self) {
+        self.queue.consumer_taken.store(false, Ordering::Release);
+    }
+}
+
+#[test_case]
+fn test_fifo_order_and_wraparound() {
+    let queue: MpscQueue<u32, 4> = MpscQueue::new();
+    let mut consumer = queue.try_consumer().unwrap();
+    for lap in 0..3 {
+        for i in 0..4 {
+            queue.push(lap * 10 + i).unwrap();
+        }
+        assert_eq!(queue.push(99), Err(99));
+        assert_eq!(queue.len(), 4);
+        for i in 0..4 {
+            assert_eq!(consumer.pop(), Some(lap * 10 + i));
+        }
+        assert_eq!(consumer.pop(), None);
+    }
+}
+
+#[test_case]
+fn test_single_consumer() {
+    static QUEUE: MpscQueue<u8, 8> = MpscQueue::new();
+    let consumer = QUEUE.try_consumer();
+    assert!(consumer.is_some());
+    assert!(QUEUE.try_consumer().is_none());
+    drop(consumer);
+    assert!(QUEUE.try_consumer().is_some());
+}
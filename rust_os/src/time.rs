@@ -0,0 +1,66 @@
+pub mod clock;
+pub mod wheel;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::config;
+
+/* Kernel time keeping based on the timer interrupt. The PIT is programmed to fire config::TIMER_HZ times per second
+(see pit.rs), and the handler calls tick() every time, so the tick count is the time since the timer was started.
+
+Tests of code that waits for time to pass would be at the mercy of how fast QEMU happens to deliver timer interrupts.
+Instead, they can switch to a virtual clock: while it is active, timer interrupts no longer advance the tick count, and
+time only moves when the test calls advance(). */
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static VIRTUAL: AtomicBool = AtomicBool::new(false);
+
+/// Called by the timer interrupt handler.
+pub fn tick() {
+    if !VIRTUAL.load(Ordering::Relaxed) {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Stops the timer interrupt from advancing time. Only meant for tests, see `advance`.
+pub fn enable_virtual_clock() {
+    VIRTUAL.store(true, Ordering::SeqCst);
+}
+
+/// Lets the timer interrupt advance time again, continuing from the current virtual time.
+pub fn disable_virtual_clock() {
+    VIRTUAL.store(false, Ordering::SeqCst);
+}
+
+pub fn virtual_clock_enabled() -> bool {
+    VIRTUAL.load(Ordering::SeqCst)
+}
+
+/// Advances the virtual clock by `ticks` timer ticks.
+pub fn advance(ticks: u64) {
+    assert!(virtual_clock_enabled(), "time::advance needs the virtual clock");
+    TICKS.fetch_add(ticks, Ordering::SeqCst);
+}
+
+/// The number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer was started, with a resolution of one tick.
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / u64::from(config::TIMER_HZ)
+}
+
+#[test_case]
+fn test_virtual_clock() {
+    enable_virtual_clock();
+    let start = ticks();
+    // Real timer interrupts keep arriving, but must not move virtual time.
+    for _ in 0..3 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(ticks(), start);
+    advance(u64::from(config::TIMER_HZ));
+    assert_eq!(ticks(), start + u64::from(config::TIMER_HZ));
+    disable_virtual_clock();
+}
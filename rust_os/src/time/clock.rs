@@ -0,0 +1,211 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::cmos::DateTime;
+use crate::config;
+
+/* Two clocks, with the same meaning as in POSIX:
+
+  - The monotonic clock counts the time since boot. It never jumps and is what timeouts and sleeps are measured with.
+  - The realtime clock is the wall-clock time in nanoseconds since the Unix epoch. It starts out at the time of the RTC
+    (see cmos.rs) and is corrected by set_realtime whenever a better source, the RTC again or later SNTP, reports the
+    time.
+
+A correction of less than STEP_THRESHOLD_NS is slewed: the realtime clock runs up to SLEW_PPM faster or slower than the
+monotonic clock until the difference is made up, so it never jumps and never runs backwards. Larger corrections step the
+clock right away, as waiting hours for a slew to finish would be worse. Either way, the monotonic clock is unaffected.
+
+The RTC is assumed to keep UTC. The syscalls clock_gettime and clock_nanosleep will wrap gettime and nanosleep once
+there is a syscall layer. */
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const NANOS_PER_TICK: u64 = NANOS_PER_SEC / config::TIMER_HZ as u64;
+/// Corrections larger than this are stepped instead of slewed.
+pub const STEP_THRESHOLD_NS: i64 = 128_000_000;
+/// How much faster or slower than the monotonic clock the realtime clock runs while slewing, in parts per million.
+pub const SLEW_PPM: i64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockId {
+    Monotonic,
+    Realtime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepError {
+    /// Interrupts are disabled, so no tick would ever wake the sleeper.
+    InterruptsDisabled,
+    /// The virtual clock is active, and only time::advance moves it.
+    VirtualClock,
+}
+
+/// A point in time on one of the clocks, or a duration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timespec {
+    pub secs: u64,
+    pub nanos: u32,
+}
+
+impl Timespec {
+    pub fn from_nanos(nanos: u64) -> Timespec {
+        Timespec { secs: nanos / NANOS_PER_SEC, nanos: (nanos % NANOS_PER_SEC) as u32 }
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.secs * NANOS_PER_SEC + u64::from(self.nanos)
+    }
+}
+
+impl fmt::Display for Timespec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09}", self.secs, self.nanos)
+    }
+}
+
+struct Realtime {
+    // Realtime minus monotonic, without the part of the current slew that has been applied so far.
+    offset: i64,
+    // The correction that is being slewed, and the monotonic time at which the slew started.
+    slew: i64,
+    slew_start: u64,
+}
+
+impl Realtime {
+    /* The part of the slew that has been applied at monotonic time `now`. */
+    fn applied(&self, now: u64) -> i64 {
+        let max = ((now - self.slew_start) as i64).saturating_mul(SLEW_PPM) / 1_000_000;
+        self.slew.clamp(-max, max)
+    }
+
+    fn at(&self, now: u64) -> u64 {
+        (now as i64 + self.offset + self.applied(now)).max(0) as u64
+    }
+}
+
+static REALTIME: Mutex<Realtime> = Mutex::new(Realtime { offset: 0, slew: 0, slew_start: 0 });
+
+/// Nanoseconds since the timer was started, with a resolution of one tick.
+pub fn monotonic_ns() -> u64 {
+    super::ticks() * NANOS_PER_TICK
+}
+
+/// Nanoseconds since the Unix epoch.
+pub fn realtime_ns() -> u64 {
+    without_interrupts(|| REALTIME.lock().at(monotonic_ns()))
+}
+
+/// Reads `clock`.
+pub fn gettime(clock: ClockId) -> Timespec {
+    match clock {
+        ClockId::Monotonic => Timespec::from_nanos(monotonic_ns()),
+        ClockId::Realtime => Timespec::from_nanos(realtime_ns()),
+    }
+}
+
+/// Corrects the realtime clock to `nanos` since the Unix epoch, by slewing or stepping it. Returns the correction.
+pub fn set_realtime(nanos: u64) -> i64 {
+    without_interrupts(|| {
+        let now = monotonic_ns();
+        let mut realtime = REALTIME.lock();
+        let correction = nanos as i64 - realtime.at(now) as i64;
+        // Keep what was slewed so far, and drop the rest of the previous slew: the new correction includes it.
+        realtime.offset += realtime.applied(now);
+        if correction.abs() > STEP_THRESHOLD_NS {
+            realtime.offset += correction;
+            realtime.slew = 0;
+        } else {
+            realtime.slew = correction;
+        }
+        realtime.slew_start = now;
+        correction
+    })
+}
+
+/// Sleeps until `clock` reaches `time` if `absolute`, or for the duration `time` otherwise. The clocks only advance
+/// with the timer interrupt, so this fails instead of hanging when interrupts are disabled or time is virtual.
+pub fn nanosleep(clock: ClockId, absolute: bool, time: Timespec) -> Result<(), SleepError> {
+    if !x86_64::instructions::interrupts::are_enabled() {
+        return Err(SleepError::InterruptsDisabled);
+    }
+    if super::virtual_clock_enabled() {
+        return Err(SleepError::VirtualClock);
+    }
+    let deadline = if absolute { time.as_nanos() } else { gettime(clock).as_nanos() + time.as_nanos() };
+    while gettime(clock).as_nanos() < deadline {
+        x86_64::instructions::hlt();
+    }
+    Ok(())
+}
+
+/// Seconds since the Unix epoch at `time`, which is taken to be UTC.
+pub fn unix_seconds(time: &DateTime) -> u64 {
+    /* Days since 1970-01-01 of the proleptic Gregorian calendar, counting years from March so that the leap day comes
+    last. */
+    let (year, month) = if time.month <= 2 {
+        (i64::from(time.year) - 1, i64::from(time.month) + 9)
+    } else {
+        (i64::from(time.year), i64::from(time.month) - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + i64::from(time.day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let seconds = i64::from(time.hour) * 3600 + i64::from(time.minute) * 60 + i64::from(time.second);
+    (days * 86_400 + seconds).max(0) as u64
+}
+
+/// Sets the realtime clock from the RTC.
+pub fn init() {
+    let time = crate::cmos::read_time();
+    set_realtime(unix_seconds(&time) * NANOS_PER_SEC);
+    crate::info!("clock: realtime {} ({})", gettime(ClockId::Realtime), time);
+}
+
+#[test_case]
+fn test_unix_seconds() {
+    let time = |year, month, day, hour| DateTime { year, month, day, hour, minute: 0, second: 0 };
+    assert_eq!(unix_seconds(&time(1970, 1, 1, 0)), 0);
+    assert_eq!(unix_seconds(&time(2000, 3, 1, 0)), 951_868_800);
+    assert_eq!(unix_seconds(&time(2024, 2, 29, 23)), 1_709_247_600);
+}
+
+#[test_case]
+fn test_nanosleep() {
+    let start = monotonic_ns();
+    assert_eq!(nanosleep(ClockId::Monotonic, false, Timespec::from_nanos(NANOS_PER_TICK)), Ok(()));
+    assert!(monotonic_ns() >= start + NANOS_PER_TICK);
+    // A deadline in the past returns right away.
+    assert_eq!(nanosleep(ClockId::Monotonic, true, Timespec::from_nanos(start)), Ok(()));
+
+    let tick = Timespec::from_nanos(NANOS_PER_TICK);
+    let disabled = without_interrupts(|| nanosleep(ClockId::Monotonic, false, tick));
+    assert_eq!(disabled, Err(SleepError::InterruptsDisabled));
+    super::enable_virtual_clock();
+    let result = nanosleep(ClockId::Monotonic, false, tick);
+    super::disable_virtual_clock();
+    assert_eq!(result, Err(SleepError::VirtualClock));
+}
+
+#[test_case]
+fn test_slewing_leaves_monotonic_alone() {
+    super::enable_virtual_clock();
+    let saved = realtime_ns();
+    set_realtime(1_000 * NANOS_PER_SEC);
+    let (mono, real) = (monotonic_ns(), realtime_ns());
+
+    // A small correction is slewed at SLEW_PPM.
+    assert_eq!(set_realtime(real + 1_000_000), 1_000_000);
+    assert_eq!(realtime_ns(), real);
+    super::advance(u64::from(config::TIMER_HZ));
+    assert_eq!(monotonic_ns(), mono + NANOS_PER_SEC);
+    assert_eq!(realtime_ns(), real + NANOS_PER_SEC + 500_000);
+    super::advance(u64::from(config::TIMER_HZ) * 10);
+    assert_eq!(realtime_ns(), real + 11 * NANOS_PER_SEC + 1_000_000);
+
+    // A large one is stepped.
+    set_realtime(2_000 * NANOS_PER_SEC);
+    assert_eq!(realtime_ns(), 2_000 * NANOS_PER_SEC);
+    set_realtime(saved);
+    super::disable_virtual_clock();
+}
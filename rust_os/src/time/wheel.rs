@@ -0,0 +1,290 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/* A hierarchical timer wheel, for timeouts that are set far more often than they expire: sleeps, retransmits and
+watchdogs. Inserting and cancelling a timer take constant time, and the tick handler only looks at the timers that are
+due, however many others are pending.
+
+Level 0 has a slot for each of the next 64 ticks. Each higher level has 64 slots that cover 64 times as many ticks as
+a slot of the level below, so four levels reach 64^4 ticks (about 46 hours at 100 Hz) into the future. A timer is put
+into the slot of the lowest level whose range covers its expiry. Whenever level 0 wraps around, the next slot of level
+1 is emptied and its timers are reinserted, which puts them into level 0, and so on up the levels. Timers that are
+further out than the wheel reaches wait in the last slot of the highest level and are reinserted until they fit.
+
+Timers live in a fixed pool, so they can be set and cancelled from interrupt handlers and before the heap exists. Each
+slot is a doubly-linked list through the pool entries, linked by index. A callback runs in the context of the timer
+interrupt, with interrupts disabled, and must not block. It runs after its timer has been removed from the wheel, so it
+may set new timers. */
+
+const LEVELS: usize = 4;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+/// The number of timers that can be pending at the same time.
+pub const MAX_TIMERS: usize = 4096;
+
+// The lists: one for each slot, then the expired timers that are about to run, then the free entries.
+const EXPIRED: u16 = (LEVELS * SLOTS) as u16;
+const FREE: u16 = EXPIRED + 1;
+const LISTS: usize = FREE as usize + 1;
+const NIL: u16 = u16::MAX;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    expires: u64,
+    callback: fn(usize),
+    data: usize,
+    next: u16,
+    prev: u16,
+    list: u16,
+    // Incremented whenever the entry is freed, so that a stale handle can't cancel the entry's next timer.
+    generation: u32,
+}
+
+fn nothing(_: usize) {}
+
+const UNUSED: Entry = Entry { expires: 0, callback: nothing, data: 0, next: NIL, prev: NIL, list: FREE, generation: 0 };
+
+struct Wheel {
+    entries: [Entry; MAX_TIMERS],
+    heads: [u16; LISTS],
+    // The next tick to process.
+    current: u64,
+    pending: usize,
+    ready: bool,
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    entries: [UNUSED; MAX_TIMERS],
+    heads: [NIL; LISTS],
+    current: 0,
+    pending: 0,
+    ready: false,
+});
+
+/// Identifies a pending timer, for cancelling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    index: u16,
+    generation: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// `MAX_TIMERS` timers are already pending.
+    Full,
+}
+
+impl fmt::Display for TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimerError::Full => write!(f, "too many pending timers"),
+        }
+    }
+}
+
+impl Wheel {
+    /* The pool can't be linked into the free list by a const initializer, so that happens on first use. */
+    fn prepare(&mut self) {
+        if !self.ready {
+            self.current = super::ticks();
+            for index in 0..MAX_TIMERS {
+                self.push(FREE, index as u16);
+            }
+            self.ready = true;
+        }
+    }
+
+    fn push(&mut self, list: u16, index: u16) {
+        let head = self.heads[list as usize];
+        let entry = &mut self.entries[index as usize];
+        entry.list = list;
+        entry.prev = NIL;
+        entry.next = head;
+        if head != NIL {
+            self.entries[head as usize].prev = index;
+        }
+        self.heads[list as usize] = index;
+    }
+
+    fn unlink(&mut self, index: u16) {
+        let Entry { next, prev, list, .. } = self.entries[index as usize];
+        if prev == NIL {
+            self.heads[list as usize] = next;
+        } else {
+            self.entries[prev as usize].next = next;
+        }
+        if next != NIL {
+            self.entries[next as usize].prev = prev;
+        }
+    }
+
+    fn pop(&mut self, list: u16) -> Option<u16> {
+        let index = self.heads[list as usize];
+        if index == NIL {
+            return None;
+        }
+        self.unlink(index);
+        Some(index)
+    }
+
+    /* The slot list for a timer that expires at `expires`. */
+    fn slot_for(&self, expires: u64) -> u16 {
+        // Timers that are already due go into the slot that is processed next.
+        let expires = expires.max(self.current);
+        let delta = expires - self.current;
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            if delta < 1 << (shift + SLOT_BITS) {
+                return (level * SLOTS) as u16 + ((expires >> shift) & SLOT_MASK) as u16;
+            }
+        }
+        // Beyond the reach of the wheel: the slot of the highest level that comes up last.
+        let shift = SLOT_BITS * (LEVELS as u32 - 1);
+        ((LEVELS - 1) * SLOTS) as u16 + (((self.current >> shift) + SLOT_MASK) & SLOT_MASK) as u16
+    }
+
+    fn insert(&mut self, index: u16) {
+        let list = self.slot_for(self.entries[index as usize].expires);
+        self.push(list, index);
+    }
+
+    fn free(&mut self, index: u16) {
+        self.entries[index as usize].generation = self.entries[index as usize].generation.wrapping_add(1);
+        self.push(FREE, index);
+        self.pending -= 1;
+    }
+
+    /* Processes tick `current`: cascades the higher levels if level 0 wraps around, then moves the timers of the
+    current slot to the expired list. */
+    fn step(&mut self) {
+        for level in 1..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            if self.current & ((1 << shift) - 1) != 0 {
+                break;
+            }
+            let slot = (level * SLOTS) as u16 + ((self.current >> shift) & SLOT_MASK) as u16;
+            while let Some(index) = self.pop(slot) {
+                self.insert(index);
+            }
+        }
+        let slot = (self.current & SLOT_MASK) as u16;
+        while let Some(index) = self.pop(slot) {
+            self.push(EXPIRED, index);
+        }
+        self.current += 1;
+    }
+}
+
+/// Calls `callback(data)` from the timer interrupt once the tick count reaches `expires`.
+pub fn schedule(expires: u64, callback: fn(usize), data: usize) -> Result<TimerHandle, TimerError> {
+    without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        wheel.prepare();
+        let index = wheel.pop(FREE).ok_or(TimerError::Full)?;
+        let entry = &mut wheel.entries[index as usize];
+        entry.expires = expires;
+        entry.callback = callback;
+        entry.data = data;
+        let generation = entry.generation;
+        wheel.insert(index);
+        wheel.pending += 1;
+        Ok(TimerHandle { index, generation })
+    })
+}
+
+/// Calls `callback(data)` from the timer interrupt `ticks` ticks from now.
+pub fn schedule_in(ticks: u64, callback: fn(usize), data: usize) -> Result<TimerHandle, TimerError> {
+    schedule(super::ticks() + ticks, callback, data)
+}
+
+/// Cancels a pending timer. Returns false if it already ran or was cancelled before.
+pub fn cancel(handle: TimerHandle) -> bool {
+    without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let entry = wheel.entries[handle.index as usize];
+        if !wheel.ready || entry.generation != handle.generation || entry.list == FREE {
+            return false;
+        }
+        wheel.unlink(handle.index);
+        wheel.free(handle.index);
+        true
+    })
+}
+
+/// The number of timers that have not run yet.
+pub fn pending() -> usize {
+    without_interrupts(|| WHEEL.lock().pending)
+}
+
+/// Runs the timers that are due. Called by the timer interrupt handler after every tick.
+pub fn expire() {
+    let now = super::ticks();
+    without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        if !wheel.ready {
+            return;
+        }
+        // Without pending timers there is nothing to cascade, so a large jump of the virtual clock is skipped at once.
+        if wheel.pending == 0 {
+            wheel.current = wheel.current.max(now + 1);
+        }
+        while wheel.current <= now {
+            wheel.step();
+        }
+    });
+    // Run the callbacks one at a time without holding the lock, so that they can set or cancel timers.
+    loop {
+        let timer = without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            let index = wheel.pop(EXPIRED)?;
+            let Entry { callback, data, .. } = wheel.entries[index as usize];
+            wheel.free(index);
+            Some((callback, data))
+        });
+        match timer {
+            Some((callback, data)) => without_interrupts(|| callback(data)),
+            None => break,
+        }
+    }
+}
+
+/// Releases the wheel lock if a panic abandoned it while it was held, see `test_panic_handler`.
+///
+/// This function is unsafe because the wheel may be halfway through an update.
+pub(crate) unsafe fn force_unlock() {
+    WHEEL.force_unlock();
+}
+
+#[test_case]
+fn test_timers_expire_in_order_and_cancel() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    fn fire(bit: usize) {
+        FIRED.fetch_or(bit, Ordering::SeqCst);
+    }
+
+    super::enable_virtual_clock();
+    FIRED.store(0, Ordering::SeqCst);
+    let near = schedule_in(3, fire, 1).unwrap();
+    schedule_in(100, fire, 2).unwrap();
+    let far = schedule_in(5000, fire, 4).unwrap();
+    let cancelled = schedule_in(200, fire, 8).unwrap();
+    assert!(cancel(cancelled));
+    assert!(!cancel(cancelled));
+
+    super::advance(3);
+    expire();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    assert!(!cancel(near));
+    // Crossing level boundaries cascades the later timers down without running them early.
+    super::advance(300);
+    expire();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1 | 2);
+    super::advance(5000);
+    expire();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1 | 2 | 4);
+    assert!(!cancel(far));
+    super::disable_virtual_clock();
+}
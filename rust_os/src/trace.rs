@@ -0,0 +1,156 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use crate::cpu::{current_cpu, MAX_CPUS};
+use crate::{serial_print, serial_println};
+
+/* A lightweight event tracer. `trace!("event", a, b)` appends a fixed-size record with a timestamp (the CPU's time stamp
+counter), the CPU number, the event name, and up to two u64 arguments to a ring buffer of the current CPU. The buffers
+are later dumped over serial with `dump`.
+
+Each CPU writes only to its own buffer so that tracing never needs a lock. A slot is reserved with a single atomic
+fetch_add and then filled field by field, so an interrupt that traces while the interrupted code is halfway through
+writing a record simply gets the next slot. */
+
+const RECORDS_PER_CPU: usize = 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+struct Record {
+    tsc: AtomicU64,
+    cpu: AtomicU64,
+    // The event name is always a string literal, so it is stored as the (pointer, length) pair of a &'static str.
+    name_ptr: AtomicU64,
+    name_len: AtomicU64,
+    args: [AtomicU64; 2],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_RECORD: Record = Record {
+    tsc: AtomicU64::new(0),
+    cpu: AtomicU64::new(0),
+    name_ptr: AtomicU64::new(0),
+    name_len: AtomicU64::new(0),
+    args: [AtomicU64::new(0), AtomicU64::new(0)],
+};
+
+struct CpuBuffer {
+    next: AtomicUsize,
+    records: [Record; RECORDS_PER_CPU],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_BUFFER: CpuBuffer = CpuBuffer {
+    next: AtomicUsize::new(0),
+    records: [EMPTY_RECORD; RECORDS_PER_CPU],
+};
+
+static BUFFERS: [CpuBuffer; MAX_CPUS] = [EMPTY_BUFFER; MAX_CPUS];
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[doc(hidden)]
+pub fn _record(name: &'static str, arg0: u64, arg1: u64) {
+    if !is_enabled() {
+        return;
+    }
+    let cpu = current_cpu();
+    let buffer = &BUFFERS[cpu];
+    let index = buffer.next.fetch_add(1, Ordering::Relaxed);
+    let record = &buffer.records[index % RECORDS_PER_CPU];
+
+    // Safety: rdtsc has no side effects besides reading the time stamp counter.
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    record.tsc.store(tsc, Ordering::Relaxed);
+    record.cpu.store(cpu as u64, Ordering::Relaxed);
+    record.name_ptr.store(name.as_ptr() as u64, Ordering::Relaxed);
+    record.name_len.store(name.len() as u64, Ordering::Relaxed);
+    record.args[0].store(arg0, Ordering::Relaxed);
+    record.args[1].store(arg1, Ordering::Relaxed);
+}
+
+/// Records a trace event on the current CPU: `trace!("event")`, `trace!("event", a)` or `trace!("event", a, b)`.
+/// The event name must be a string literal; the arguments are converted to u64.
+#[macro_export]
+macro_rules! trace {
+    ($event:literal) => {
+        $crate::trace::_record($event, 0, 0)
+    };
+    ($event:literal, $arg0:expr) => {
+        $crate::trace::_record($event, $arg0 as u64, 0)
+    };
+    ($event:literal, $arg0:expr, $arg1:expr) => {
+        $crate::trace::_record($event, $arg0 as u64, $arg1 as u64)
+    };
+}
+
+/* The dump is a JSON array in the Chrome trace event format, so the serial log can be pasted straight into
+chrome://tracing or Perfetto. Every record becomes an instant event ("ph": "i") on the thread of its CPU. The timestamps
+are raw TSC values rather than microseconds, so the time axis is scaled by the TSC frequency, but relative ordering and
+spacing are preserved. */
+
+/// Dumps the trace buffers of all CPUs over serial. Tracing is paused while dumping.
+pub fn dump() {
+    let was_enabled = ENABLED.swap(false, Ordering::SeqCst);
+
+    serial_println!("[");
+    let mut first = true;
+    for_each_recent(RECORDS_PER_CPU, |name, cpu, tsc, [a, b]| {
+        if !first {
+            serial_println!(",");
+        }
+        first = false;
+        serial_print!(
+            "{{\"name\":\"{}\",\"ph\":\"i\",\"s\":\"t\",\"ts\":{},\"pid\":0,\"tid\":{},\"args\":{{\"a\":{},\"b\":{}}}}}",
+            name, tsc, cpu, a, b,
+        );
+    });
+    serial_println!("\n]");
+
+    ENABLED.store(was_enabled, Ordering::SeqCst);
+}
+
+/// Calls `f` with the name, CPU, timestamp and arguments of the last `count` records of every CPU, oldest first.
+pub fn for_each_recent(count: usize, mut f: impl FnMut(&'static str, u64, u64, [u64; 2])) {
+    for buffer in BUFFERS.iter() {
+        let written = buffer.next.load(Ordering::SeqCst);
+        // Once the ring buffer has wrapped around, the oldest surviving record is the one at `written`.
+        let start = written.saturating_sub(count.min(RECORDS_PER_CPU));
+        for index in start..written {
+            let record = &buffer.records[index % RECORDS_PER_CPU];
+            f(
+                record_name(record),
+                record.cpu.load(Ordering::Relaxed),
+                record.tsc.load(Ordering::Relaxed),
+                [record.args[0].load(Ordering::Relaxed), record.args[1].load(Ordering::Relaxed)],
+            );
+        }
+    }
+}
+
+/// Discards all recorded events.
+pub fn clear() {
+    for buffer in BUFFERS.iter() {
+        buffer.next.store(0, Ordering::SeqCst);
+    }
+}
+
+fn record_name(record: &Record) -> &'static str {
+    let ptr = record.name_ptr.load(Ordering::Relaxed) as *const u8;
+    let len = record.name_len.load(Ordering::Relaxed) as usize;
+    // A slot that was reserved but not yet filled in when tracing was paused.
+    if ptr.is_null() {
+        return "?";
+    }
+    /* Safety: name_ptr and name_len are only ever written by _record, from a &'static str passed in by the trace!
+    macro. */
+    unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len)) }
+}
@@ -0,0 +1,244 @@
+use spin::Mutex;
+
+/* A line discipline sits between an input device and whoever reads from it. In canonical mode it collects characters
+into a line buffer, echoes them, and handles the editing characters itself: erase (Backspace or Delete) removes the last
+character and kill (Ctrl+U) the whole line. Only when Enter is pressed does the line, including its newline, become
+readable. In raw mode every character is readable as soon as it arrives and nothing is interpreted, which is what a full
+screen program like a text editor needs. Echo can be switched off independently, e.g. for password prompts.
+
+Readers see a stream of UTF-8 bytes. Keys without a character, like the arrows, are delivered in raw mode as the ANSI
+escape sequences a terminal would send, and ignored in canonical mode. */
+
+const LINE_CAPACITY: usize = 256;
+const INPUT_CAPACITY: usize = 1024;
+
+const ERASE: char = '\x08';
+const DELETE: char = '\x7f';
+const KILL: char = '\x15';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Line editing, input becomes readable a whole line at a time.
+    Canonical,
+    /// Every character is readable immediately and nothing is interpreted.
+    Raw,
+}
+
+pub struct LineDiscipline {
+    mode: Mode,
+    echo: bool,
+    // The line being edited in canonical mode.
+    line: [u8; LINE_CAPACITY],
+    line_len: usize,
+    // Bytes ready to be read, as a ring buffer.
+    input: [u8; INPUT_CAPACITY],
+    input_start: usize,
+    input_len: usize,
+}
+
+impl LineDiscipline {
+    pub const fn new(mode: Mode) -> LineDiscipline {
+        LineDiscipline {
+            mode,
+            echo: true,
+            line: [0; LINE_CAPACITY],
+            line_len: 0,
+            input: [0; INPUT_CAPACITY],
+            input_start: 0,
+            input_len: 0,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Switches the mode. A partially edited line is made readable when switching to raw mode, so that no input is
+    /// lost.
+    pub fn set_mode(&mut self, mode: Mode) {
+        if mode == Mode::Raw {
+            self.flush_line();
+        }
+        self.mode = mode;
+    }
+
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// Processes one character from the input device, passing anything that should be echoed to `echo`.
+    pub fn receive(&mut self, ch: char, mut echo: impl FnMut(&str)) {
+        let mut encoded = [0; 4];
+        let encoded = ch.encode_utf8(&mut encoded);
+        if self.mode == Mode::Raw {
+            self.push_input(encoded.as_bytes());
+            if self.echo {
+                echo(encoded);
+            }
+            return;
+        }
+
+        match ch {
+            ERASE | DELETE => {
+                let cells = self.erase_char();
+                if self.echo {
+                    erase_cells(cells, &mut echo);
+                }
+            }
+            KILL => loop {
+                let cells = self.erase_char();
+                if cells == 0 {
+                    break;
+                }
+                if self.echo {
+                    erase_cells(cells, &mut echo);
+                }
+            },
+            '\n' => {
+                // There is always room for the newline, see below.
+                self.line[self.line_len] = b'\n';
+                self.line_len += 1;
+                self.flush_line();
+                if self.echo {
+                    echo("\n");
+                }
+            }
+            ch if ch.is_control() => {}
+            _ => {
+                // Keep one byte free for the newline.
+                if self.line_len + encoded.len() < LINE_CAPACITY {
+                    self.line[self.line_len..self.line_len + encoded.len()].copy_from_slice(encoded.as_bytes());
+                    self.line_len += encoded.len();
+                    if self.echo {
+                        echo(encoded);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Processes a key that has no character. Only raw mode readers see these, as ANSI escape sequences.
+    pub fn receive_key(&mut self, key: pc_keyboard::KeyCode) {
+        use pc_keyboard::KeyCode;
+
+        if self.mode != Mode::Raw {
+            return;
+        }
+        let sequence: &[u8] = match key {
+            KeyCode::ArrowUp => b"\x1b[A",
+            KeyCode::ArrowDown => b"\x1b[B",
+            KeyCode::ArrowRight => b"\x1b[C",
+            KeyCode::ArrowLeft => b"\x1b[D",
+            _ => return,
+        };
+        self.push_input(sequence);
+    }
+
+    /// Copies readable input into `buf` and returns the number of bytes copied.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.input_len);
+        for byte in buf[..count].iter_mut() {
+            *byte = self.input[self.input_start];
+            self.input_start = (self.input_start + 1) % INPUT_CAPACITY;
+        }
+        self.input_len -= count;
+        count
+    }
+
+    /// The number of bytes that can be read right now.
+    pub fn available(&self) -> usize {
+        self.input_len
+    }
+
+    /* Removes the last character of the line, which may be several bytes long, and returns the number of screen cells
+    its echo took: the VGA console prints a cell for every byte that isn't ASCII (see vga_buffer.rs). */
+    fn erase_char(&mut self) -> usize {
+        let end = self.line_len;
+        if end == 0 {
+            return 0;
+        }
+        self.line_len -= 1;
+        // UTF-8 continuation bytes look like 0b10xx_xxxx.
+        while self.line_len > 0 && self.line[self.line_len] & 0xc0 == 0x80 {
+            self.line_len -= 1;
+        }
+        end - self.line_len
+    }
+
+    fn flush_line(&mut self) {
+        let line = self.line;
+        self.push_input(&line[..self.line_len]);
+        self.line_len = 0;
+    }
+
+    /* Input that doesn't fit is dropped, like a terminal does when nobody reads from it. */
+    fn push_input(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.input_len == INPUT_CAPACITY {
+                return;
+            }
+            self.input[(self.input_start + self.input_len) % INPUT_CAPACITY] = byte;
+            self.input_len += 1;
+        }
+    }
+}
+
+/* Moves back over `cells` cells and blanks them, the way a terminal expects an erased character to be echoed. */
+fn erase_cells(cells: usize, echo: &mut impl FnMut(&str)) {
+    for _ in 0..cells {
+        echo("\x08 \x08");
+    }
+}
+
+/// The line discipline of the console, fed by the keyboard interrupt handler and by input from the serial port (see
+/// console.rs).
+pub static CONSOLE: Mutex<LineDiscipline> = Mutex::new(LineDiscipline::new(Mode::Canonical));
+
+/// Reads input from the VGA console. Safe to call while keyboard interrupts may arrive.
+pub fn read(buf: &mut [u8]) -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| CONSOLE.lock().read(buf))
+}
+
+pub fn set_mode(mode: Mode) {
+    x86_64::instructions::interrupts::without_interrupts(|| CONSOLE.lock().set_mode(mode));
+}
+
+pub fn set_echo(echo: bool) {
+    x86_64::instructions::interrupts::without_interrupts(|| CONSOLE.lock().set_echo(echo));
+}
+
+#[test_case]
+fn test_canonical_line_editing() {
+    let mut tty = LineDiscipline::new(Mode::Canonical);
+    let mut echoed = 0;
+    for ch in "helo\x08lo\x15hi\u{e4}\x08!\n".chars() {
+        tty.receive(ch, |s| echoed += s.len());
+        // Nothing is readable until the line is complete.
+        if ch != '\n' {
+            assert_eq!(tty.available(), 0);
+        }
+    }
+    let mut buf = [0; 16];
+    let len = tty.read(&mut buf);
+    assert_eq!(&buf[..len], b"hi!\n");
+    assert!(echoed > 0);
+
+    // The two byte character took two cells on screen, so both are erased.
+    let mut erased = 0;
+    tty.receive('\u{e4}', |_| {});
+    tty.receive(ERASE, |s| erased += s.matches('\x08').count());
+    assert_eq!(erased, 4);
+}
+
+#[test_case]
+fn test_raw_mode_passes_everything() {
+    let mut tty = LineDiscipline::new(Mode::Canonical);
+    tty.receive('a', |_| {});
+    tty.set_mode(Mode::Raw);
+    tty.set_echo(false);
+    tty.receive('\x08', |_| panic!("echo is off"));
+    tty.receive_key(pc_keyboard::KeyCode::ArrowUp);
+    let mut buf = [0; 16];
+    let len = tty.read(&mut buf);
+    assert_eq!(&buf[..len], b"a\x08\x1b[A");
+}
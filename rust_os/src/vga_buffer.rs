@@ -30,6 +30,14 @@ impl ColorCode {
     fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    fn with_foreground(self, foreground: Color) -> ColorCode {
+        ColorCode(self.0 & 0xf0 | foreground as u8)
+    }
+
+    fn with_background(self, background: Color) -> ColorCode {
+        ColorCode((background as u8) << 4 | self.0 & 0x0f)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -51,13 +59,62 @@ struct Buffer {
 pub struct Writer {
     column_position: usize, // keeps track of the current position in the last row
     color_code: ColorCode, // contains the current foreground and background colors
+    default_color: ColorCode, // the colors an SGR reset (ESC [ 0 m) goes back to
+    escape: Escape, // how far into an ANSI escape sequence we are
+    params: [u16; MAX_ESCAPE_PARAMS], // the parameters of the current control sequence
+    param_count: usize,
     buffer: &'static mut Buffer, // reference to the buffer that is valid for the whole program's lifetimes
 }
 
+const BACKSPACE: u8 = 0x08;
+const ESC: u8 = 0x1b;
+
+/* The writer understands the ANSI "select graphic rendition" sequences that set colors, ESC [ n ; ... m, so that
+colored output looks the same on the screen as on a terminal attached to the serial port. Other escape sequences, like
+cursor movement, are consumed and ignored rather than printed as garbage. */
+const MAX_ESCAPE_PARAMS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    /// Not in an escape sequence.
+    Ground,
+    /// Right after ESC.
+    Esc,
+    /// Inside a control sequence, after ESC [.
+    ControlSequence,
+}
+
+/* The eight ANSI colors, in SGR order, and their bright variants. */
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black, Color::Red, Color::Green, Color::Brown, Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray,
+];
+const ANSI_BRIGHT_COLORS: [Color; 8] = [
+    Color::DarkGray, Color::LightRed, Color::LightGreen, Color::Yellow,
+    Color::LightBlue, Color::Pink, Color::LightCyan, Color::White,
+];
+
 impl Writer {
+    fn new(color_code: ColorCode, buffer: &'static mut Buffer) -> Writer {
+        Writer {
+            column_position: 0,
+            color_code,
+            default_color: color_code,
+            escape: Escape::Ground,
+            params: [0; MAX_ESCAPE_PARAMS],
+            param_count: 0,
+            buffer,
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
+        if self.escape != Escape::Ground {
+            self.escape_byte(byte);
+            return;
+        }
         match byte {
             b'\n' => self.new_line(),
+            BACKSPACE => self.backspace(),
+            ESC => self.escape = Escape::Esc,
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -88,6 +145,65 @@ impl Writer {
         self.column_position = 0;
     }
 
+    /* Erases the character before the cursor, which is how the line discipline echoes an erased character. Only the
+    last row can be edited, so a backspace at the start of a row does nothing. */
+    fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+            let blank = ScreenChar { ascii_character: b' ', color_code: self.color_code };
+            self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(blank);
+        }
+    }
+
+    fn escape_byte(&mut self, byte: u8) {
+        match (self.escape, byte) {
+            (Escape::Esc, b'[') => {
+                self.escape = Escape::ControlSequence;
+                self.params = [0; MAX_ESCAPE_PARAMS];
+                self.param_count = 1;
+            }
+            // Escape sequences other than control sequences are two bytes long.
+            (Escape::Esc, _) => self.escape = Escape::Ground,
+            (Escape::ControlSequence, b'0'..=b'9') => {
+                // Parameters past the last one we have room for are dropped.
+                if let Some(param) = self.params.get_mut(self.param_count - 1) {
+                    *param = param.saturating_mul(10).saturating_add(u16::from(byte - b'0'));
+                }
+            }
+            (Escape::ControlSequence, b';') => self.param_count += 1,
+            (Escape::ControlSequence, b'm') => {
+                let count = self.param_count.min(MAX_ESCAPE_PARAMS);
+                let params = self.params;
+                self.select_graphic_rendition(&params[..count]);
+                self.escape = Escape::Ground;
+            }
+            // Any other final byte ends a sequence we don't support.
+            (Escape::ControlSequence, 0x40..=0x7e) => self.escape = Escape::Ground,
+            (Escape::ControlSequence, _) => {}
+            (Escape::Ground, _) => unreachable!("escape_byte called outside of an escape sequence"),
+        }
+    }
+
+    /* Applies the color parameters of an SGR sequence. An empty parameter counts as 0, as in ESC [ m. Attributes the
+    VGA text mode can't show, like bold or underline, are ignored. */
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        for &param in params {
+            let param = usize::from(param);
+            self.color_code = match param {
+                0 => self.default_color,
+                30..=37 => self.color_code.with_foreground(ANSI_COLORS[param - 30]),
+                39 => self.color_code.with_foreground(Color::from_u8(self.default_color.0)),
+                40..=47 => self.color_code.with_background(ANSI_COLORS[param - 40]),
+                49 => self.color_code.with_background(Color::from_u8(self.default_color.0 >> 4)),
+                90..=97 => self.color_code.with_foreground(ANSI_BRIGHT_COLORS[param - 90]),
+                100..=107 => self.color_code.with_background(ANSI_BRIGHT_COLORS[param - 100]),
+                // 256-color and RGB colors take further parameters that would be misread as attributes.
+                38 | 48 => return,
+                _ => self.color_code,
+            };
+        }
+    }
+
     fn clear_row(&mut self, row: usize) {
         // Clears a row by writing the ascii space character as each byte.
         let blank = ScreenChar {
@@ -102,8 +218,8 @@ impl Writer {
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                // printable ASCII byte, newline, or part of an escape sequence
+                0x20..=0x7e | b'\n' | BACKSPACE | ESC => self.write_byte(byte),
                 // not part of printable ASCII range
                 // For unprintable bytes, we print a ■ character, which has the hex code 0xfe on the VGA hardware
                 _ => self.write_byte(0xfe),
@@ -113,6 +229,76 @@ impl Writer {
     }
 }
 
+/* A copy of the screen contents, used by tests to compare what was printed against an expected snapshot. Taking a
+snapshot copies the whole buffer, so the checks don't race with later prints (such as the dots from the timer). */
+#[derive(Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    chars: [[u8; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    colors: [[u8; BUFFER_WIDTH]; BUFFER_HEIGHT],
+}
+
+impl Snapshot {
+    /// The characters of `row`, without trailing spaces.
+    pub fn row(&self, row: usize) -> &[u8] {
+        let chars = &self.chars[row];
+        let len = chars.iter().rposition(|&c| c != b' ').map_or(0, |last| last + 1);
+        &chars[..len]
+    }
+
+    /// The (foreground, background) colors of the character at `row`, `col`.
+    pub fn color_at(&self, row: usize, col: usize) -> (Color, Color) {
+        let code = self.colors[row][col];
+        (Color::from_u8(code & 0xf), Color::from_u8(code >> 4))
+    }
+
+    /// Panics unless the last `expected.len()` rows of the screen match `expected`, ignoring trailing spaces.
+    /// The last row is the one the writer is currently printing into.
+    #[track_caller]
+    pub fn assert_bottom_rows(&self, expected: &[&str]) {
+        assert!(expected.len() <= BUFFER_HEIGHT, "more expected rows than the screen has");
+        let first_row = BUFFER_HEIGHT - expected.len();
+        for (i, expected_row) in expected.iter().enumerate() {
+            let actual = self.row(first_row + i);
+            assert!(
+                actual == expected_row.as_bytes(),
+                "screen row {} is {:?}, expected {:?}",
+                first_row + i,
+                core::str::from_utf8(actual).unwrap_or("<non-ASCII>"),
+                expected_row
+            );
+        }
+    }
+}
+
+impl Color {
+    fn from_u8(value: u8) -> Color {
+        use Color::*;
+        const COLORS: [Color; 16] = [
+            Black, Blue, Green, Cyan, Red, Magenta, Brown, LightGray,
+            DarkGray, LightBlue, LightGreen, LightCyan, LightRed, Pink, Yellow, White,
+        ];
+        COLORS[usize::from(value & 0xf)]
+    }
+}
+
+impl Writer {
+    /// Copies the current screen contents.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot {
+            chars: [[b' '; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            colors: [[0; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        };
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let screen_char = self.buffer.chars[row][col].read();
+                snapshot.chars[row][col] = screen_char.ascii_character;
+                snapshot.colors[row][col] = screen_char.color_code.0;
+            }
+        }
+        snapshot
+    }
+}
+
 /* We want to use Rust formatting macros, so let's implement core::fmt::Write for our Writer. It just invokes the
 write_string method we already wrote, and never errors out. */
 use core::fmt;
@@ -130,11 +316,10 @@ use lazy_static::lazy_static;
 We also use a spin Mutex to perform atomic writes. We use a spinlock since it is CPU dependent
 and doesn't require the standard library. It does burn CPU time though. */
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new(
+        ColorCode::new(Color::Yellow, Color::Black),
+        unsafe { &mut *(0xb8000 as *mut Buffer) },
+    ));
 }
 
 /* Define the println and print macros (code taken from the standard lib and repurposed to use the buffer). */
@@ -163,6 +348,44 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+/// Prints with the given foreground color, then restores the previous color.
+#[doc(hidden)]
+pub fn _print_colored(foreground: Color, args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let previous = writer.color_code;
+        writer.color_code = ColorCode::new(foreground, Color::from_u8(previous.0 >> 4));
+        writer.write_fmt(args).unwrap();
+        writer.color_code = previous;
+    });
+}
+
+/* The panic handler can't use WRITER: the panic may have happened while the writer was locked, e.g. in a fmt::Display
+implementation called by println!, and spinning on that lock would hang the kernel without printing anything. Instead,
+it prints through a second Writer that points at the same buffer and is never locked. Its output may interleave with a
+half finished line of the interrupted writer, which is fine for the last message before the kernel stops. */
+
+#[doc(hidden)]
+pub fn _emergency_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let mut writer = Writer::new(
+        ColorCode::new(Color::White, Color::Red),
+        // Safety: only used on the way down, where a second reference to the buffer can no longer do harm.
+        unsafe { &mut *(0xb8000 as *mut Buffer) },
+    );
+    // Start on a fresh line, since we don't know the column the interrupted writer was at.
+    writer.new_line();
+    let _ = writer.write_fmt(args);
+}
+
+/// Prints to the VGA buffer without taking the WRITER lock. Only for the panic handler.
+#[macro_export]
+macro_rules! emergency_println {
+    ($($arg:tt)*) => ($crate::vga_buffer::_emergency_print(format_args!($($arg)*)));
+}
+
 /* Add tests using our new testing framework. */
 #[test_case]
 fn test_println_simple() {
@@ -193,4 +416,77 @@ fn test_println_output() {
             assert_eq!(char::from(screen_char.ascii_character), c);
         }
     });
-}
\ No newline at end of file
+}
+
+/* Prints a few lines with interrupts disabled, so that no timer output can sneak in, and returns the resulting screen. */
+#[cfg(test)]
+fn print_and_snapshot(lines: &[&str]) -> Snapshot {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        for line in lines {
+            writeln!(writer, "{}", line).expect("writeln failed");
+        }
+        writer.snapshot()
+    })
+}
+
+#[test_case]
+fn test_snapshot_scrolling() {
+    let snapshot = print_and_snapshot(&["first", "second", "third"]);
+    // The last row is the empty one after the final newline; earlier lines have scrolled up.
+    snapshot.assert_bottom_rows(&["first", "second", "third", ""]);
+}
+
+#[test_case]
+fn test_snapshot_wraps_long_lines() {
+    let mut long = [0u8; 100];
+    for (i, byte) in long.iter_mut().enumerate() {
+        *byte = b'0' + (i % 10) as u8;
+    }
+    let snapshot = print_and_snapshot(&[core::str::from_utf8(&long).unwrap()]);
+    snapshot.assert_bottom_rows(&[
+        "01234567890123456789012345678901234567890123456789012345678901234567890123456789",
+        "01234567890123456789",
+        "",
+    ]);
+}
+
+#[test_case]
+fn test_snapshot_colors_and_unprintable() {
+    let snapshot = print_and_snapshot(&["a\u{e4}b"]);
+    // The two-byte UTF-8 encoding of 'ä' is printed as two ■ characters.
+    assert_eq!(snapshot.row(BUFFER_HEIGHT - 2), &[b'a', 0xfe, 0xfe, b'b']);
+    assert_eq!(snapshot.color_at(BUFFER_HEIGHT - 2, 0), (Color::Yellow, Color::Black));
+}
+
+#[test_case]
+fn test_snapshot_backspace() {
+    let snapshot = print_and_snapshot(&["abc\x08\x08d"]);
+    assert_eq!(snapshot.row(BUFFER_HEIGHT - 2), b"ad");
+}
+
+#[test_case]
+fn test_snapshot_ansi_colors() {
+    let snapshot = print_and_snapshot(&["a\x1b[31mb\x1b[1;44mc\x1b[39md\x1b[0me\x1b[mf"]);
+    let row = BUFFER_HEIGHT - 2;
+    assert_eq!(snapshot.row(row), b"abcdef");
+    assert_eq!(snapshot.color_at(row, 0), (Color::Yellow, Color::Black));
+    assert_eq!(snapshot.color_at(row, 1), (Color::Red, Color::Black));
+    // Bold is ignored, and the background changes independently of the foreground.
+    assert_eq!(snapshot.color_at(row, 2), (Color::Red, Color::Blue));
+    assert_eq!(snapshot.color_at(row, 3), (Color::Yellow, Color::Blue));
+    assert_eq!(snapshot.color_at(row, 4), (Color::Yellow, Color::Black));
+    assert_eq!(snapshot.color_at(row, 5), (Color::Yellow, Color::Black));
+}
+
+#[test_case]
+fn test_snapshot_ansi_unsupported_sequences() {
+    // Cursor movement and line erasure aren't supported, but they must not show up on the screen either.
+    let snapshot = print_and_snapshot(&["x\x1b[2Ky\x1b[1;1Hz"]);
+    assert_eq!(snapshot.row(BUFFER_HEIGHT - 2), b"xyz");
+    assert_eq!(snapshot.color_at(BUFFER_HEIGHT - 2, 2), (Color::Yellow, Color::Black));
+}
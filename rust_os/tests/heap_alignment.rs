@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::boxed::Box;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(boot_info)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/* Sizes that are deliberately not multiples of any power of two, so that allocations leave odd-sized holes behind. */
+const ODD_SIZES: [usize; 6] = [1, 3, 7, 13, 100, 1021];
+const MAX_ALIGN: usize = 4096;
+
+/* Allocates `layout`, checks that the returned pointer is aligned and that the memory is usable, and returns it. */
+fn checked_alloc(layout: Layout, fill: u8) -> *mut u8 {
+    let ptr = unsafe { alloc(layout) };
+    assert!(!ptr.is_null(), "allocation of {:?} failed", layout);
+    assert_eq!(ptr as usize % layout.align(), 0, "{:?} returned misaligned {:p}", layout, ptr);
+    unsafe { core::ptr::write_bytes(ptr, fill, layout.size()) };
+    ptr
+}
+
+/* Checks that the memory behind ptr still holds the byte pattern written by checked_alloc. If two live allocations
+overlapped, the later one would have overwritten part of the earlier one. */
+fn verify_fill(ptr: *mut u8, layout: Layout, fill: u8) {
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+    assert!(bytes.iter().all(|&b| b == fill), "allocation at {:p} was overwritten", ptr);
+}
+
+#[test_case]
+fn every_alignment_odd_sizes() {
+    let mut align = 1;
+    while align <= MAX_ALIGN {
+        for &size in ODD_SIZES.iter() {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = checked_alloc(layout, 0xa5);
+            verify_fill(ptr, layout, 0xa5);
+            unsafe { dealloc(ptr, layout) };
+        }
+        align *= 2;
+    }
+}
+
+#[test_case]
+fn live_allocations_do_not_overlap() {
+    const COUNT: usize = 12;
+    let mut live: [(usize, Layout); COUNT] = [(0, Layout::new::<u8>()); COUNT];
+
+    // Interleave small and page-aligned allocations so that the allocator has to skip over unaligned space.
+    for (i, slot) in live.iter_mut().enumerate() {
+        let align = if i % 2 == 0 { MAX_ALIGN } else { 8 << (i % 4) };
+        let layout = Layout::from_size_align(ODD_SIZES[i % ODD_SIZES.len()], align).unwrap();
+        *slot = (checked_alloc(layout, i as u8) as usize, layout);
+    }
+
+    for (i, &(ptr, layout)) in live.iter().enumerate() {
+        verify_fill(ptr as *mut u8, layout, i as u8);
+        for &(other, other_layout) in live[i + 1..].iter() {
+            let disjoint = ptr + layout.size() <= other || other + other_layout.size() <= ptr;
+            assert!(disjoint, "{:#x} ({:?}) overlaps {:#x} ({:?})", ptr, layout, other, other_layout);
+        }
+    }
+
+    for &(ptr, layout) in live.iter() {
+        unsafe { dealloc(ptr as *mut u8, layout) };
+    }
+}
+
+#[test_case]
+fn page_aligned_box() {
+    #[repr(align(4096))]
+    struct PageAligned([u8; 13]);
+
+    for i in 0..8 {
+        let value = Box::new(PageAligned([i; 13]));
+        assert_eq!(&*value as *const PageAligned as usize % 4096, 0);
+        assert!(value.0.iter().all(|&b| b == i));
+    }
+}
+
+#[test_case]
+fn aligned_reuse_after_free() {
+    // Freeing and reallocating with a growing alignment must keep working after the heap has been fragmented.
+    let small = Layout::from_size_align(3, 1).unwrap();
+    let spacer = checked_alloc(small, 1);
+    let mut align = 16;
+    while align <= MAX_ALIGN {
+        let layout = Layout::from_size_align(align + 1, align).unwrap();
+        let ptr = checked_alloc(layout, 2);
+        unsafe { dealloc(ptr, layout) };
+        align *= 2;
+    }
+    verify_fill(spacer, small, 1);
+    unsafe { dealloc(spacer, small) };
+}
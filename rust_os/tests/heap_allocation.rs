@@ -22,10 +22,11 @@ fn main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+        BootInfoFrameAllocator::init(boot_info)
     };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    allocator::enable_growth(mapper, frame_allocator);
 
     test_main();
     loop {}
@@ -60,4 +61,64 @@ fn many_boxes() {
         let x = Box::new(i);
         assert_eq!(*x, i);
     }
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn heap_grows_when_full() {
+    use rust_os::allocator::{self, OomPolicy};
+
+    let policy = allocator::oom_policy();
+    allocator::set_oom_policy(OomPolicy::Grow);
+    let vec: Vec<u8> = Vec::with_capacity(HEAP_SIZE);
+    assert!(allocator::stats().size > HEAP_SIZE);
+    assert!(allocator::stats().oom_events > 0);
+    drop(vec);
+    allocator::set_oom_policy(policy);
+}
+
+#[test_case]
+fn freed_frames_are_zeroed() {
+    use rust_os::{allocator, memory::{self, ZeroPolicy}};
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    // Only OnAllocate leaves freed frames dirty for the scrubber, and config.rs may choose another policy.
+    let policy = memory::zero_policy();
+    memory::set_zero_policy(ZeroPolicy::OnAllocate);
+    let frame = allocator::with_frame_allocator(|frames| {
+        let frame = frames.allocate_frame().unwrap();
+        let page = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        unsafe {
+            core::ptr::write_bytes(page, 0xaa, 4096);
+            frames.deallocate_frame(frame);
+        }
+        frame
+    })
+    .unwrap();
+    assert_eq!(allocator::scrub_frames(usize::MAX), 1);
+    assert_eq!(allocator::with_frame_allocator(|frames| frames.dirty_frames()), Some(0));
+
+    // The freed frame is reused first, without its old contents.
+    let again = allocator::with_frame_allocator(|frames| frames.allocate_frame().unwrap()).unwrap();
+    assert_eq!(again, frame);
+    let page = memory::phys_to_virt(again.start_address()).as_ptr::<u8>();
+    let bytes = unsafe { core::slice::from_raw_parts(page, 4096) };
+    assert!(bytes.iter().all(|&byte| byte == 0));
+    memory::set_zero_policy(policy);
+}
+
+#[test_case]
+fn frames_are_accounted_by_zone_and_owner() {
+    use rust_os::{allocator, memory::{self, Owner, Zone}};
+
+    let before = memory::free_memory_report().unwrap();
+    assert!(before.owned_by(Owner::KernelHeap) >= HEAP_SIZE / 4096);
+    let frame = allocator::with_frame_allocator(|frames| frames.allocate_frame_for(Owner::Dma).unwrap()).unwrap();
+    assert_eq!(Zone::of(frame), Zone::Dma);
+    let during = memory::free_memory_report().unwrap();
+    assert_eq!(during.owned_by(Owner::Dma), before.owned_by(Owner::Dma) + 1);
+    assert_eq!(during.free[Zone::Dma as usize], before.free[Zone::Dma as usize] - 1);
+
+    allocator::with_frame_allocator(|frames| unsafe { frames.deallocate_frame_for(frame, Owner::Dma) }).unwrap();
+    let after = memory::free_memory_report().unwrap();
+    assert_eq!(after.free_frames(), before.free_frames());
+}
@@ -1,27 +1,55 @@
 #![no_std]
 #![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
-use rust_os::{QemuExitCode, exit_qemu, serial_println, serial_print};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::should_panic_case;
 
-/* This test uses the harness=false flag in Cargo.toml to disable the default and custom test runner.
-We run the test directly from the _start entry point. */
+/* Counts how many of the should_panic cases started, so the last test can check that none was skipped. */
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+/* Every test in this file is expected to panic. The custom test framework catches each expected panic in
+test_panic_handler and continues with the next test, so they all run in a single QEMU boot. */
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    should_fail();
-    serial_println!("[test did not panic]");
-    exit_qemu(QemuExitCode::Failed);
-    loop{}
-}
+    test_main();
 
-fn should_fail() {
-    serial_print!("should_panic::should_fail...\t");
-    assert_eq!(0, 1);
+    rust_os::hlt_loop();
 }
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-    loop {}
-}
\ No newline at end of file
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+should_panic_case! {
+    fn should_fail() {
+        STARTED.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(0, 1);
+    }
+}
+
+should_panic_case! {
+    fn explicit_panic() {
+        STARTED.fetch_add(1, Ordering::SeqCst);
+        panic!("expected panic");
+    }
+}
+
+should_panic_case! {
+    fn index_out_of_bounds() {
+        STARTED.fetch_add(1, Ordering::SeqCst);
+        let values = [1, 2, 3];
+        let index = volatile::Volatile::new(3).read();
+        assert_eq!(values[index], 0);
+    }
+}
+
+/* A normal test after the expected panics checks that the run really continued. */
+#[test_case]
+fn runs_after_expected_panics() {
+    assert_eq!(STARTED.load(Ordering::SeqCst), 3);
+}